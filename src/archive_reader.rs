@@ -0,0 +1,426 @@
+// Joseph Prichard
+// 1/5/2023
+// Read the entries of an archive into memory, either iterating over each entry or looking entries up by name
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::vec;
+use crate::bitwise_io::{FileReader, FileWriter};
+use crate::coder::SymbolCoder;
+use crate::crc::Crc32;
+use crate::compress::{check_block_crc, decompress, get_file_blocks, open_block, read_file_blocks, read_trailing_index, MANIFEST_NAME};
+use crate::structures::FileBlock;
+
+// yields the name and decompressed bytes of each entry in archive order
+pub struct ArchiveReader {
+    reader: FileReader<'static>,
+    blocks: vec::IntoIter<FileBlock>,
+}
+
+impl ArchiveReader {
+    // reads the headers up front, the entries are only decompressed when they are iterated over
+    pub fn open(archive_filepath: &str) -> io::Result<ArchiveReader> {
+        let mut reader = FileReader::new(archive_filepath)?;
+        let blocks = get_file_blocks(&mut reader)?;
+        Ok(ArchiveReader { reader, blocks: blocks.into_iter() })
+    }
+
+}
+
+impl Iterator for ArchiveReader {
+    type Item = io::Result<(String, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let block = self.blocks.next()?;
+        Some(decompress_to_vec(&block, &mut self.reader).map(|bytes| (block.filename_rel, bytes)))
+    }
+}
+
+// keeps the archive open with its headers parsed, so extracting many entries one at a time doesn't reopen or reparse it
+pub struct ArchiveHandle {
+    reader: FileReader<'static>,
+    blocks: Vec<FileBlock>,
+}
+
+impl ArchiveHandle {
+    pub fn open(archive_filepath: &str) -> io::Result<ArchiveHandle> {
+        let mut reader = FileReader::new(archive_filepath)?;
+        let blocks = get_file_blocks(&mut reader)?;
+        Ok(ArchiveHandle { reader, blocks })
+    }
+
+    // open an archive written with a trailing index, finding the entries from the end of the archive instead of the front headers
+    pub fn open_indexed(archive_filepath: &str) -> io::Result<ArchiveHandle> {
+        let mut reader = FileReader::new(archive_filepath)?;
+        let blocks = read_trailing_index(&mut reader, false)?;
+        Ok(ArchiveHandle { reader, blocks })
+    }
+
+    // names of the entries in archive order
+    pub fn entry_names(&self) -> Vec<&str> {
+        self.blocks.iter().map(|block| block.filename_rel.as_str()).collect()
+    }
+
+    pub fn read_entry(&mut self, filename_rel: &str) -> io::Result<Vec<u8>> {
+        let block = find_block(&self.blocks, filename_rel)?;
+        decompress_to_vec(block, &mut self.reader)
+    }
+
+    // read an entry a buffer at a time, for entries too large to decompress into memory
+    pub fn open_entry(&mut self, filename_rel: &str) -> io::Result<BlockReader<'_, 'static>> {
+        let block = find_block(&self.blocks, filename_rel)?.clone();
+        BlockReader::new(&mut self.reader, block)
+    }
+
+    // decompress an entry into the destination file
+    // a file that failed to decompress or its checksum holds damaged data, so it isn't left behind
+    pub fn extract(&mut self, filename_rel: &str, dest_filepath: &str) -> io::Result<()> {
+        let block = find_block(&self.blocks, filename_rel)?;
+        let mut writer = FileWriter::new(dest_filepath)?;
+        let result = decompress(block, &mut self.reader, &mut writer).and_then(|_| writer.flush());
+        drop(writer);
+        if result.is_err() {
+            let _ = fs::remove_file(dest_filepath);
+        }
+        result
+    }
+}
+
+// decodes an entry only as far as each read asks for, so memory use stays bounded by the caller's buffer
+pub struct BlockReader<'r, 'a> {
+    reader: &'r mut FileReader<'a>,
+    coder: Box<dyn SymbolCoder>,
+    block: FileBlock,
+    // bytes of the entry not yet decoded
+    remaining: u64,
+    data_start: u64,
+    // checksum of the bytes decoded so far, checked once the last byte is decoded
+    crc: Crc32,
+}
+
+impl<'r, 'a> BlockReader<'r, 'a> {
+    pub fn new(reader: &'r mut FileReader<'a>, block: FileBlock) -> io::Result<BlockReader<'r, 'a>> {
+        let coder = open_block(&block, reader)?;
+        let data_start = reader.read_len();
+        Ok(BlockReader { reader, coder, remaining: block.og_byte_size, block, data_start, crc: Crc32::new() })
+    }
+}
+
+impl Read for BlockReader<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = (buf.len() as u64).min(self.remaining) as usize;
+        self.coder.decode_into(self.reader, &mut buf[..count])?;
+        self.crc.update_bytes(&buf[..count]);
+        self.remaining -= count as u64;
+        // the read length only increases so the bits read can't underflow
+        if self.reader.read_len() - self.data_start > self.block.data_bit_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("Compressed data for {} is longer than its block size", self.block.filename_rel)));
+        }
+        if self.remaining == 0 && count > 0 {
+            check_block_crc(&self.block, self.crc.value())?;
+        }
+        Ok(count)
+    }
+}
+
+fn find_block<'a>(blocks: &'a [FileBlock], filename_rel: &str) -> io::Result<&'a FileBlock> {
+    blocks.iter()
+        .find(|block| block.filename_rel == filename_rel)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No entry named {} in the archive", filename_rel)))
+}
+
+// decompress every entry into memory keyed by name, for loading a small archive such as a bundle of config files at once
+// the sizes in the headers are checked against the limit before anything is decompressed, so a huge archive fails up front
+pub fn read_all_entries(archive_filepath: &str, max_total_size: u64) -> io::Result<HashMap<String, Vec<u8>>> {
+    let reader = &mut FileReader::new(archive_filepath)?;
+    let blocks = read_file_blocks(reader, false)?;
+    // an entry repeated in a later archive replaces the earlier one, the same as when extracting
+    let mut latest = HashMap::new();
+    for block in &blocks {
+        latest.insert(block.filename_rel.as_str(), block);
+    }
+    // only the entries that are kept count towards the limit, and a hardlink's data is counted for the block it links to
+    let total_size = latest.values()
+        .filter(|block| !block.hardlink)
+        .try_fold(0u64, |total, block| total.checked_add(block.og_byte_size));
+    if total_size.is_none_or(|total_size| total_size > max_total_size) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("{} holds more than the limit of {} bytes to read into memory", archive_filepath, max_total_size)));
+    }
+    let mut entries = HashMap::new();
+    for (name, block) in latest {
+        entries.insert(String::from(name), decompress_to_vec(block, reader)?);
+    }
+    Ok(entries)
+}
+
+// the listing of entries an archive was written with, which is always its first entry
+pub fn read_manifest(archive_filepath: &str) -> io::Result<Vec<u8>> {
+    let reader = &mut FileReader::new(archive_filepath)?;
+    let blocks = read_file_blocks(reader, false)?;
+    match blocks.first() {
+        Some(block) if block.filename_rel == MANIFEST_NAME => decompress_to_vec(block, reader),
+        _ => Err(io::Error::new(io::ErrorKind::NotFound,
+            format!("{} has no manifest, it was written without --with-manifest", archive_filepath)))
+    }
+}
+
+pub(crate) fn decompress_to_vec(block: &FileBlock, reader: &mut FileReader) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut writer = FileWriter::from_writer(&mut bytes);
+    // decompress seeks to the block's offset so entries don't depend on where the last read stopped
+    decompress(block, reader, &mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::Read;
+    use crate::archive_reader::{read_all_entries, read_manifest, ArchiveHandle, ArchiveReader};
+    use crate::compress::{archive_dir, sizeof, ArchiveMetadata, ArchiveOptions, MANIFEST_NAME, SIG};
+    use crate::structures::NAME_LEN_SIZE;
+    use crate::testing::scratch_dir;
+
+    // offset of the first header: the signature, version and flag bytes, block count, then the metadata
+    fn headers_start() -> usize {
+        sizeof(SIG) + 6 + ArchiveMetadata::current().size() as usize
+    }
+
+    #[test]
+    fn test_iterate_entries() {
+        let dir = scratch_dir("archive_reader");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(input_dir.join("nested")).unwrap();
+        let files = HashMap::from([
+            (String::from("input/first.txt"), b"first file".to_vec()),
+            (String::from("input/nested/second.bin"), (0..3000u32).map(|i| (i * 13 % 256) as u8).collect()),
+            (String::from("input/tiny.txt"), b"a".to_vec()),
+        ]);
+        for (name, data) in &files {
+            fs::write(dir.join(name), data).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        let mut entries = HashMap::new();
+        for entry in ArchiveReader::open(&format!("{}.zipr", input_path)).unwrap() {
+            let (name, bytes) = entry.unwrap();
+            entries.insert(name, bytes);
+        }
+        assert_eq!(entries, files);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_all_entries() {
+        let dir = scratch_dir("read_all");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(input_dir.join("nested")).unwrap();
+        let files = HashMap::from([
+            (String::from("input/app.toml"), b"[server]\nport = 8080\n".to_vec()),
+            (String::from("input/nested/users.json"), br#"{"users": ["alpha", "bravo"]}"#.to_vec()),
+            (String::from("input/empty.cfg"), vec![]),
+        ]);
+        for (name, data) in &files {
+            fs::write(dir.join(name), data).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        let total_size = files.values().map(|data| data.len() as u64).sum();
+        assert_eq!(read_all_entries(&archive_path, total_size).unwrap(), files);
+        let err = read_all_entries(&archive_path, total_size - 1).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // the same archive twice over is read as one archive whose entries are all replaced, so they're only counted once
+        let archive_bytes = fs::read(&archive_path).unwrap();
+        fs::write(&archive_path, [archive_bytes.as_slice(), archive_bytes.as_slice()].concat()).unwrap();
+        assert_eq!(read_all_entries(&archive_path, total_size).unwrap(), files);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_all_entries_counts_hardlinks_once() {
+        let dir = scratch_dir("read_all_hardlinks");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("data.bin"), "data shared by two names").unwrap();
+        fs::hard_link(input_dir.join("data.bin"), input_dir.join("link.bin")).unwrap();
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        let blocks = archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+        assert!(blocks.iter().any(|block| block.hardlink));
+
+        let entries = read_all_entries(&archive_path, "data shared by two names".len() as u64).unwrap();
+        assert_eq!(entries["input/link.bin"], entries["input/data.bin"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_handle_extracts_entries() {
+        let dir = scratch_dir("archive_handle");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        let files = [("input/alpha.txt", "alpha file"), ("input/bravo.txt", "bravo file"), ("input/charlie.txt", "charlie file")];
+        for (name, data) in &files {
+            fs::write(dir.join(name), data).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        let mut handle = ArchiveHandle::open(&format!("{}.zipr", input_path)).unwrap();
+        assert_eq!(handle.entry_names(), files.map(|(name, _)| name));
+        // read out of archive order so each read has to seek back
+        for (name, data) in files.iter().rev() {
+            assert_eq!(handle.read_entry(name).unwrap(), data.as_bytes());
+        }
+        let dest = dir.join("extracted.txt");
+        handle.extract("input/bravo.txt", dest.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "bravo file");
+        assert_eq!(handle.read_entry("missing.txt").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_entry_read_in_small_buffers() {
+        let dir = scratch_dir("block_reader");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        // skewed so the codes have different lengths and symbols straddle the byte boundaries between reads
+        let large: Vec<u8> = (0..3_000_000u64).map(|i| ((i * 2654435761) >> 7).trailing_zeros() as u8 + b'a').collect();
+        fs::write(input_dir.join("large.bin"), &large).unwrap();
+        fs::write(input_dir.join("stored.txt"), "tiny").unwrap();
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        let mut handle = ArchiveHandle::open(&format!("{}.zipr", input_path)).unwrap();
+        let mut entry = handle.open_entry("input/large.bin").unwrap();
+        let mut assembled = vec![];
+        let mut buf = [0u8; 7];
+        loop {
+            let count = entry.read(&mut buf).unwrap();
+            if count == 0 {
+                break;
+            }
+            assembled.extend_from_slice(&buf[..count]);
+        }
+        assert!(assembled == large);
+
+        let mut stored = String::new();
+        handle.open_entry("input/stored.txt").unwrap().read_to_string(&mut stored).unwrap();
+        assert_eq!(stored, "tiny");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_handle_extract_removes_corrupt_entry() {
+        let dir = scratch_dir("handle_extract_corrupt");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("notes.txt"), "the quick brown fox jumps over the lazy dog").unwrap();
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        let dest_path = dir.join("notes.txt");
+        let dest = dest_path.to_str().unwrap();
+        ArchiveHandle::open(&archive_path).unwrap().extract("input/notes.txt", dest).unwrap();
+        assert_eq!(fs::read_to_string(&dest_path).unwrap(), "the quick brown fox jumps over the lazy dog");
+        fs::remove_file(&dest_path).unwrap();
+
+        // the entry's data is at the end of the archive, so only its checksum catches the damage
+        let mut bytes = fs::read(&archive_path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x5A;
+        fs::write(&archive_path, &bytes).unwrap();
+        let err = ArchiveHandle::open(&archive_path).unwrap().extract("input/notes.txt", dest).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(!dest_path.exists(), "A corrupt entry shouldn't be left behind");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_handle_opens_from_trailing_index() {
+        let dir = scratch_dir("trailing_index");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        for i in 0..200 {
+            fs::write(input_dir.join(format!("file{:03}.txt", i)), format!("contents of file {}", i)).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        let options = ArchiveOptions { trailing_index: true, ..ArchiveOptions::default() };
+        let blocks = archive_dir(std::slice::from_ref(&input_path), &options).unwrap();
+        // the front headers still read normally past the index
+        assert_eq!(ArchiveHandle::open(&archive_path).unwrap().entry_names().len(), 200);
+
+        // break the first front header's name so only the index can find the entries
+        let mut bytes = fs::read(&archive_path).unwrap();
+        let name_pos = headers_start() + NAME_LEN_SIZE as usize;
+        let name = blocks[0].filename_rel.as_bytes();
+        assert_eq!(&bytes[name_pos..name_pos + name.len()], name);
+        bytes[name_pos] ^= 0xFF;
+        fs::write(&archive_path, &bytes).unwrap();
+        assert!(ArchiveHandle::open(&archive_path).is_err());
+        let mut handle = ArchiveHandle::open_indexed(&archive_path).unwrap();
+        assert_eq!(handle.read_entry("input/file137.txt").unwrap(), b"contents of file 137");
+
+        // an archive without an index says so
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+        let err = ArchiveHandle::open_indexed(&archive_path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_lists_entries() {
+        let dir = scratch_dir("manifest");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(input_dir.join("nested")).unwrap();
+        let files = [("input/a.txt", "first"), ("input/nested/b.txt", "the second file"), ("input/z.txt", "")];
+        for (name, data) in &files {
+            fs::write(dir.join(name), data).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        let options = ArchiveOptions { manifest: true, ..ArchiveOptions::default() };
+        archive_dir(std::slice::from_ref(&input_path), &options).unwrap();
+
+        // the manifest is the first entry and has a line with the size and name of every other entry
+        let handle = ArchiveHandle::open(&archive_path).unwrap();
+        assert_eq!(handle.entry_names()[0], MANIFEST_NAME);
+        assert_eq!(handle.entry_names().len(), files.len() + 1);
+        let manifest = String::from_utf8(read_manifest(&archive_path).unwrap()).unwrap();
+        let mut lines: Vec<(u64, &str)> = manifest.lines()
+            .map(|line| {
+                let (size, name) = line.trim_start().split_once("  ").unwrap();
+                (size.parse().unwrap(), name)
+            })
+            .collect();
+        lines.sort();
+        let mut expected: Vec<(u64, &str)> = files.iter().map(|(name, data)| (data.len() as u64, *name)).collect();
+        expected.sort();
+        assert_eq!(lines, expected);
+
+        // an archive written without one says so
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+        assert_eq!(read_manifest(&archive_path).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
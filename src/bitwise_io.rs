@@ -3,10 +3,12 @@
 // File IO using bit layer abstractions (read and write bits from a file)
 
 use std::fs::{File, OpenOptions};
-use std::io::{self, Write};
+use std::io::{self, Cursor, Write};
 use std::io::{Read, Seek, SeekFrom};
-use std::mem;
-use crate::structures::{FileBlock, SymbolCode};
+use crate::crc::Crc32;
+use crate::coder::METHOD_HUFFMAN;
+use crate::structures::{BitVec, FileBlock, SymbolCode, ATTRIBUTES_VERSION, ATTR_EXECUTABLE, CHECKSUM_VERSION, ATTR_HARDLINK, HARDLINK_VERSION};
+use crate::structures::{COUNTED_HEADERS_VERSION, METHOD_VERSION};
 
 const BUFFER_LEN: usize = 4096;
 const BUFFER_BIT_LEN: u32 = (BUFFER_LEN * 8) as u32;
@@ -20,9 +22,33 @@ pub fn get_bit(num: u32, n: u32) -> u8 {
     ((num >> n) & 1) as u8
 }
 
-pub struct FileReader {
+// the bits of a byte are read and written one at a time, so a count past 8 would silently read or write zeros
+fn check_bit_count(count: u8) -> io::Result<()> {
+    if count > 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot read or write {} bits of a byte, at most 8 fit", count)));
+    }
+    Ok(())
+}
+
+// a stream that can be read from any position, such as a file
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+// convert the stored bytes of a name to a string, names that aren't valid utf-8 are an error unless lossy names are allowed
+pub fn decode_name(name_bytes: Vec<u8>, lossy_names: bool) -> io::Result<String> {
+    match String::from_utf8(name_bytes) {
+        Ok(name) => Ok(name),
+        Err(e) if lossy_names => Ok(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "Entry name {} is not valid utf-8, use --lossy-names to replace the invalid bytes",
+            String::from_utf8_lossy(e.as_bytes())))),
+    }
+}
+
+pub struct FileReader<'a> {
     // the file stream to read from
-    file: File,
+    file: Box<dyn ReadSeek + 'a>,
     // a buffer storing a block from the file
     buffer: [u8; BUFFER_LEN],
     // the number of bytes read from the file into the buffer
@@ -31,12 +57,30 @@ pub struct FileReader {
     bit_position: u32,
     // the total number of bits read
     read_len: u64,
+    // running checksum of the bytes read since the checksum was started
+    crc: Option<Crc32>,
 }
 
-impl FileReader {
-    pub fn new(filepath: &str) -> io::Result<FileReader> {
+impl FileReader<'static> {
+    pub fn new(filepath: &str) -> io::Result<FileReader<'static>> {
         // open the file into memory
-        let mut file = File::open(filepath)?;
+        let file = File::open(filepath)?;
+        FileReader::from_stream(Box::new(file))
+    }
+}
+
+impl<'a> FileReader<'a> {
+    // read from bytes that are already in memory as if they were a file
+    pub fn from_bytes(bytes: &'a [u8]) -> io::Result<FileReader<'a>> {
+        FileReader::from_seekable(Cursor::new(bytes))
+    }
+
+    // read from any seekable stream, such as a decrypted volume or an archive nested inside another archive
+    pub fn from_seekable(stream: impl Read + Seek + 'a) -> io::Result<FileReader<'a>> {
+        FileReader::from_stream(Box::new(stream))
+    }
+
+    fn from_stream(mut file: Box<dyn ReadSeek + 'a>) -> io::Result<FileReader<'a>> {
         // read the first buffer into memory
         let mut buffer = [0u8; BUFFER_LEN];
         let read_size = file.read(&mut buffer)?;
@@ -47,12 +91,14 @@ impl FileReader {
             read_size,
             bit_position: 0,
             read_len: 0,
+            crc: None,
         })
     }
 
     fn update_buffer(&mut self) -> io::Result<()> {
-        // at end of buffer: read a new buffer
-        if self.bit_position >= BUFFER_BIT_LEN {
+        // at end of the bytes read into the buffer: read a new buffer
+        // a read can fill less than the whole buffer before the end of the file so the read size is used
+        if self.bit_position >= 8 * self.read_size as u32 && self.read_size > 0 {
             self.read_size = self.file.read(&mut self.buffer)?;
             self.bit_position = 0;
         }
@@ -68,29 +114,90 @@ impl FileReader {
         Ok(())
     }
 
+    // length in bytes of the whole stream, regardless of the position of the next read
+    pub fn stream_len(&mut self) -> io::Result<u64> {
+        let position = self.file.stream_position()?;
+        let len = self.file.seek(SeekFrom::End(0))?;
+        self.file.seek(SeekFrom::Start(position))?;
+        Ok(len)
+    }
+
     pub fn read_len(&mut self) -> u64 {
         self.read_len
     }
 
+    // the byte position in the file of the next read
+    pub fn byte_position(&mut self) -> io::Result<u64> {
+        let buffer_start = self.file.stream_position()? - self.read_size as u64;
+        Ok(buffer_start + (self.bit_position / 8) as u64)
+    }
+
     pub fn eof(&mut self) -> bool {
-        // eof: if buffer pointer goes past read size or last buffer read was empty
-        (self.bit_position > (8 * self.read_size) as u32) || self.read_size == 0
+        // a failed read isn't eof, the next read will return the error instead
+        if self.update_buffer().is_err() {
+            return false;
+        }
+        // eof: if the buffer pointer reached the read size after reading the next buffer
+        self.bit_position >= 8 * self.read_size as u32
     }
 
-    pub  fn peek_byte(&mut self) -> io::Result<u8> {
+    pub fn peek_byte(&mut self) -> io::Result<u8> {
         self.update_buffer()?;
-        let byte = self.buffer[(self.bit_position / 8) as usize];
-        Ok(byte)
+        let i = (self.bit_position / 8) as usize;
+        if i >= self.read_size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Cannot read past the end of the file"));
+        }
+        Ok(self.buffer[i])
     }
 
     pub fn read_byte(&mut self) -> io::Result<u8> {
-        let byte = self.peek_byte();
+        let byte = self.peek_byte()?;
         self.bit_position += 8;
         self.read_len += 8;
-        byte
+        if let Some(crc) = &mut self.crc {
+            crc.update(byte);
+        }
+        Ok(byte)
+    }
+
+    // fill as much of the buffer as possible, a count less than the buffer length means the end of the file was reached
+    pub fn read_into(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.bit_position.is_multiple_of(8) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot read bytes starting in the middle of a byte"));
+        }
+        let mut filled = 0;
+        while filled < buf.len() {
+            self.update_buffer()?;
+            let start = (self.bit_position / 8) as usize;
+            if start >= self.read_size {
+                break;
+            }
+            // copy what remains of the internal buffer, or as much as the caller's buffer can hold
+            let count = (self.read_size - start).min(buf.len() - filled);
+            let bytes = &mut buf[filled..(filled + count)];
+            bytes.copy_from_slice(&self.buffer[start..(start + count)]);
+            if let Some(crc) = &mut self.crc {
+                crc.update_bytes(bytes);
+            }
+            self.bit_position += 8 * count as u32;
+            self.read_len += 8 * count as u64;
+            filled += count;
+        }
+        Ok(filled)
+    }
+
+    // start a running checksum over each byte read from now on
+    pub fn start_crc(&mut self) {
+        self.crc = Some(Crc32::new());
+    }
+
+    // checksum of the bytes read since the checksum was started
+    pub fn current_crc(&self) -> u32 {
+        self.crc.map_or(Crc32::new().value(), |crc| crc.value())
     }
 
     pub fn read_bits(&mut self, count: u8) -> io::Result<u8> {
+        check_bit_count(count)?;
         // read each bit individually as they might end up in different bytes in the buffer
         let mut byte = 0;
         for i in 0..count {
@@ -106,63 +213,156 @@ impl FileReader {
         let bit = get_bit(byte as u32, self.bit_position % 8);
         self.bit_position += 1;
         self.read_len += 1;
+        // a byte read a bit at a time is added to the checksum once its last bit is read
+        if let Some(crc) = &mut self.crc {
+            if self.bit_position.is_multiple_of(8) {
+                crc.update(byte);
+            }
+        }
         Ok(bit)
     }
 
-    pub fn read_block(&mut self) -> io::Result<FileBlock> {
-        // reads string as bytes from file
-        let mut filename_rel = String::from("/");
-        let mut byte = self.read_byte()?;
-        while byte != 0 {
-            filename_rel.push(byte as char);
-            byte = self.read_byte()?;
+    // read a block header, names that aren't valid utf-8 are an error unless lossy names are allowed
+    pub fn read_block(&mut self, lossy_names: bool, version: u8) -> io::Result<FileBlock> {
+        let mut name_bytes = vec![];
+        if version >= COUNTED_HEADERS_VERSION {
+            // reads the length prefixed string as bytes from file
+            let name_len = self.read_u32()?;
+            for _ in 0..name_len {
+                name_bytes.push(self.read_byte()?);
+            }
+        } else {
+            // older archives end each name with a null byte instead
+            let mut byte = self.read_byte()?;
+            while byte != 0 {
+                name_bytes.push(byte);
+                byte = self.read_byte()?;
+            }
         }
+        let filename_rel = decode_name(name_bytes, lossy_names)?;
+        self.read_block_fields(filename_rel, version)
+    }
+
+    // read the fields of a block header that follow the name, for an archive of the format version
+    pub fn read_block_fields(&mut self, filename_rel: String, version: u8) -> io::Result<FileBlock> {
         // create block and read u64 values from file into fields
-        Ok(FileBlock {
-            filename_rel: String::from(filename_rel),
+        let mut block = FileBlock {
+            filename_rel,
             tree_bit_size: self.read_u64()?,
             data_bit_size: self.read_u64()?,
             file_byte_offset: self.read_u64()?,
             og_byte_size: self.read_u64()?,
-        })
+            // archives before checksums were added go straight from the size to the method
+            crc32: if version >= CHECKSUM_VERSION { Some(self.read_u32()?) } else { None },
+            // archives from before the method byte was added were always huffman coded
+            method: if version >= METHOD_VERSION { self.read_byte()? } else { METHOD_HUFFMAN },
+            data_offset: None,
+            model_offset: None,
+            executable: false,
+            hardlink: false,
+        };
+        // archives before attribute bytes were added don't record any attributes
+        if version >= ATTRIBUTES_VERSION {
+            let attributes = self.read_byte()?;
+            block.executable = attributes & ATTR_EXECUTABLE != 0;
+            // the bit meant nothing before hardlinks were added, so it isn't trusted in older archives
+            block.hardlink = version >= HARDLINK_VERSION && attributes & ATTR_HARDLINK != 0;
+        }
+        Ok(block)
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        let mut buffer = [0u8; 4];
+        for byte in buffer.iter_mut() {
+            *byte = self.read_byte()?;
+        }
+        Ok(u32::from_le_bytes(buffer))
     }
 
     pub fn read_u64(&mut self) -> io::Result<u64> {
         let mut buffer = [0u8; 8];
-        for i in 0..8 {
-            buffer[i] = self.read_byte()?;
+        for byte in buffer.iter_mut() {
+            *byte = self.read_byte()?;
         }
         Ok(u64::from_le_bytes(buffer))
     }
 }
 
-pub struct FileWriter {
+pub struct FileWriter<'a> {
     // the file stream to write to
-    file: File,
+    file: Box<dyn Write + 'a>,
     // a buffer storing a block to be written to the file
     buffer: [u8; BUFFER_LEN],
     // the bit position of the last write in the buffer
     bit_position: u32,
+    // running checksum of the bytes written since the checksum was started
+    crc: Option<Crc32>,
+    // a handle to the file that is synced to disk after each write, only set when durability is needed
+    sync_file: Option<File>,
+}
+
+impl FileWriter<'static> {
+    pub fn new(filepath: &str) -> io::Result<FileWriter<'static>> {
+        Ok(FileWriter::from_writer(FileWriter::open(filepath)?))
+    }
+
+    // a writer that syncs the file to disk each time the buffer is written, trading throughput for durability
+    pub fn new_synced(filepath: &str) -> io::Result<FileWriter<'static>> {
+        let file = FileWriter::open(filepath)?;
+        let sync_file = file.try_clone()?;
+        let mut writer = FileWriter::from_writer(file);
+        writer.sync_file = Some(sync_file);
+        Ok(writer)
+    }
+
+    fn open(filepath: &str) -> io::Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .append(false)
+            .create(true)
+            .truncate(true)
+            .open(filepath)
+    }
 }
 
-impl FileWriter {
-    pub fn new(filepath: &str) -> io::Result<FileWriter> {
-        Ok(FileWriter {
-            file: OpenOptions::new()
-                .write(true)
-                .append(false)
-                .create(true)
-                .open(filepath)?,
+impl<'a> FileWriter<'a> {
+    // write into any stream, the stream is only written to and never seeked
+    pub fn from_writer(file: impl Write + 'a) -> FileWriter<'a> {
+        FileWriter {
+            file: Box::new(file),
             buffer: [0u8; BUFFER_LEN],
             bit_position: 0,
-        })
+            crc: None,
+            sync_file: None,
+        }
     }
 
     fn persist_buffer(&mut self) -> io::Result<()> {
-        self.file.write(&self.buffer[0..((self.bit_position / 8) as usize)])?;
+        self.file.write_all(&self.buffer[0..((self.bit_position / 8) as usize)])?;
+        if let Some(sync_file) = &self.sync_file {
+            sync_file.sync_all()?;
+        }
         Ok(())
     }
 
+    // write and sync every complete byte if the writer syncs to disk, otherwise the bytes stay buffered
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.sync_file.is_some() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // write every complete byte to the file, keeping a partially written byte in the buffer
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.persist_buffer()?;
+        let partial_byte = self.buffer.get((self.bit_position / 8) as usize).copied().unwrap_or(0);
+        self.buffer = [0u8; BUFFER_LEN];
+        self.buffer[0] = partial_byte;
+        self.bit_position %= 8;
+        self.file.flush()
+    }
+
     fn update_buffer(&mut self) -> io::Result<()> {
         // check if at end of buffer: persist current buffer and start writing on a new one
         if self.bit_position >= BUFFER_BIT_LEN {
@@ -174,7 +374,13 @@ impl FileWriter {
     }
 
     pub fn align_to_byte(&mut self) -> io::Result<()> {
-        self.bit_position = ((self.bit_position + 7) / 8) * 8;
+        // the rest of a partly written byte is padding, which completes the byte for the checksum
+        if let Some(crc) = &mut self.crc {
+            if !self.bit_position.is_multiple_of(8) {
+                crc.update(self.buffer[(self.bit_position / 8) as usize]);
+            }
+        }
+        self.bit_position = self.bit_position.div_ceil(8) * 8;
         Ok(())
     }
 
@@ -185,10 +391,46 @@ impl FileWriter {
         self.buffer[(self.bit_position / 8) as usize] = byte;
         self.bit_position += 8;
 
+        if let Some(crc) = &mut self.crc {
+            crc.update(byte);
+        }
         Ok(())
     }
 
+    // copy whole bytes into the buffer a buffer at a time, the writer must be at the start of a byte
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if !self.bit_position.is_multiple_of(8) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot write bytes starting in the middle of a byte"));
+        }
+        let mut written = 0;
+        while written < bytes.len() {
+            self.update_buffer()?;
+            let start = (self.bit_position / 8) as usize;
+            // copy as much as the buffer has room for, or what remains of the bytes
+            let count = (BUFFER_LEN - start).min(bytes.len() - written);
+            let chunk = &bytes[written..(written + count)];
+            self.buffer[start..(start + count)].copy_from_slice(chunk);
+            if let Some(crc) = &mut self.crc {
+                crc.update_bytes(chunk);
+            }
+            self.bit_position += 8 * count as u32;
+            written += count;
+        }
+        Ok(())
+    }
+
+    // start a running checksum over each byte written from now on
+    pub fn start_crc(&mut self) {
+        self.crc = Some(Crc32::new());
+    }
+
+    // checksum of the bytes written since the checksum was started
+    pub fn current_crc(&self) -> u32 {
+        self.crc.map_or(Crc32::new().value(), |crc| crc.value())
+    }
+
     pub fn write_bits(&mut self, byte: u8, count: u8) -> io::Result<()> {
+        check_bit_count(count)?;
         // write each bit individually as they might end up in different bytes in the buffer
         for i in 0..count {
             let bit = get_bit(byte as u32, i as u32);
@@ -207,44 +449,82 @@ impl FileWriter {
         }
 
         self.bit_position += 1;
+        // a byte written a bit at a time is added to the checksum once its last bit is written
+        if let Some(crc) = &mut self.crc {
+            if self.bit_position.is_multiple_of(8) {
+                crc.update(self.buffer[(self.bit_position / 8 - 1) as usize]);
+            }
+        }
         Ok(())
     }
 
     pub fn write_symbol(&mut self, symbol: &SymbolCode) -> io::Result<()> {
         for i in 0..symbol.bit_len {
-            let bit = get_bit(symbol.encoded_symbol, i as u32);
+            let bit = ((symbol.encoded_symbol >> i) & 1) as u8;
+            self.write_bit(bit)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_bit_vec(&mut self, bits: &BitVec) -> io::Result<()> {
+        for bit in bits.iter() {
             self.write_bit(bit)?;
         }
         Ok(())
     }
 
     pub fn write_block(&mut self, block: &FileBlock) -> io::Result<()> {
-        // write string with a null terminator at the end
-        for c in block.filename_rel.chars() {
-            self.write_byte(c as u8)?;
+        // write string as utf-8 bytes after its length
+        let name_len = u32::try_from(block.filename_rel.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Entry name {} is too long", block.filename_rel)))?;
+        self.write_u32(name_len)?;
+        for &byte in block.filename_rel.as_bytes() {
+            self.write_byte(byte)?;
         }
-        self.write_byte(0)?;
+        self.write_block_fields(block)
+    }
+
+    // write the fields of a block header that follow the name
+    pub fn write_block_fields(&mut self, block: &FileBlock) -> io::Result<()> {
         // write each u64 field into the file
         self.write_u64(block.tree_bit_size)?;
         self.write_u64(block.data_bit_size)?;
         self.write_u64(block.file_byte_offset)?;
         self.write_u64(block.og_byte_size)?;
+        let crc32 = block.crc32.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+            format!("Entry {} has no checksum to write", block.filename_rel)))?;
+        self.write_u32(crc32)?;
+        self.write_byte(block.method)?;
+        let mut attributes = 0;
+        if block.executable {
+            attributes |= ATTR_EXECUTABLE;
+        }
+        if block.hardlink {
+            attributes |= ATTR_HARDLINK;
+        }
+        self.write_byte(attributes)?;
+        Ok(())
+    }
+
+    pub fn write_u32(&mut self, num: u32) -> io::Result<()> {
+        for byte in num.to_le_bytes() {
+            self.write_byte(byte)?;
+        }
         Ok(())
     }
 
     pub fn write_u64(&mut self, num: u64) -> io::Result<()> {
-        let buffer: [u8; 8] = unsafe { mem::transmute(num) };
-        for i in 0..8 {
-            self.write_byte(buffer[i])?;
+        for byte in num.to_le_bytes() {
+            self.write_byte(byte)?;
         }
         Ok(())
     }
 }
 
-impl Drop for FileWriter {
+impl Drop for FileWriter<'_> {
     fn drop(&mut self) {
         if let Err(e) = self.persist_buffer() {
-            panic!("Fatal: failed to write the buffer to file when dropping: {}", e.to_string());
+            panic!("Fatal: failed to write the buffer to file when dropping: {}", e);
         }
     }
 }
@@ -252,6 +532,8 @@ impl Drop for FileWriter {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crc::crc32;
+    use crate::testing::scratch_dir;
 
     #[test]
     fn test_bitwise() {
@@ -266,4 +548,194 @@ mod tests {
         num = set_bit(num, 2) as u32;
         assert_eq!(num, 0b01111);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_bit_counts() {
+        let mut bytes = vec![];
+        let mut writer = FileWriter::from_writer(&mut bytes);
+        writer.write_bits(0xFF, 0).unwrap();
+        writer.write_bits(0b1010_0101, 8).unwrap();
+        writer.write_bits(0b101, 3).unwrap();
+        assert_eq!(writer.write_bits(0xFF, 9).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        writer.align_to_byte().unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(bytes, [0b1010_0101, 0b101]);
+
+        let mut reader = FileReader::from_bytes(&bytes).unwrap();
+        assert_eq!(reader.read_bits(0).unwrap(), 0);
+        assert_eq!(reader.read_bits(8).unwrap(), 0b1010_0101);
+        assert_eq!(reader.read_bits(3).unwrap(), 0b101);
+        assert_eq!(reader.read_bits(9).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_running_crc_matches_crc32() {
+        let bytes: Vec<u8> = (0..100_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+
+        // the reader's checksum covers every byte however it's read, a byte, a few bits, or a buffer at a time
+        let mut reader = FileReader::from_bytes(&bytes).unwrap();
+        reader.start_crc();
+        reader.read_byte().unwrap();
+        reader.read_bits(3).unwrap();
+        reader.read_bits(5).unwrap();
+        let mut buf = vec![0u8; bytes.len() - 2];
+        assert_eq!(reader.read_into(&mut buf).unwrap(), buf.len());
+        assert_eq!(reader.current_crc(), crc32(&bytes));
+
+        // and so does the writer's, with the padding of a partly written byte counted once it's aligned
+        let mut written = vec![];
+        let mut writer = FileWriter::from_writer(&mut written);
+        writer.start_crc();
+        writer.write_bits(0b101, 3).unwrap();
+        writer.align_to_byte().unwrap();
+        for &byte in &bytes[..5000] {
+            writer.write_bits(byte, 8).unwrap();
+        }
+        writer.write_bytes(&bytes[5000..]).unwrap();
+        let crc = writer.current_crc();
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(crc, crc32(&written));
+    }
+
+    // writes a block header with the raw name bytes and reads it back
+    fn read_block_named(test_name: &str, name_bytes: &[u8], lossy_names: bool) -> io::Result<FileBlock> {
+        let dir = scratch_dir(test_name);
+        let path = dir.join("block.bin");
+        let path = path.to_str().unwrap();
+        {
+            let mut writer = FileWriter::new(path).unwrap();
+            writer.write_u32(name_bytes.len() as u32).unwrap();
+            for &byte in name_bytes {
+                writer.write_byte(byte).unwrap();
+            }
+            for field in 1..=4 {
+                writer.write_u64(field).unwrap();
+            }
+            writer.write_byte(0).unwrap();
+        }
+        // the header has no attribute byte, as in a version 3 archive
+        let block = FileReader::new(path).unwrap().read_block(lossy_names, 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+        block
+    }
+
+    #[test]
+    fn test_read_block_utf8_name() {
+        let name = "naïve/日本語.txt";
+        for lossy_names in [false, true] {
+            let block = read_block_named("utf8_name", name.as_bytes(), lossy_names).unwrap();
+            assert_eq!(block.filename_rel, name);
+            assert_eq!(block.og_byte_size, 4);
+        }
+    }
+
+    #[test]
+    fn test_read_block_invalid_utf8_name() {
+        let name = [b'a', 0xFF, 0xFE, b'b'];
+        let err = read_block_named("invalid_name_strict", &name, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("--lossy-names"));
+
+        let block = read_block_named("invalid_name_lossy", &name, true).unwrap();
+        assert_eq!(block.filename_rel, "a\u{FFFD}\u{FFFD}b");
+    }
+
+    #[test]
+    fn test_reader_stops_at_end_of_file() {
+        let dir = scratch_dir("reader_eof");
+        let path = dir.join("data.bin");
+        // a file spanning more than one buffer so reads cross a buffer refill
+        let data: Vec<u8> = (0..(BUFFER_LEN + 10)).map(|i| (i % 256) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let mut reader = FileReader::new(path.to_str().unwrap()).unwrap();
+        let mut read = vec![];
+        while !reader.eof() {
+            read.push(reader.read_byte().unwrap());
+        }
+        assert_eq!(read, data);
+        assert_eq!(reader.read_byte().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_into_chunks() {
+        let dir = scratch_dir("read_into");
+        let path = dir.join("data.bin");
+        let data: Vec<u8> = (0..(2 * BUFFER_LEN + 50)).map(|i| (i * 31 % 256) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let mut reader = FileReader::new(path.to_str().unwrap()).unwrap();
+        let mut read = vec![];
+        let mut chunk = [0u8; 100];
+        loop {
+            let count = reader.read_into(&mut chunk).unwrap();
+            read.extend_from_slice(&chunk[..count]);
+            if count < chunk.len() {
+                break;
+            }
+        }
+        assert_eq!(read, data);
+        assert!(reader.eof());
+
+        // bytes can't be copied once the reader is part way through a byte
+        let mut reader = FileReader::new(path.to_str().unwrap()).unwrap();
+        reader.read_bit().unwrap();
+        assert_eq!(reader.read_into(&mut chunk).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_header_size_matches_bytes_written() {
+        let block = FileBlock {
+            filename_rel: String::from("dir/naïve.txt"),
+            file_byte_offset: 1,
+            og_byte_size: 2,
+            crc32: Some(5),
+            tree_bit_size: 3,
+            data_bit_size: 4,
+            method: 1,
+            data_offset: None,
+            model_offset: None,
+            executable: false,
+            hardlink: false,
+        };
+        let mut header = vec![];
+        let mut fields = vec![];
+        {
+            FileWriter::from_writer(&mut header).write_block(&block).unwrap();
+            FileWriter::from_writer(&mut fields).write_block_fields(&block).unwrap();
+        }
+        assert_eq!(header.len() as u64, block.get_header_size());
+        assert_eq!(fields.len() as u64, block.get_fields_size());
+    }
+
+    #[test]
+    fn test_hardlink_bit_needs_hardlink_version() {
+        let block = FileBlock {
+            filename_rel: String::from("link.txt"),
+            file_byte_offset: 1,
+            og_byte_size: 2,
+            crc32: Some(5),
+            tree_bit_size: 3,
+            data_bit_size: 4,
+            method: 1,
+            data_offset: None,
+            model_offset: None,
+            executable: true,
+            hardlink: true,
+        };
+        let mut header = vec![];
+        FileWriter::from_writer(&mut header).write_block(&block).unwrap();
+
+        let read = FileReader::from_bytes(&header).unwrap().read_block(false, HARDLINK_VERSION).unwrap();
+        assert_eq!(read, block);
+        // an older archive's attribute byte only has the executable bit
+        let read = FileReader::from_bytes(&header).unwrap().read_block(false, HARDLINK_VERSION - 1).unwrap();
+        assert_eq!(read, FileBlock { hardlink: false, ..block });
+    }
+}
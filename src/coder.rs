@@ -0,0 +1,325 @@
+// Joseph Prichard
+// 1/5/2023
+// Entropy coders that can be plugged into the archiver, selected by the method byte stored with each block
+
+use std::io;
+use crate::bitwise_io::{FileReader, FileWriter};
+use crate::compress::{create_code_table, create_code_tree, decompress_symbol, read_tree, validate_code_table, write_tree, TABLE_SIZE};
+use crate::structures::{FileBlock, SymbolCode, Tree};
+
+pub const METHOD_HUFFMAN: u8 = 0;
+pub const METHOD_STORED: u8 = 1;
+pub const METHOD_DICTIONARY: u8 = 2;
+
+// a symbol coder builds a model of a file from its byte frequencies and uses it to encode and decode the file
+// the model is written before the encoded data so a coder for the same method can read it back to decode
+pub trait SymbolCoder: Send + Sync {
+    // the method byte identifying this coder in the archive
+    fn method(&self) -> u8;
+
+    // build the model used to encode a file from the frequency of each byte in the file
+    fn build_model(&mut self, freq_table: &[u64; TABLE_SIZE]);
+
+    // length of the serialized model in bits
+    fn model_bit_size(&self) -> u64;
+
+    // length of the encoded data in bits for the frequencies the model was built from
+    fn data_bit_size(&self) -> u64;
+
+    fn write_model(&self, writer: &mut FileWriter) -> io::Result<()>;
+
+    fn read_model(&mut self, reader: &mut FileReader) -> io::Result<()>;
+
+    // check the model built from the frequencies is consistent before anything is encoded with it
+    fn validate_model(&self, _freq_table: &[u64; TABLE_SIZE]) -> io::Result<()> {
+        Ok(())
+    }
+
+    // encode each byte from the reader into a stream of bits
+    fn encode(&self, reader: &mut FileReader, writer: &mut FileWriter) -> io::Result<()>;
+
+    // decode the bits of a block back into the original byte stream
+    fn decode(&self, reader: &mut FileReader, writer: &mut FileWriter, block: &FileBlock) -> io::Result<()>;
+
+    // decode exactly enough bits to fill the buffer, the caller keeps the buffer within what's left of the block
+    fn decode_into(&self, reader: &mut FileReader, buf: &mut [u8]) -> io::Result<()>;
+}
+
+// create an empty coder for a method byte, the model must be built or read before the coder is used
+pub fn new_coder(method: u8) -> io::Result<Box<dyn SymbolCoder>> {
+    match method {
+        METHOD_HUFFMAN => Ok(Box::new(HuffmanCoder::new())),
+        METHOD_STORED => Ok(Box::new(StoredCoder::new())),
+        METHOD_DICTIONARY => Ok(Box::new(DictionaryCoder::new())),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown compression method {}", method)))
+    }
+}
+
+pub fn parse_method(name: &str) -> Option<u8> {
+    match name {
+        "huffman" => Some(METHOD_HUFFMAN),
+        "stored" => Some(METHOD_STORED),
+        _ => None
+    }
+}
+
+pub struct HuffmanCoder {
+    root: Option<Box<Tree>>,
+    symbol_table: Box<[SymbolCode; TABLE_SIZE]>,
+    symbol_count: u64,
+    data_bit_size: u64,
+}
+
+impl HuffmanCoder {
+    pub fn new() -> HuffmanCoder {
+        HuffmanCoder {
+            root: None,
+            symbol_table: Box::new([SymbolCode::new(); TABLE_SIZE]),
+            symbol_count: 0,
+            data_bit_size: 0,
+        }
+    }
+
+    fn root(&self) -> &Tree {
+        // invariant: the model is always built or read before the tree is used
+        self.root.as_ref().expect("Expected the huffman model to be built before use")
+    }
+}
+
+impl Default for HuffmanCoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SymbolCoder for HuffmanCoder {
+    fn method(&self) -> u8 {
+        METHOD_HUFFMAN
+    }
+
+    fn build_model(&mut self, freq_table: &[u64; TABLE_SIZE]) {
+        let tree = create_code_tree(freq_table);
+        self.symbol_table = create_code_table(&tree);
+        self.symbol_count = tree.symbol_count as u64;
+        self.data_bit_size = freq_table.iter()
+            .zip(self.symbol_table.iter())
+            .map(|(freq, symbol)| freq * (symbol.bit_len as u64))
+            .sum();
+        self.root = Some(tree.root);
+    }
+
+    fn model_bit_size(&self) -> u64 {
+        // each leaf is a 1 bit followed by a byte, and each of the internal nodes is a 0 bit
+        10 * self.symbol_count - 1
+    }
+
+    fn data_bit_size(&self) -> u64 {
+        self.data_bit_size
+    }
+
+    fn write_model(&self, writer: &mut FileWriter) -> io::Result<()> {
+        write_tree(writer, self.root())
+    }
+
+    fn read_model(&mut self, reader: &mut FileReader) -> io::Result<()> {
+        self.root = Some(read_tree(reader)?);
+        Ok(())
+    }
+
+    fn validate_model(&self, freq_table: &[u64; TABLE_SIZE]) -> io::Result<()> {
+        validate_code_table(&self.symbol_table, freq_table)
+    }
+
+    fn encode(&self, reader: &mut FileReader, writer: &mut FileWriter) -> io::Result<()> {
+        while !reader.eof() {
+            let byte = reader.read_byte()?;
+            let symbol = &self.symbol_table[byte as usize];
+            writer.write_symbol(symbol)?;
+        }
+        Ok(())
+    }
+
+    fn decode(&self, reader: &mut FileReader, writer: &mut FileWriter, block: &FileBlock) -> io::Result<()> {
+        // each decoded symbol is exactly one byte of the original file
+        let root = self.root();
+        let start_read_len = reader.read_len();
+        for _ in 0..block.og_byte_size {
+            decompress_symbol(reader, writer, root)?;
+            // the read length only increases so the bits read can't underflow
+            if reader.read_len() - start_read_len > block.data_bit_size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("Compressed data for {} is longer than its block size", block.filename_rel)));
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_into(&self, reader: &mut FileReader, buf: &mut [u8]) -> io::Result<()> {
+        // each symbol is walked from the root without recursing, and the reader keeps the bit position between calls
+        let root = self.root();
+        for byte in buf.iter_mut() {
+            let mut node = root;
+            while !node.is_leaf() {
+                // invariant: a non-leaf should have left and right nodes in a full tree
+                node = if reader.read_bit()? == 0 {
+                    node.left.as_ref().expect("Expected left node to be Some")
+                } else {
+                    node.right.as_ref().expect("Expected right node to be Some")
+                };
+            }
+            *byte = node.plain_symbol;
+        }
+        Ok(())
+    }
+}
+
+// huffman codes a file with the archive's shared dictionary tree, so the block stores no tree of its own
+// the tree is written once in the archive headers, and the reader must be at it when the model is read
+pub struct DictionaryCoder {
+    huffman: HuffmanCoder,
+}
+
+impl DictionaryCoder {
+    pub fn new() -> DictionaryCoder {
+        DictionaryCoder { huffman: HuffmanCoder::new() }
+    }
+
+    // a coder for encoding with the codes of a dictionary
+    pub fn with_table(symbol_table: &[SymbolCode; TABLE_SIZE]) -> DictionaryCoder {
+        let mut huffman = HuffmanCoder::new();
+        huffman.symbol_table = Box::new(*symbol_table);
+        DictionaryCoder { huffman }
+    }
+}
+
+impl Default for DictionaryCoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SymbolCoder for DictionaryCoder {
+    fn method(&self) -> u8 {
+        METHOD_DICTIONARY
+    }
+
+    fn build_model(&mut self, freq_table: &[u64; TABLE_SIZE]) {
+        // the codes are the dictionary's, so only the size of the data depends on the file
+        self.huffman.data_bit_size = freq_table.iter()
+            .zip(self.huffman.symbol_table.iter())
+            .map(|(freq, symbol)| freq * (symbol.bit_len as u64))
+            .sum();
+    }
+
+    fn model_bit_size(&self) -> u64 {
+        0
+    }
+
+    fn data_bit_size(&self) -> u64 {
+        self.huffman.data_bit_size
+    }
+
+    fn write_model(&self, _: &mut FileWriter) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_model(&mut self, reader: &mut FileReader) -> io::Result<()> {
+        self.huffman.read_model(reader)
+    }
+
+    fn validate_model(&self, _freq_table: &[u64; TABLE_SIZE]) -> io::Result<()> {
+        // a file only uses some of the dictionary's codes, but the dictionary has a code for every byte so its whole tree is checked
+        self.huffman.validate_model(&[1; TABLE_SIZE])
+    }
+
+    fn encode(&self, reader: &mut FileReader, writer: &mut FileWriter) -> io::Result<()> {
+        self.huffman.encode(reader, writer)
+    }
+
+    fn decode(&self, reader: &mut FileReader, writer: &mut FileWriter, block: &FileBlock) -> io::Result<()> {
+        self.huffman.decode(reader, writer, block)
+    }
+
+    fn decode_into(&self, reader: &mut FileReader, buf: &mut [u8]) -> io::Result<()> {
+        self.huffman.decode_into(reader, buf)
+    }
+}
+
+// stores each byte as is, useful for data that huffman coding cannot shrink
+pub struct StoredCoder {
+    byte_size: u64,
+}
+
+impl StoredCoder {
+    pub fn new() -> StoredCoder {
+        StoredCoder { byte_size: 0 }
+    }
+
+    // a coder for a file of a known size, so the file doesn't need to be read to build the model
+    pub fn with_byte_size(byte_size: u64) -> StoredCoder {
+        StoredCoder { byte_size }
+    }
+}
+
+impl Default for StoredCoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SymbolCoder for StoredCoder {
+    fn method(&self) -> u8 {
+        METHOD_STORED
+    }
+
+    fn build_model(&mut self, freq_table: &[u64; TABLE_SIZE]) {
+        self.byte_size = freq_table.iter().sum();
+    }
+
+    fn model_bit_size(&self) -> u64 {
+        0
+    }
+
+    fn data_bit_size(&self) -> u64 {
+        8 * self.byte_size
+    }
+
+    fn write_model(&self, _: &mut FileWriter) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_model(&mut self, _: &mut FileReader) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn encode(&self, reader: &mut FileReader, writer: &mut FileWriter) -> io::Result<()> {
+        while !reader.eof() {
+            writer.write_byte(reader.read_byte()?)?;
+        }
+        Ok(())
+    }
+
+    fn decode(&self, reader: &mut FileReader, writer: &mut FileWriter, block: &FileBlock) -> io::Result<()> {
+        // the data is byte aligned so it's copied a chunk at a time instead of a byte at a time
+        let mut chunk = [0u8; 8192];
+        let mut remaining = block.og_byte_size;
+        while remaining > 0 {
+            let want = remaining.min(chunk.len() as u64) as usize;
+            let count = reader.read_into(&mut chunk[..want])?;
+            if count < want {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                    format!("Stored data for {} ends before its original size", block.filename_rel)));
+            }
+            writer.write_bytes(&chunk[..count])?;
+            remaining -= count as u64;
+        }
+        Ok(())
+    }
+
+    fn decode_into(&self, reader: &mut FileReader, buf: &mut [u8]) -> io::Result<()> {
+        if reader.read_into(buf)? < buf.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Stored data ends before its original size"));
+        }
+        Ok(())
+    }
+}
@@ -2,30 +2,70 @@
 // 1/5/2023
 // Byte-by-byte file compressor and decompressor
 
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::thread::available_parallelism;
-use std::{fs, io, path};
-use std::path::Path;
-use std::time::Instant;
+use std::{env, fs, io};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf, MAIN_SEPARATOR};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use rayon::prelude::*;
 use rayon::ThreadPool;
-use crate::structures::{FileBlock, SymbolCode, Tree};
+use crate::structures::{FileBitSize, FileBlock, SymbolCode, Tree, ARCHIVE_HEADER_SIZE, INDEX_FOOTER_SIZE, MAX_CODE_LEN, METADATA_VERSION};
+use crate::structures::{COUNTED_HEADERS_VERSION, FLAGS_VERSION, METHOD_VERSION};
 use crate::bitwise_io::{FileReader, FileWriter};
+use crate::coder::{new_coder, DictionaryCoder, StoredCoder, SymbolCoder, METHOD_DICTIONARY, METHOD_HUFFMAN, METHOD_STORED};
+use crate::dictionary::Dictionary;
+use crate::glob::matches_any;
+use crate::stats::{compare_with_gzip, write_gzip_comparison, write_stats, ArchiveStats, PhaseTimes};
+use crate::names::{decode_name_table, encode_name_table, read_name_table};
+use crate::progress::{Progress, ProgressFormat};
+use crate::file_limit::FileLimit;
+use crate::crc::crc32;
+use crate::entry_sink::{DirSink, EntryMeta, EntrySink};
 
 pub const TABLE_SIZE: usize = 256;
-pub const REC_SEP: u8 = 0x1E;
-pub const GRP_SEP: u8 = 0x1D;
 pub const SIG: u64 = str_to_u64("zipper");
+pub const FORMAT_VERSION: u8 = 7;
+// oldest format version byte that can still be read, archives from before the version byte are read as version 0
+pub const MIN_FORMAT_VERSION: u8 = 1;
+// markers of the headers of version 1 and unversioned archives, each header follows a record separator and a group separator ends them
+const REC_SEP: u8 = 0x1E;
+const GRP_SEP: u8 = 0x1D;
+// header flag set when the names are stored in a compressed name table instead of in each header
+pub const FLAG_COMPRESSED_NAMES: u8 = 0x01;
+// header flag set when an index of every header follows the data, so entries can be found from the end of the archive
+pub const FLAG_TRAILING_INDEX: u8 = 0x02;
+// header flag set when the trees of every block are stored together ahead of all of the data
+pub const FLAG_GROUPED_LAYOUT: u8 = 0x04;
+// header flag set when a dictionary tree shared by blocks follows the block count
+pub const FLAG_SHARED_DICTIONARY: u8 = 0x08;
+// offset of the dictionary tree after the signature: the version and flag bytes, the block count, then the tree's length
+const DICTIONARY_MODEL_OFFSET: u64 = (2 * std::mem::size_of::<u8>() + 2 * std::mem::size_of::<u32>()) as u64;
+// signature at the very end of an archive with a trailing index
+pub const INDEX_SIG: u64 = str_to_u64("zipridx");
+pub const DEFAULT_STORE_BELOW: u64 = 16;
+pub const DEFAULT_MIN_SAVINGS: f64 = 0.05;
 
-pub fn configure_thread_pool(multithreaded: bool, file_count: usize) -> io::Result<ThreadPool> {
-    let threads = if multithreaded {
-        let cores = available_parallelism()?.get();
-        file_count.min(cores)
-    } else {
-        1
-    };
+// jobs with fewer bytes than this run on one thread even when multithreaded, starting the threads would cost more than it saves
+pub const DEFAULT_MIN_PARALLEL_BYTES: u64 = 1024 * 1024;
+
+// most bytes of file contents kept in memory between modelling and compressing the files
+pub const DEFAULT_MAX_BUFFERED_BYTES: u64 = 256 * 1024 * 1024;
+
+// one thread per file up to the number of cores, but only for jobs with more than one file and enough bytes to be worth it
+pub fn thread_count(multithreaded: bool, file_count: usize, byte_size: u64, min_parallel_bytes: u64) -> io::Result<usize> {
+    if !multithreaded || file_count < 2 || byte_size < min_parallel_bytes {
+        return Ok(1);
+    }
+    let cores = available_parallelism()?.get();
+    Ok(file_count.min(cores))
+}
 
-    println!("Running with {} threads", threads);
+pub fn configure_thread_pool(threads: usize) -> io::Result<ThreadPool> {
+    // status goes to stderr, stdout may be carrying a streamed archive
+    eprintln!("Running with {} threads", threads);
     let tp = rayon::ThreadPoolBuilder::new()
         .num_threads(threads)
         .build()
@@ -45,446 +85,5324 @@ pub const fn str_to_u64(str: &str) -> u64 {
     u64::from_le_bytes(buffer)
 }
 
-pub fn archive_dir(input_entry: &[String], multithreaded: bool) -> io::Result<Vec<FileBlock>> {
-    let labels = get_file_labels(input_entry)?;
+// decides whether each regular file found under the entries is archived, from its path and metadata
+pub type PathFilter = Arc<Mutex<dyn FnMut(&Path, &fs::Metadata) -> bool + Send>>;
+
+// options controlling how files are compressed into an archive
+#[derive(Clone)]
+pub struct ArchiveOptions {
+    pub multithreaded: bool,
+    // method byte of the coder used to compress each file
+    pub method: u8,
+    // store the full path of each entry instead of the path relative to the entry's parent
+    pub follow_parent: bool,
+    // abort the compression if it runs longer than this, checked between files
+    pub time_limit: Option<Duration>,
+    // store names with forward slashes so the archive extracts the same on every platform
+    pub portable_paths: bool,
+    // files smaller than this many bytes are stored without building a model, the model would outweigh any savings
+    pub store_below: u64,
+    // write archive statistics as JSON to this path
+    pub stats_path: Option<String>,
+    // skip files larger than this many bytes
+    pub exclude_larger_than: Option<u64>,
+    // write each file to its own archive next to the file instead of one archive for every file
+    pub separate: bool,
+    // write an archive even if every file was excluded, otherwise it is an error
+    pub allow_empty: bool,
+    // front code and huffman code the names together instead of storing them in each header
+    pub compress_names: bool,
+    // sync the archive to disk as it is written and after each file, for durability on unreliable power
+    pub fsync: bool,
+    // report progress to stderr after each file is compressed
+    pub progress: Option<ProgressFormat>,
+    // check each code table is prefix free before it's used, this always happens in debug builds
+    pub paranoid: bool,
+    // decide whether to huffman code or store each file from the entropy of this many leading bytes
+    pub sample_size: Option<u64>,
+    // fraction of the sample that huffman coding is projected to save for a file to be compressed instead of stored
+    pub min_savings: f64,
+    // write an index of every header after the data, so an entry can be found without reading the front headers
+    pub trailing_index: bool,
+    // levels of directories to descend into below each entry, none for no limit
+    pub max_depth: Option<usize>,
+    // read the headers back and check them against the blocks before any data is written
+    pub verify_headers: bool,
+    // fail on an input that isn't a regular file, such as a fifo or a device, instead of skipping it
+    pub strict: bool,
+    // skip a file whose metadata can't be read, such as one removed while the directory is walked, instead of failing
+    pub skip_errors: bool,
+    // total bytes of input needed before multithreading is used
+    pub min_parallel_bytes: u64,
+    // report the model sizes, ratio, and compression time of each file to stderr
+    pub verbose: bool,
+    // only archive the files the filter returns true for, every file is archived when none
+    pub path_filter: Option<PathFilter>,
+    // write a text listing of every entry and its size as the first entry of the archive
+    pub manifest: bool,
+    // only archive the files whose contents differ from the entry of the same name in this earlier archive
+    pub base: Option<String>,
+    // code each file with this dictionary's tree when that's smaller than storing a tree of the file's own
+    pub dictionary: Option<Arc<Dictionary>>,
+    // write a plain text listing of each entry's name, offset, and size next to each archive
+    pub sidecar_index: bool,
+    // creation time to record in seconds since the unix epoch instead of the current time, for reproducible archives
+    pub created_at: Option<u64>,
+    // gzip each archived file in memory and print its size next to its size in the archive
+    pub compare_gzip: bool,
+    // most files the threads may have open at once, there's no limit besides the thread count when none
+    pub max_open_files: Option<usize>,
+    // files are read once and kept in memory until they're compressed while their sizes add up to less than this
+    pub max_buffered_bytes: u64,
+}
+
+impl ArchiveOptions {
+    // archive only the files a predicate returns true for, called once for each file in the order the files are found
+    pub fn with_path_filter(self, filter: impl FnMut(&Path, &fs::Metadata) -> bool + Send + 'static) -> ArchiveOptions {
+        ArchiveOptions { path_filter: Some(Arc::new(Mutex::new(filter))), ..self }
+    }
+}
+
+impl Default for ArchiveOptions {
+    fn default() -> Self {
+        ArchiveOptions {
+            multithreaded: false,
+            method: METHOD_HUFFMAN,
+            follow_parent: false,
+            time_limit: None,
+            portable_paths: true,
+            store_below: DEFAULT_STORE_BELOW,
+            stats_path: None,
+            exclude_larger_than: None,
+            separate: false,
+            allow_empty: false,
+            compress_names: false,
+            fsync: false,
+            progress: None,
+            paranoid: false,
+            sample_size: None,
+            min_savings: DEFAULT_MIN_SAVINGS,
+            trailing_index: false,
+            max_depth: None,
+            verify_headers: false,
+            strict: false,
+            skip_errors: false,
+            min_parallel_bytes: DEFAULT_MIN_PARALLEL_BYTES,
+            verbose: false,
+            path_filter: None,
+            manifest: false,
+            base: None,
+            dictionary: None,
+            sidecar_index: false,
+            created_at: None,
+            compare_gzip: false,
+            max_open_files: None,
+            max_buffered_bytes: DEFAULT_MAX_BUFFERED_BYTES,
+        }
+    }
+}
+
+pub fn archive_dir(input_entry: &[String], options: &ArchiveOptions) -> io::Result<Vec<FileBlock>> {
+    archive_dir_with_progress(input_entry, options, &mut io::stderr())
+}
+
+// archive the entries, writing progress to a stream if the options ask for progress
+pub fn archive_dir_with_progress(input_entry: &[String], options: &ArchiveOptions, progress_out: &mut dyn Write) -> io::Result<Vec<FileBlock>> {
+    let deadline = options.time_limit.map(|limit| Instant::now() + limit);
+    let scan_start = Instant::now();
+    let labels = scan_entries(input_entry, options)?;
 
     let now = Instant::now();
+    let scan_time = now - scan_start;
 
-    let tp = configure_thread_pool(multithreaded, labels.len())?;
-    let code_books = create_code_books(&labels, &tp)?;
+    let byte_size = labels.iter().map(|label| label.size).sum();
+    let tp = configure_thread_pool(thread_count(options.multithreaded, labels.len(), byte_size, options.min_parallel_bytes)?)?;
+    let mut code_books = create_code_books(&labels, options, deadline, &tp)?;
+    // separate archives hold one file each, so a listing would tell nothing the file's name doesn't
+    if options.manifest && !options.separate {
+        add_manifest(&mut code_books, options)?;
+    }
 
     let blocks = create_file_blocks(&code_books);
+    let model_time = now.elapsed();
 
-    let archive_filename = fs::canonicalize(String::from(&input_entry[0]) + ".zipr")?;
-    let archive_filename = archive_filename.to_str().unwrap();
+    let mut progress = options.progress.map(|format| {
+        let bytes_total = code_books.iter().map(|code_book| code_book.og_byte_size).sum();
+        Progress::new(format, progress_out, code_books.len(), bytes_total)
+    });
 
-    let writer = &mut FileWriter::new(archive_filename)?;
-    writer.write_u64(SIG)?;
-    write_block_headers(writer, &blocks)?;
-    compress_files(writer, &code_books)?;
+    if options.separate {
+        // each file is written to its own single block archive next to the file
+        for (code_book, block) in code_books.iter().zip(&blocks) {
+            // invariant: code books for an archive are always created from files
+            let filename_abs = code_book.filename_abs.as_ref()
+                .expect("Expected code book to be created from a file");
+            let archive_filename = String::from(filename_abs) + ".zipr";
+            write_archive(&archive_filename, std::slice::from_ref(code_book), std::slice::from_ref(block), options, deadline, &mut progress, &tp)?;
+            if options.sidecar_index {
+                write_sidecar_index(&archive_filename)?;
+            }
+            if options.compare_gzip {
+                write_gzip_comparison(&mut io::stdout().lock(), &compare_with_gzip(&archive_filename)?)?;
+            }
+        }
+    } else {
+        let archive_filename = String::from(&input_entry[0]) + ".zipr";
+        write_archive(&archive_filename, &code_books, &blocks, options, deadline, &mut progress, &tp)?;
+        if options.sidecar_index {
+            write_sidecar_index(&archive_filename)?;
+        }
+        if options.compare_gzip {
+            write_gzip_comparison(&mut io::stdout().lock(), &compare_with_gzip(&archive_filename)?)?;
+        }
+    }
 
     let elapsed = now.elapsed();
-    println!("Finished zipping in {:.2?}", elapsed);
-    println!("Wrote archive to: {}", &archive_filename);
+    eprintln!("Finished zipping in {:.2?}", elapsed);
+    if options.separate {
+        eprintln!("Wrote {} archives next to their files", blocks.len());
+    } else {
+        let archive_filename = fs::canonicalize(String::from(&input_entry[0]) + ".zipr")?;
+        eprintln!("Wrote archive to: {}", archive_filename.display());
+    }
+
+    if let Some(stats_path) = &options.stats_path {
+        let phases = PhaseTimes::new(scan_time, model_time, elapsed - model_time);
+        write_stats(stats_path, &ArchiveStats::from_blocks(&blocks, phases))?;
+    }
+
+    Ok(blocks)
+}
+
+// write a line for each entry of an archive with its name, the byte offset of its data in the archive, and its original size
+// the listing goes next to the archive with .idx appended, so tools like grep can find which archive holds a file without opening it
+pub fn write_sidecar_index(archive_filename: &str) -> io::Result<()> {
+    // the offsets are read back from the archive so the listing matches what was written
+    let blocks = get_file_blocks(&mut FileReader::new(archive_filename)?)?;
+    let listing: String = blocks.iter()
+        .map(|block| {
+            let offset = sizeof(SIG) as u64 + block.data_offset.unwrap_or(block.file_byte_offset);
+            format!("{}\t{}\t{}\n", escape_index_name(&block.filename_rel), offset, block.og_byte_size)
+        })
+        .collect();
+    fs::write(String::from(archive_filename) + ".idx", listing)
+}
+
+// escape the tabs and newlines that separate the listing's fields and lines, and the backslash that starts an escape
+pub fn escape_index_name(name: &str) -> String {
+    name.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+// archive the entries into a stream that is only ever written forward, such as a pipe to another process
+pub fn archive_to_writer(input_entry: &[String], options: &ArchiveOptions, sink: &mut dyn Write) -> io::Result<Vec<FileBlock>> {
+    if options.separate {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot write separate archives into a single stream"));
+    }
+    // the index and comparison are read back from the archive file, which a stream doesn't have
+    if options.sidecar_index {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot write a sidecar index for an archive written to a stream"));
+    }
+    if options.compare_gzip {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot compare with gzip for an archive written to a stream"));
+    }
+    if options.stats_path.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot write stats for an archive written to a stream"));
+    }
+    let deadline = options.time_limit.map(|limit| Instant::now() + limit);
+    let labels = scan_entries(input_entry, options)?;
+    let byte_size = labels.iter().map(|label| label.size).sum();
+    let tp = configure_thread_pool(thread_count(options.multithreaded, labels.len(), byte_size, options.min_parallel_bytes)?)?;
+    let mut code_books = create_code_books(&labels, options, deadline, &tp)?;
+    if options.manifest {
+        add_manifest(&mut code_books, options)?;
+    }
+    let blocks = create_file_blocks(&code_books);
+
+    let progress_out = &mut io::stderr();
+    let mut progress = options.progress.map(|format| {
+        let bytes_total = code_books.iter().map(|code_book| code_book.og_byte_size).sum();
+        Progress::new(format, progress_out, code_books.len(), bytes_total)
+    });
+    // there's no archive file to stage files compressed in parallel next to, so they're staged in the temporary directory
+    let tmp_prefix = std::env::temp_dir().join(format!("zipper_stream_{}", std::process::id()));
+    let tmp_prefix = tmp_prefix.to_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Temporary directory path is not valid unicode"))?;
 
+    let mut writer = FileWriter::from_writer(sink);
+    write_archive_to(&mut writer, &code_books, &blocks, options, deadline, &mut progress, (tmp_prefix, &tp))?;
+    writer.flush()?;
     Ok(blocks)
 }
 
-pub fn list_file_blocks(blocks: &[FileBlock]) {
-    println!("{:>15}\t\t{:>15}\t\t{:>8}\t\t{:25}", "compressed", "uncompressed", "ratio", "uncompressed_name");
+// find the files to archive, an archive of no files is an error unless the options allow it
+fn scan_entries(input_entry: &[String], options: &ArchiveOptions) -> io::Result<Vec<FileLabel>> {
+    let mut labels = get_file_labels(input_entry, options)?;
+    exclude_archive_outputs(input_entry, options, &mut labels)?;
+    if let Some(base) = &options.base {
+        exclude_unchanged(base, &mut labels)?;
+    }
+    // each separate archive holds a single file, so there's no earlier file in it to link to
+    if !options.separate {
+        find_hardlinks(&mut labels);
+    }
+    if labels.is_empty() && !options.allow_empty {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "No files matched the inputs, use --allow-empty to write an empty archive"));
+    }
+    Ok(labels)
+}
+
+pub(crate) fn write_archive(
+    archive_filename: &str,
+    code_books: &[CodeBook],
+    blocks: &[FileBlock],
+    options: &ArchiveOptions,
+    deadline: Option<Instant>,
+    progress: &mut Option<Progress>,
+    tp: &ThreadPool,
+) -> io::Result<()> {
+    write_atomically(archive_filename, options.fsync, |writer| {
+        write_archive_to(writer, code_books, blocks, options, deadline, progress, (archive_filename, tp))
+    })
+}
+
+// write an archive strictly front to back, every offset is known before the headers are written so nothing is patched afterwards
+// files compressed in parallel are staged in temporary files named after the prefix before being copied in order
+fn write_archive_to(
+    writer: &mut FileWriter,
+    code_books: &[CodeBook],
+    blocks: &[FileBlock],
+    options: &ArchiveOptions,
+    deadline: Option<Instant>,
+    progress: &mut Option<Progress>,
+    (tmp_prefix, tp): (&str, &ThreadPool),
+) -> io::Result<()> {
+    let mut flags = 0;
+    if options.compress_names {
+        flags |= FLAG_COMPRESSED_NAMES;
+    }
+    if options.trailing_index {
+        flags |= FLAG_TRAILING_INDEX;
+    }
+    // the dictionary is only stored when a file was coded with it
+    let dictionary = options.dictionary.as_ref()
+        .filter(|_| code_books.iter().any(|code_book| code_book.method() == METHOD_DICTIONARY))
+        .map(|dictionary| dictionary.tree_bytes());
+    if dictionary.is_some() {
+        flags |= FLAG_SHARED_DICTIONARY;
+    }
+    let mut metadata = ArchiveMetadata::current();
+    metadata.created_at = options.created_at.unwrap_or(metadata.created_at);
+    writer.write_u64(SIG)?;
+    let (placed_blocks, data_end) = if options.verify_headers {
+        write_verified_headers(writer, blocks, flags, dictionary, &metadata)?
+    } else {
+        write_block_headers(writer, blocks, flags, dictionary, &metadata)?
+    };
+    if tp.current_num_threads() > 1 && code_books.len() > 1 {
+        compress_files_parallel(writer, code_books, tmp_prefix, deadline, progress, options, tp)?;
+    } else {
+        compress_files(writer, code_books, deadline, progress, options.verbose)?;
+    }
+    if options.trailing_index {
+        write_trailing_index(writer, &placed_blocks, data_end)?;
+    }
+    Ok(())
+}
+
+// write to a temporary file next to the destination and only rename it to the destination once every byte is written
+// an interrupted or failed write leaves the destination as it was instead of leaving a partial archive
+pub fn write_atomically<F>(filename: &str, fsync: bool, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut FileWriter) -> io::Result<()>,
+{
+    let tmp_filename = format!("{}.tmp", filename);
+    let writer = if fsync { FileWriter::new_synced(&tmp_filename) } else { FileWriter::new(&tmp_filename) };
+    let result = writer.and_then(|mut writer| {
+        write(&mut writer)?;
+        writer.flush()
+    });
+    match result {
+        Ok(()) => fs::rename(&tmp_filename, filename),
+        Err(e) => {
+            // the write already failed so a failure to clean up isn't reported over it
+            let _ = fs::remove_file(&tmp_filename);
+            Err(e)
+        }
+    }
+}
+
+// the column a listing is sorted by
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListSortKey {
+    Name,
+    Compressed,
+    Uncompressed,
+    Ratio,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ListSort {
+    pub key: ListSortKey,
+    pub descending: bool,
+}
+
+impl ListSort {
+    // parse a key with an optional :asc or :desc suffix, ascending by default
+    pub fn parse(value: &str) -> Option<ListSort> {
+        let (key, order) = value.split_once(':').unwrap_or((value, "asc"));
+        let key = match key {
+            "name" => ListSortKey::Name,
+            "compressed" => ListSortKey::Compressed,
+            "uncompressed" => ListSortKey::Uncompressed,
+            "ratio" => ListSortKey::Ratio,
+            _ => return None
+        };
+        let descending = match order {
+            "asc" => false,
+            "desc" => true,
+            _ => return None
+        };
+        Some(ListSort { key, descending })
+    }
+}
+
+// a sorted copy of the blocks for listing, blocks that compare equal stay in archive order
+pub fn sort_file_blocks(blocks: &[FileBlock], sort: ListSort) -> Vec<FileBlock> {
+    let compressed = |block: &FileBlock| block.tree_bit_size + block.data_bit_size;
+    let ratio = |block: &FileBlock| compressed(block) as f64 / (8 * block.og_byte_size) as f64;
+    let mut sorted = blocks.to_vec();
+    sorted.sort_by(|a, b| {
+        let ordering = match sort.key {
+            ListSortKey::Name => a.filename_rel.cmp(&b.filename_rel),
+            ListSortKey::Compressed => compressed(a).cmp(&compressed(b)),
+            ListSortKey::Uncompressed => a.og_byte_size.cmp(&b.og_byte_size),
+            ListSortKey::Ratio => ratio(a).total_cmp(&ratio(b)),
+        };
+        if sort.descending { ordering.reverse() } else { ordering }
+    });
+    sorted
+}
+
+pub fn list_file_blocks(blocks: &[FileBlock], grouped: bool) {
+    let stdout = &mut io::stdout().lock();
+    write_file_blocks(stdout, blocks, grouped).expect("Cannot write file block listing to stdout");
+}
 
+// write the listing of blocks in archive order, one line per block after the column titles
+// grouped listings separate the thousands in the sizes with commas
+pub fn write_file_blocks(out: &mut impl Write, blocks: &[FileBlock], grouped: bool) -> io::Result<()> {
+    writeln!(out, "{:>15}\t\t{:>15}\t\t{:>8}\t\t{:25}", "compressed", "uncompressed", "ratio", "uncompressed_name")?;
+
+    let format_size = |size: u64| if grouped { group_thousands(size) } else { size.to_string() };
     for block in blocks {
         let total_byte_size = (block.data_bit_size + block.tree_bit_size) / 8;
         let ratio_str = format!("{:.2}%", (total_byte_size as f64) / (block.og_byte_size as f64) * 100.0);
 
-        println!("{:>15}\t\t{:>15}\t\t{:>8}\t\t{:25}", total_byte_size, block.og_byte_size, &ratio_str, &block.filename_rel);
+        writeln!(out, "{:>15}\t\t{:>15}\t\t{:>8}\t\t{:25}", format_size(total_byte_size), format_size(block.og_byte_size), &ratio_str, &block.filename_rel)?;
     }
-    println!();
+    writeln!(out)
+}
+
+// the digits of a number with a comma between each group of three, the same in every locale
+pub fn group_thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
 }
 
 struct FileLabel {
     filename_abs: String,
     filename_rel: String,
     size: u64,
+    executable: bool,
+    // device and inode of a file with more than one link
+    file_id: Option<(u64, u64)>,
+    // index of the earlier label for the same file when this file is a hardlink to it
+    hardlink_of: Option<usize>,
 }
 
-// get file system metadata for the files to be compressed
-fn get_file_labels(entries: &[String]) -> io::Result<Vec<FileLabel>> {
+// get file system metadata for the files to be compressed, labels keep the order of the entries
+fn get_file_labels(entries: &[String], options: &ArchiveOptions) -> io::Result<Vec<FileLabel>> {
     let mut labels = vec![];
     for entry in entries {
-        let path = Path::new(entry);
-        let base_path = path.parent().unwrap_or_else(|| Path::new(""));
-        walk_path(base_path, path, &mut labels)?;
+        if options.follow_parent {
+            // the root is the base path so the relative name is the full path of the entry
+            let path = normalize_path(&std::path::absolute(entry)?);
+            walk_path(Path::new(""), &path, options, &mut labels)?;
+        } else {
+            let path = Path::new(entry);
+            walk_path(entry_base_path(path), path, options, &mut labels)?;
+        }
+    }
+    if let Some(max_size) = options.exclude_larger_than {
+        let count = labels.len();
+        labels.retain(|label| label.size <= max_size);
+        let skipped = count - labels.len();
+        if skipped > 0 {
+            eprintln!("Skipped {} files larger than {} bytes", skipped, max_size);
+        }
+    }
+    if options.portable_paths {
+        for label in &mut labels {
+            label.filename_rel = to_portable_path(&label.filename_rel, MAIN_SEPARATOR);
+        }
     }
     Ok(labels)
 }
 
-fn walk_path(base_path: &Path, path: &Path, labels: &mut Vec<FileLabel>) -> io::Result<()> {
+// drop the . and .. components of an absolute path without following any links, so ../b/f.txt is named by where it really is
+// an archive refuses to extract a name with a .. component, so a full path has to be stored without them
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            // the parent of the root is the root itself
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+// the archive being written may be inside a directory being archived, so it would include itself while it grows
+fn exclude_archive_outputs(entries: &[String], options: &ArchiveOptions, labels: &mut Vec<FileLabel>) -> io::Result<()> {
+    let outputs: Vec<String> = if options.separate {
+        labels.iter().map(|label| String::from(&label.filename_abs) + ".zipr").collect()
+    } else {
+        vec![String::from(&entries[0]) + ".zipr"]
+    };
+    let outputs = outputs.iter()
+        .map(std::path::absolute)
+        .collect::<io::Result<HashSet<_>>>()?;
+
+    let mut result = Ok(());
+    labels.retain(|label| match std::path::absolute(&label.filename_abs) {
+        Ok(path) if outputs.contains(&path) => {
+            eprintln!("Skipping {} since it is the archive being written", label.filename_abs);
+            false
+        }
+        Ok(_) => true,
+        Err(e) => {
+            result = Err(e);
+            true
+        }
+    });
+    result
+}
+
+// drop the files whose size and checksum match the entry of the same name in a base archive, leaving a delta of the changes
+// modification times aren't trusted since copies and checkouts reset them, so the contents are compared instead
+fn exclude_unchanged(base_filepath: &str, labels: &mut Vec<FileLabel>) -> io::Result<()> {
+    let reader = &mut FileReader::new(base_filepath)?;
+    let base_blocks: HashMap<String, FileBlock> = read_file_blocks(reader, false)?
+        .into_iter()
+        .map(|block| (block.filename_rel.clone(), block))
+        .collect();
+
+    let mut result = Ok(());
+    labels.retain(|label| {
+        let Some(block) = base_blocks.get(&label.filename_rel).filter(|block| block.og_byte_size == label.size) else {
+            return true;
+        };
+        match (file_crc(&label.filename_abs), block_crc(block, reader)) {
+            (Ok(file_crc), Ok(block_crc)) => file_crc != block_crc,
+            (Err(e), _) | (_, Err(e)) => {
+                result = Err(e);
+                true
+            }
+        }
+    });
+    result
+}
+
+// convert a path using a native separator into a stored name using forward slashes
+pub fn to_portable_path(path: &str, separator: char) -> String {
+    path.replace(separator, "/")
+}
+
+// convert a stored name using forward slashes back into a path using a native separator
+pub fn from_portable_path(name: &str, separator: char) -> String {
+    name.replace('/', &separator.to_string())
+}
+
+// every file under a root in the order it would be archived, a root that is a file is returned by itself
+pub fn collect_files(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    walk_files(root, None, &mut |path| {
+        files.push(path.to_path_buf());
+        Ok(())
+    })?;
+    Ok(files)
+}
+
+// visit each file under a path, directories are followed through symlinks
+// a max depth of 0 only visits the files directly inside the path, each level deeper allows another level of directories
+fn walk_files(path: &Path, max_depth: Option<usize>, visit: &mut impl FnMut(&Path) -> io::Result<()>) -> io::Result<()> {
+    walk_files_within(path, max_depth, &mut vec![], visit)
+}
+
+// the canonical paths of the directories being walked are kept, so a symlink back to one of them isn't followed forever
+fn walk_files_within(path: &Path, max_depth: Option<usize>, ancestors: &mut Vec<PathBuf>, visit: &mut impl FnMut(&Path) -> io::Result<()>) -> io::Result<()> {
     if path.is_dir() {
-        for entry in fs::read_dir(path)? {
-            let entry = entry?;
-            let path = entry.path();
-            walk_path(&base_path, &path, labels)?;
+        let canonical = fs::canonicalize(path)?;
+        if ancestors.contains(&canonical) {
+            eprintln!("Skipped {}, it links back to a directory it is inside", path.display());
+            return Ok(());
         }
+        // directory listings are in filesystem order so sort them to keep archives predictable
+        let mut paths = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        paths.sort();
+        ancestors.push(canonical);
+        for path in paths {
+            if path.is_dir() && max_depth == Some(0) {
+                continue;
+            }
+            walk_files_within(&path, max_depth.map(|depth| depth.saturating_sub(1)), ancestors, visit)?;
+        }
+        ancestors.pop();
         Ok(())
     } else {
+        visit(path)
+    }
+}
+
+fn walk_path(base_path: &Path, path: &Path, options: &ArchiveOptions, labels: &mut Vec<FileLabel>) -> io::Result<()> {
+    walk_files(path, options.max_depth, &mut |path| {
+        // a live directory can lose a file or deny access to it between listing it and reading its metadata
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if options.skip_errors => {
+                eprintln!("Skipped {}, cannot read its metadata: {}", path.display(), e);
+                return Ok(());
+            }
+            Err(e) => return Err(io::Error::new(e.kind(), format!("Cannot read metadata of {}: {}", path.display(), e))),
+        };
+        // reading a fifo blocks until something writes to it and a device may never end, so only regular files are read
+        if !metadata.file_type().is_file() {
+            if options.strict {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("Cannot archive {}, it is not a regular file", path.display())));
+            }
+            eprintln!("Skipped {}, it is not a regular file", path.display());
+            return Ok(());
+        }
+        if let Some(filter) = &options.path_filter {
+            // a filter that panicked before has no state worth protecting, so it's used as is
+            let mut filter = filter.lock().unwrap_or_else(|e| e.into_inner());
+            if !(*filter)(path, &metadata) {
+                return Ok(());
+            }
+        }
+
         // invariant: a valid path is also a valid string in this context
         let filename_abs = String::from(path.to_str()
             .expect("Expected file path to be valid string"));
 
-        // invariant: the base path must be a valid prefix of the path and an empty string is always a valid prefix
-        let filename_rel = String::from(path
-            .strip_prefix(base_path)
-            .expect("Expected base path to be a valid prefix of lower path")
-            .to_str()
-            .expect("Expected file path to be valid string"));
+        let filename_rel = relative_name(base_path, path);
 
-        let size = dir_entry_size(&path);
-        let file = FileLabel { filename_abs, filename_rel, size };
+        let file = FileLabel { filename_abs, filename_rel, size: metadata.len(), executable: is_executable(&metadata), file_id: file_id(&metadata), hardlink_of: None };
         labels.push(file);
         Ok(())
-    }
+    })
 }
 
-pub fn dir_entry_size(path: &Path) -> u64 {
-    let mut size = 0;
-    if path.is_dir() {
-        for entry in fs::read_dir(path).expect("Can't read directory") {
-            let entry = entry.expect("Entry is invalid");
-            let path = entry.path();
-            size += dir_entry_size(&path);
+// files with the same device and inode are hardlinks to the same data, only files with more than one link can share them
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// point each hardlink at the first label for the same file, so the file's data is only stored once
+// empty files are left alone since there's no data to share and their blocks can't be told apart by offset
+fn find_hardlinks(labels: &mut [FileLabel]) {
+    let mut first_labels = HashMap::new();
+    for (i, label) in labels.iter_mut().enumerate() {
+        if let Some(file_id) = label.file_id.filter(|_| label.size > 0) {
+            let first = *first_labels.entry(file_id).or_insert(i);
+            if first != i {
+                label.hardlink_of = Some(first);
+            }
         }
-    } else {
-        size += path.metadata().expect("Can't get metadata").len();
     }
-    size
 }
 
-// a codebook is an instruction set specifying what to compress and how it should be done
-struct CodeBook<'a> {
-    label: &'a FileLabel,
-    symbol_table: Box<[SymbolCode; TABLE_SIZE]>,
-    tree: CodeTree,
-    freq_table: Box<[u64; TABLE_SIZE]>,
+// only the owner's execute bit is stored, it's the part of the mode that matters for scripts and is portable to restore
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o100 != 0
 }
 
-fn create_code_books<'a>(labels: &'a [FileLabel], tp: &ThreadPool) -> io::Result<Vec<CodeBook<'a>>> {
-    // create code books, this operation can be parallelized because it only reads
-    tp.install(|| {
-        labels.into_par_iter()
-            .map(|label| create_code_book(label))
-            .collect()
-    })
+#[cfg(not(unix))]
+fn is_executable(_: &fs::Metadata) -> bool {
+    false
 }
 
-// create a codebook from the intermediate file block argument
-fn create_code_book(label: &FileLabel) -> io::Result<CodeBook> {
-    let reader = &mut FileReader::new(&label.filename_abs)?;
-    let freq_table = create_freq_table(reader)?;
-    let tree = create_code_tree(freq_table.as_ref());
-    let symbol_table = create_code_table(&tree);
-    Ok(CodeBook { label, symbol_table, tree, freq_table })
+// mark an extracted file executable by whoever can read it, like chmod +x
+#[cfg(unix)]
+pub(crate) fn set_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(mode | ((mode & 0o444) >> 2));
+    fs::set_permissions(path, permissions)
 }
 
-fn create_freq_table(reader: &mut FileReader) -> io::Result<Box<[u64; TABLE_SIZE]>> {
-    let mut freq_table = [0u64; TABLE_SIZE];
-    // iterate through each byte in the file and increment count
-    while !reader.eof() {
-        let byte = reader.read_byte()?;
-        freq_table[byte as usize] += 1;
-    }
-    Ok(Box::new(freq_table))
+// there's no executable bit to restore on other platforms
+#[cfg(not(unix))]
+pub(crate) fn set_executable(_: &Path) -> io::Result<()> {
+    Ok(())
 }
 
-pub struct CodeTree {
-    pub root: Box<Tree>,
-    pub symbol_count: u32,
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
 }
 
-fn create_code_tree(freq_table: &[u64]) -> CodeTree {
-    let mut heap = BinaryHeap::new();
+#[cfg(not(unix))]
+fn set_mode(_: &Path, _: u32) -> io::Result<()> {
+    Ok(())
+}
 
-    // add the frequency table nodes to priority queue
-    let mut symbol_count = 0;
-    for i in 0..TABLE_SIZE {
-        let freq = freq_table[i];
-        if freq != 0 {
-            heap.push(Box::new(Tree::leaf(i as u8, freq)));
-            symbol_count += 1;
-        }
+// a forced mode replaces whatever the archive stored about the file
+pub(crate) fn apply_file_mode(path: &Path, block: &FileBlock, options: &ExtractOptions) -> io::Result<()> {
+    match options.file_mode {
+        Some(mode) => set_mode(path, mode),
+        None if block.executable => set_executable(path),
+        None => Ok(()),
     }
+}
 
-    // huffman coding algorithm
-    while heap.len() >= 2 {
-        // invariant: the heap should never have 1 or 0 elements at this point
-        let first_node = heap.pop()
-            .expect("Expected first node to be Some after checking length");
-        let second_node = heap.pop()
-            .expect("Expected second node to be Some after checking length");
-        let w = first_node.weight + second_node.weight;
-        heap.push(Box::new(Tree::internal(first_node, second_node, 0, w)));
+// set every directory between the output directory and the extracted files to the forced mode
+// the deepest directories are set first, so a mode that takes away access to a directory doesn't stop its children being set
+fn apply_dir_mode(paths: &[PathBuf], output_dir: &str, mode: u32) -> io::Result<()> {
+    let output_dir = Path::new(output_dir);
+    let mut dirs: Vec<&Path> = paths.iter()
+        .flat_map(|path| path.ancestors().skip(1).take_while(|dir| *dir != output_dir && dir.starts_with(output_dir)))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    for dir in dirs {
+        set_mode(dir, mode)?;
     }
+    Ok(())
+}
 
-    // invariant: the heap should not be empty after the huffman coding algorithm is finished
-    let root = heap.pop()
-        .expect("Expected heap to have at least one element after huffman coding algorithm");
-    CodeTree { root, symbol_count }
+// the directory an entry's names are relative to, its parent so the entry's own name starts each name
+// a bare file.txt has an empty parent, so it is named the same as ./file.txt or /abs/file.txt
+fn entry_base_path(path: &Path) -> &Path {
+    path.parent().unwrap_or_else(|| Path::new(""))
 }
 
-fn create_code_table(tree: &CodeTree) -> Box<[SymbolCode; TABLE_SIZE]> {
-    let symbol_code = SymbolCode::new();
-    let mut symbol_table = [symbol_code; TABLE_SIZE];
-    walk_code_tree(&tree.root, symbol_code, &mut symbol_table);
-    Box::new(symbol_table)
+// name of a path relative to a base, dropping any . components so ./dir/file.txt and dir/file.txt are named the same
+fn relative_name(base_path: &Path, path: &Path) -> String {
+    // invariant: the base path must be a valid prefix of the path and an empty string is always a valid prefix
+    let relative: PathBuf = path
+        .strip_prefix(base_path)
+        .expect("Expected base path to be a valid prefix of lower path")
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect();
+    String::from(relative.to_str().expect("Expected file path to be valid string"))
 }
 
-fn walk_code_tree(node: &Box<Tree>, mut symbol_code: SymbolCode, symbol_table: &mut [SymbolCode]) {
-    if node.is_leaf() {
-        symbol_code.plain_symbol = node.plain_symbol;
-        symbol_table[node.plain_symbol as usize] = symbol_code;
+// total size of the files under a path, failing with the path of the first file whose metadata can't be read
+// when errors are skipped, a file whose metadata can't be read counts as empty, the same as walking skips it
+pub fn dir_entry_size(path: &Path, skip_errors: bool) -> io::Result<u64> {
+    let mut size = 0;
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            size += dir_entry_size(&entry?.path(), skip_errors)?;
+        }
+    } else {
+        match path.metadata() {
+            Ok(metadata) => size += metadata.len(),
+            Err(e) if skip_errors => eprintln!("Skipped {}, cannot read its metadata: {}", path.display(), e),
+            Err(e) => return Err(io::Error::new(e.kind(), format!("Cannot read metadata of {}: {}", path.display(), e))),
+        }
     }
-    if let Some(left) = &node.left {
-        let symbol_code = symbol_code.append_bit(0);
-        walk_code_tree(left, symbol_code, symbol_table);
+    Ok(size)
+}
+
+// a codebook is an instruction set specifying what to compress and how it should be done
+pub struct CodeBook {
+    // relative name of the file in the archive
+    pub filename_rel: String,
+    // path the file is read from when it is compressed, none for a codebook built from memory
+    pub filename_abs: Option<String>,
+    pub og_byte_size: u64,
+    // crc32 of the file's bytes, taken while they're counted
+    pub crc: u32,
+    // frequency of each byte in the file, or only in the sample for a file stored because of its sample
+    pub freq_table: Box<[u64; TABLE_SIZE]>,
+    // whether the owner can execute the file
+    pub executable: bool,
+    // index of the code book of the file this one is a hardlink to, a hardlink has no data of its own to compress
+    pub hardlink_of: Option<usize>,
+    // the file's bytes kept when it was read, so it's compressed from them instead of read from its path again
+    contents: Option<Contents>,
+    coder: Box<dyn SymbolCoder>,
+}
+
+impl CodeBook {
+    // create a huffman codebook for a file whose contents are already in memory
+    pub fn from_bytes(name: &str, data: &[u8]) -> CodeBook {
+        // invariant: the huffman method always has a coder
+        CodeBook::from_bytes_with_method(name, data, METHOD_HUFFMAN)
+            .expect("Expected huffman coder to exist")
     }
-    if let Some(right) = &node.right {
-        let symbol_code = symbol_code.append_bit(1);
-        walk_code_tree(right, symbol_code, symbol_table);
+
+    fn from_bytes_with_method(name: &str, data: &[u8], method: u8) -> io::Result<CodeBook> {
+        let mut freq_table = [0u64; TABLE_SIZE];
+        for &byte in data {
+            freq_table[byte as usize] += 1;
+        }
+        CodeBook::from_freq_table(name, None, data.len() as u64, crc32(data), Box::new(freq_table), method)
     }
-}
 
-// create the file blocks to be put into the archive - missing the offset this is calculated at write time
-fn create_file_blocks(code_books: &[CodeBook]) -> Vec<FileBlock> {
-    let mut blocks = vec![];
-    for code_book in code_books {
-        let mut tree_bit_size = 0u64;
-        let mut data_bit_size = 0u64;
-
-        // calculate the bit size for the file block for compressed data and for tree
-        let mut char_count = 0;
-        for i in 0..TABLE_SIZE {
-            let freq = code_book.freq_table[i];
-            data_bit_size += freq * (code_book.symbol_table[i].bit_len as u64);
-            if freq > 0 {
-                char_count += 1;
-            }
+    fn from_freq_table(
+        filename_rel: &str,
+        filename_abs: Option<&str>,
+        og_byte_size: u64,
+        crc: u32,
+        freq_table: Box<[u64; TABLE_SIZE]>,
+        method: u8,
+    ) -> io::Result<CodeBook> {
+        let mut coder = new_coder(method)?;
+        coder.build_model(&freq_table);
+        Ok(CodeBook {
+            filename_rel: String::from(filename_rel),
+            filename_abs: filename_abs.map(String::from),
+            og_byte_size,
+            crc,
+            freq_table,
+            executable: false,
+            hardlink_of: None,
+            contents: None,
+            coder,
+        })
+    }
+
+    // switch to the dictionary's codes when they take fewer bits than a tree of the file's own and the data coded with it
+    fn prefer_dictionary(&mut self, dictionary: &Dictionary) {
+        let mut coder = DictionaryCoder::with_table(dictionary.symbol_table());
+        coder.build_model(&self.freq_table);
+        if coder.data_bit_size() < self.coder.model_bit_size() + self.coder.data_bit_size() {
+            self.coder = Box::new(coder);
         }
-        tree_bit_size += 10 * char_count - 1;
+    }
 
-        let block = FileBlock {
-            filename_rel: String::from(&code_book.label.filename_rel),
-            file_byte_offset: 0,
-            og_byte_size: code_book.label.size,
-            tree_bit_size,
-            data_bit_size,
-        };
-        blocks.push(block);
+    // a code book that keeps the bytes it was built from, so the file is compressed from memory without a path to read
+    pub(crate) fn from_contents(name: &str, contents: Vec<u8>, method: u8) -> io::Result<CodeBook> {
+        let mut code_book = CodeBook::from_bytes_with_method(name, &contents, method)?;
+        code_book.contents = Some(Contents::Memory(contents));
+        Ok(code_book)
     }
-    blocks
-}
 
-fn write_block_headers(writer: &mut FileWriter, blocks: &[FileBlock]) -> io::Result<()> {
-    // calculate the total block size for the header, including the grp sep byte
-    let mut header_size = 1;
-    for block in blocks {
-        // header size plus an additional rec sep byte
-        let block = &block;
-        header_size += block.get_header_size() + 1;
+    // a code book for a stream such as a tar member, read once and kept in memory or a spill file the same as a file on disk
+    pub(crate) fn from_stream(name: &str, source: &mut impl Read, size: u64, method: u8, budget: &AtomicU64) -> io::Result<CodeBook> {
+        let contents = Contents::read(source, size, budget)?;
+        let mut reader = contents.reader()?;
+        reader.start_crc();
+        let freq_table = create_freq_table(&mut reader)?;
+        let (byte_size, crc) = (reader.read_len() / 8, reader.current_crc());
+        drop(reader);
+        let mut code_book = CodeBook::from_freq_table(name, None, byte_size, crc, freq_table, method)?;
+        code_book.contents = Some(contents);
+        Ok(code_book)
     }
 
-    let mut total_offset = 0;
-    for block in blocks {
-        // write record sep to identify start of record
-        writer.write_byte(REC_SEP)?;
+    // a code book for a file whose sample was too random to compress, the rest of the file is only read for its checksum
+    fn stored_from_sample(label: &FileLabel, crc: u32, sample_freq_table: Box<[u64; TABLE_SIZE]>) -> CodeBook {
+        CodeBook {
+            filename_rel: String::from(&label.filename_rel),
+            filename_abs: Some(String::from(&label.filename_abs)),
+            og_byte_size: label.size,
+            crc,
+            freq_table: sample_freq_table,
+            executable: label.executable,
+            hardlink_of: None,
+            contents: None,
+            coder: Box::new(StoredCoder::with_byte_size(label.size)),
+        }
+    }
 
-        // calculate the offset of the compressed data using values from all previous file blocks
-        let mut block = block.clone();
-        block.file_byte_offset = header_size + total_offset;
-        total_offset += 1 + (block.data_bit_size + block.tree_bit_size) / 8;
+    // a code book for a hardlink to an earlier file, the file is never read since its data is stored for the earlier file
+    fn hardlink(label: &FileLabel, target: usize) -> CodeBook {
+        CodeBook {
+            filename_rel: String::from(&label.filename_rel),
+            filename_abs: Some(String::from(&label.filename_abs)),
+            og_byte_size: label.size,
+            // a hardlink's block takes its checksum from the block it links to
+            crc: 0,
+            freq_table: Box::new([0u64; TABLE_SIZE]),
+            executable: label.executable,
+            hardlink_of: Some(target),
+            contents: None,
+            coder: Box::new(StoredCoder::with_byte_size(0)),
+        }
+    }
 
-        writer.write_block(&block)?;
+    // number of distinct bytes in the file
+    pub fn symbol_count(&self) -> u64 {
+        self.freq_table.iter().filter(|&&freq| freq > 0).count() as u64
     }
-    // write group sep after headers are complete
-    writer.write_byte(GRP_SEP)?;
-    Ok(())
-}
 
-fn compress_files(writer: &mut FileWriter, code_books: &[CodeBook]) -> io::Result<()> {
-    for code_book in code_books {
-        write_tree(writer, &code_book.tree.root)?;
+    pub fn method(&self) -> u8 {
+        self.coder.method()
+    }
 
-        let reader = &mut FileReader::new(&code_book.label.filename_abs)?;
-        while !reader.eof() {
-            let byte = reader.read_byte()?;
-            let symbol = &code_book.symbol_table[byte as usize];
-            writer.write_symbol(symbol)?;
-        }
+    pub fn tree_bit_size(&self) -> u64 {
+        self.coder.model_bit_size()
+    }
 
-        writer.align_to_byte()?;
+    pub fn data_bit_size(&self) -> u64 {
+        self.coder.data_bit_size()
     }
-    Ok(())
 }
 
-fn write_tree(writer: &mut FileWriter, tree: &Box<Tree>) -> io::Result<()> {
-    if tree.is_leaf() {
-        writer.write_bit(1)?;
-        writer.write_bits(tree.plain_symbol, 8)?;
-        Ok(())
-    } else {
-        writer.write_bit(0)?;
-        let left = tree.left.as_ref().expect("Expected left node to be Some");
-        write_tree(writer, left)?;
-        let right = tree.right.as_ref().expect("Expected right node to be Some");
-        write_tree(writer, right)
+// a cooperative check that an operation hasn't run past its deadline, the operation stops itself when this fails
+fn check_deadline(deadline: Option<Instant>) -> io::Result<()> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline =>
+            Err(io::Error::new(io::ErrorKind::TimedOut, "Compression exceeded its time limit")),
+        _ => Ok(())
     }
 }
 
-pub fn debug_binary_file(filepath: &str) {
-    let mut reader = FileReader::new(filepath)
-        .expect("Cannot create reader in debugger");
-    println!();
-    let mut c = 0;
-    while !reader.eof() {
-        let bit = reader.read_bit()
-            .expect("Cannot read bit in debugger");
-        print!("{}", bit);
-        if (c + 1) % 4 == 0 {
-            print!(" ");
+fn create_code_books(labels: &[FileLabel], options: &ArchiveOptions, deadline: Option<Instant>, tp: &ThreadPool) -> io::Result<Vec<CodeBook>> {
+    // create code books, this operation can be parallelized because it only reads
+    // each file is its own task so the threads pick up files in schedule order
+    let schedule = schedule_by_size(labels);
+    let file_limit = FileLimit::new(options.max_open_files.unwrap_or(usize::MAX));
+    let buffer_budget = AtomicU64::new(options.max_buffered_bytes);
+    let scheduled_books: Vec<CodeBook> = tp.install(|| {
+        schedule.par_iter()
+            .with_max_len(1)
+            .map(|&i| {
+                check_deadline(deadline)?;
+                if let Some(target) = labels[i].hardlink_of {
+                    return Ok(CodeBook::hardlink(&labels[i], target));
+                }
+                let _permit = file_limit.acquire(1);
+                let code_book = create_code_book(&labels[i], method_for_size(labels[i].size, options), options, &buffer_budget)?;
+                if options.paranoid || cfg!(debug_assertions) {
+                    code_book.coder.validate_model(&code_book.freq_table)?;
+                }
+                Ok(code_book)
+            })
+            .collect::<io::Result<_>>()
+    })?;
+
+    // put the code books back in label order so the archive layout doesn't depend on the schedule
+    let mut code_books: Vec<(usize, CodeBook)> = schedule.into_iter().zip(scheduled_books).collect();
+    code_books.sort_by_key(|(i, _)| *i);
+    Ok(code_books.into_iter().map(|(_, code_book)| code_book).collect())
+}
+
+// order the labels to be processed largest first, so a large file isn't started last while other threads are idle
+fn schedule_by_size(labels: &[FileLabel]) -> Vec<usize> {
+    let mut schedule: Vec<usize> = (0..labels.len()).collect();
+    // stable sort keeps files of equal size in label order
+    schedule.sort_by(|&a, &b| labels[b].size.cmp(&labels[a].size));
+    schedule
+}
+
+// name of the manifest entry, it's at the top of the output directory next to the archived entries
+pub const MANIFEST_NAME: &str = "zipr-manifest.txt";
+
+// put a listing of each entry's size and name in front of the code books, so it's the first entry of the archive
+pub(crate) fn add_manifest(code_books: &mut Vec<CodeBook>, options: &ArchiveOptions) -> io::Result<()> {
+    let listing: String = code_books.iter()
+        .map(|code_book| format!("{:>12}  {}\n", code_book.og_byte_size, code_book.filename_rel))
+        .collect();
+    // hardlinks refer to their target by index, which moves back by one
+    for code_book in code_books.iter_mut() {
+        if let Some(target) = &mut code_book.hardlink_of {
+            *target += 1;
         }
-        c += 1;
     }
+    let method = method_for_size(listing.len() as u64, options);
+    code_books.insert(0, CodeBook::from_contents(MANIFEST_NAME, listing.into_bytes(), method)?);
+    Ok(())
 }
 
-pub fn debug_tree_file(filepath: &str) {
-    let mut reader = FileReader::new(filepath)
-        .expect("Cannot create reader in debugger");
-    println!();
-    while !reader.eof() {
-        let bit = reader.read_bit()
-            .expect("Cannot read bit in debugger");
-        print!("{}", bit);
-        if bit > 0 {
-            let byte = reader.read_bits(8)
-                .expect("Cannot read bits in debugger");
-            print!("{}", byte as char);
+// tiny files are always stored, the method byte records this so extraction needs no special case
+pub(crate) fn method_for_size(size: u64, options: &ArchiveOptions) -> u8 {
+    if size < options.store_below {
+        METHOD_STORED
+    } else {
+        options.method
+    }
+}
+
+// leading bytes of formats that are already compressed, with the name of each format
+const COMPRESSED_MAGICS: [(&[u8], &str); 7] = [
+    (&SIG.to_le_bytes(), "a zipr archive"),
+    (&[0x1F, 0x8B], "gzip"),
+    (b"PK\x03\x04", "zip"),
+    (b"BZh", "bzip2"),
+    (&[0xFD, b'7', b'z', b'X', b'Z', 0x00], "xz"),
+    (&[0x28, 0xB5, 0x2F, 0xFD], "zstd"),
+    (&[b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C], "7z"),
+];
+
+// longest leading bytes needed to recognize a compressed format
+const MAX_MAGIC_LEN: usize = 8;
+
+// the name of the compressed format data starts with, if any
+pub fn compressed_format(leading_bytes: &[u8]) -> Option<&'static str> {
+    COMPRESSED_MAGICS.iter()
+        .find(|(magic, _)| leading_bytes.starts_with(magic))
+        .map(|(_, name)| *name)
+}
+
+// take a file's size from what's left of the memory budget, if it fits
+fn reserve_buffer(budget: &AtomicU64, size: u64) -> bool {
+    budget.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| remaining.checked_sub(size)).is_ok()
+}
+
+// a copy of a file's bytes taken when it's read, which the file is compressed from
+enum Contents {
+    Memory(Vec<u8>),
+    Spilled(SpillFile),
+}
+
+impl Contents {
+    // read a stream once, into memory if its size fits in what's left of the budget and into a spill file if not
+    fn read(source: &mut impl Read, size: u64, budget: &AtomicU64) -> io::Result<Contents> {
+        if reserve_buffer(budget, size) {
+            let mut bytes = Vec::with_capacity(size as usize);
+            source.read_to_end(&mut bytes)?;
+            Ok(Contents::Memory(bytes))
+        } else {
+            Ok(Contents::Spilled(SpillFile::copy_from(source)?))
+        }
+    }
+
+    fn reader(&self) -> io::Result<FileReader<'_>> {
+        match self {
+            Contents::Memory(bytes) => FileReader::from_bytes(bytes),
+            Contents::Spilled(spill) => FileReader::from_seekable(fs::File::open(&spill.path)?),
+        }
+    }
+}
+
+// a temporary copy of a file that didn't fit in the memory budget, removed once the code book is dropped
+struct SpillFile {
+    path: PathBuf,
+}
+
+impl SpillFile {
+    // copy a stream into a new spill file
+    fn copy_from(source: &mut impl Read) -> io::Result<SpillFile> {
+        static SPILL_COUNT: AtomicU64 = AtomicU64::new(0);
+        let n = SPILL_COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("zipper_spill_{}_{}", std::process::id(), n));
+        let spill = SpillFile { path };
+        io::copy(source, &mut io::BufWriter::new(fs::File::create(&spill.path)?))?;
+        Ok(spill)
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// every file being archived is opened here and nowhere else, so it's read exactly once
+fn open_source(filepath: &str) -> io::Result<fs::File> {
+    #[cfg(test)]
+    tests::count_open(filepath);
+    fs::File::open(filepath)
+}
+
+// create a codebook from the intermediate file block argument
+// each file is opened and read once and its bytes are kept, so it can't be deleted or changed before it's compressed
+// a file that fits in the memory budget is kept in memory, larger files are copied to a spill file so memory stays bounded
+fn create_code_book(label: &FileLabel, method: u8, options: &ArchiveOptions, budget: &AtomicU64) -> io::Result<CodeBook> {
+    let contents = Contents::read(&mut open_source(&label.filename_abs)?, label.size, budget)?;
+    let mut code_book = model_file(label, method, options, &mut contents.reader()?)?;
+    code_book.contents = Some(contents);
+    Ok(code_book)
+}
+
+// the checksum of the file is taken in the same pass that counts its bytes
+fn model_file(label: &FileLabel, method: u8, options: &ArchiveOptions, reader: &mut FileReader) -> io::Result<CodeBook> {
+    reader.start_crc();
+    let mut freq_table = Box::new([0u64; TABLE_SIZE]);
+    if method == METHOD_HUFFMAN {
+        // compressed data is close to random, so coding it again wastes time and usually makes it larger
+        let mut leading_bytes = [0u8; MAX_MAGIC_LEN];
+        let count = reader.read_into(&mut leading_bytes)?;
+        for &byte in &leading_bytes[..count] {
+            freq_table[byte as usize] += 1;
+        }
+        if let Some(format) = compressed_format(&leading_bytes[..count]) {
+            eprintln!("Storing {} as is, it is already compressed as {}", label.filename_rel, format);
+            return Ok(CodeBook::stored_from_sample(label, rest_crc(reader)?, freq_table));
         }
     }
+    if let Some(sample_size) = options.sample_size.filter(|_| method == METHOD_HUFFMAN) {
+        // a file that looks incompressible from its first bytes is stored without reading the rest
+        count_bytes(reader, &mut freq_table, sample_size)?;
+        if projected_savings(&freq_table) < options.min_savings {
+            return Ok(CodeBook::stored_from_sample(label, rest_crc(reader)?, freq_table));
+        }
+    }
+    // the sample was already counted, so counting carries on from where it stopped
+    count_bytes(reader, &mut freq_table, u64::MAX)?;
+    let mut code_book = CodeBook::from_freq_table(&label.filename_rel, Some(&label.filename_abs), label.size, reader.current_crc(), freq_table, method)?;
+    if let Some(dictionary) = options.dictionary.as_ref().filter(|_| method == METHOD_HUFFMAN) {
+        code_book.prefer_dictionary(dictionary);
+    }
+    code_book.executable = label.executable;
+    Ok(code_book)
+}
+
+// read the rest of a file only to finish its checksum, without counting its bytes
+fn rest_crc(reader: &mut FileReader) -> io::Result<u32> {
+    let mut chunk = [0u8; 8192];
+    while reader.read_into(&mut chunk)? == chunk.len() {}
+    Ok(reader.current_crc())
+}
+
+fn create_freq_table(reader: &mut FileReader) -> io::Result<Box<[u64; TABLE_SIZE]>> {
+    let mut freq_table = Box::new([0u64; TABLE_SIZE]);
+    count_bytes(reader, &mut freq_table, u64::MAX)?;
+    Ok(freq_table)
+}
+
+// add the bytes read from the reader to the frequency table until the limit or the end of the file
+fn count_bytes(reader: &mut FileReader, freq_table: &mut [u64; TABLE_SIZE], limit: u64) -> io::Result<()> {
+    // iterate through each byte in the file and increment count, reading a chunk at a time
+    let mut chunk = [0u8; 4096];
+    let mut counted = 0;
+    while counted < limit {
+        let want = (limit - counted).min(chunk.len() as u64) as usize;
+        let count = reader.read_into(&mut chunk[..want])?;
+        for &byte in &chunk[..count] {
+            freq_table[byte as usize] += 1;
+        }
+        counted += count as u64;
+        if count < want {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// fraction of the bytes an ideal entropy coder would save, huffman coding comes within a bit per byte of this
+fn projected_savings(freq_table: &[u64; TABLE_SIZE]) -> f64 {
+    let weights: Vec<f64> = freq_table.iter().map(|&freq| freq as f64).collect();
+    1.0 - entropy_bits(&weights) / 8.0
+}
+
+// entropy in bits per symbol of a distribution given by the weight of each symbol
+pub(crate) fn entropy_bits(weights: &[f64]) -> f64 {
+    let total: f64 = weights.iter().sum();
+    weights.iter()
+        .filter(|&&weight| weight > 0.0)
+        .map(|&weight| {
+            let p = weight / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+pub struct CodeTree {
+    pub root: Box<Tree>,
+    pub symbol_count: u32,
+}
+
+// shape of a code tree, a max depth far above the average points to a pathological tree with very long codes
+#[derive(Clone, Debug, PartialEq)]
+pub struct TreeMetrics {
+    pub max_depth: u32,
+    pub min_depth: u32,
+    // code length averaged over every byte of the input, so frequent symbols count for more
+    pub weighted_code_len: f64,
+    pub leaf_count: u32,
+}
+
+pub fn tree_metrics(tree: &CodeTree) -> TreeMetrics {
+    let mut metrics = TreeMetrics { max_depth: 0, min_depth: u32::MAX, weighted_code_len: 0.0, leaf_count: 0 };
+    let mut weighted_bits = 0;
+    walk_tree_metrics(&tree.root, 0, &mut metrics, &mut weighted_bits);
+    // the root's weight is the sum of every leaf's weight
+    if tree.root.weight > 0 {
+        metrics.weighted_code_len = weighted_bits as f64 / tree.root.weight as f64;
+    }
+    metrics
+}
+
+fn walk_tree_metrics(node: &Tree, depth: u32, metrics: &mut TreeMetrics, weighted_bits: &mut u64) {
+    if node.is_leaf() {
+        metrics.max_depth = metrics.max_depth.max(depth);
+        metrics.min_depth = metrics.min_depth.min(depth);
+        metrics.leaf_count += 1;
+        *weighted_bits += node.weight * depth as u64;
+    }
+    for child in [&node.left, &node.right].into_iter().flatten() {
+        walk_tree_metrics(child, depth + 1, metrics, weighted_bits);
+    }
+}
+
+pub(crate) fn create_code_tree(freq_table: &[u64]) -> CodeTree {
+    let mut heap = BinaryHeap::new();
+
+    // add the frequency table nodes to priority queue
+    let mut symbol_count = 0;
+    for (i, &freq) in freq_table.iter().enumerate() {
+        if freq != 0 {
+            heap.push(Box::new(Tree::leaf(i as u8, freq)));
+            symbol_count += 1;
+        }
+    }
+
+    // huffman coding algorithm
+    let mut merges = 0;
+    while heap.len() >= 2 {
+        // invariant: the heap should never have 1 or 0 elements at this point
+        let first_node = heap.pop()
+            .expect("Expected first node to be Some after checking length");
+        let second_node = heap.pop()
+            .expect("Expected second node to be Some after checking length");
+        let w = first_node.weight + second_node.weight;
+        let mut node = Tree::internal(first_node, second_node, 0, w);
+        node.order = (TABLE_SIZE + merges) as u32;
+        merges += 1;
+        heap.push(Box::new(node));
+    }
+
+    // an empty file has no symbols but a tree always has a root, so it gets a leaf that is never used
+    if heap.is_empty() {
+        heap.push(Box::new(Tree::leaf(0, 0)));
+        symbol_count += 1;
+    }
+
+    // invariant: the heap should not be empty after the huffman coding algorithm is finished
+    let root = heap.pop()
+        .expect("Expected heap to have at least one element after huffman coding algorithm");
+    CodeTree { root, symbol_count }
+}
+
+pub(crate) fn create_code_table(tree: &CodeTree) -> Box<[SymbolCode; TABLE_SIZE]> {
+    let symbol_code = SymbolCode::new();
+    let mut symbol_table = [symbol_code; TABLE_SIZE];
+    walk_code_tree(&tree.root, symbol_code, &mut symbol_table);
+    Box::new(symbol_table)
 }
 
-pub fn debug_tree(node: &Box<Tree>, symbol_code: SymbolCode) {
+fn walk_code_tree(node: &Tree, mut symbol_code: SymbolCode, symbol_table: &mut [SymbolCode]) {
     if node.is_leaf() {
-        println!("Leaf: {:#b} {} {}", symbol_code.encoded_symbol, symbol_code.bit_len, node.plain_symbol as char);
+        symbol_code.plain_symbol = node.plain_symbol;
+        symbol_table[node.plain_symbol as usize] = symbol_code;
     }
     if let Some(left) = &node.left {
         let symbol_code = symbol_code.append_bit(0);
-        debug_tree(left, symbol_code);
+        walk_code_tree(left, symbol_code, symbol_table);
     }
     if let Some(right) = &node.right {
         let symbol_code = symbol_code.append_bit(1);
-        debug_tree(right, symbol_code);
+        walk_code_tree(right, symbol_code, symbol_table);
     }
 }
 
-pub fn unarchive_zip(archive_filepath: &str, multithreaded: bool) -> io::Result<()> {
-    let output_dir = strip_ext(archive_filepath);
-    fs::create_dir_all(&output_dir)?;
+// the huffman code of each byte for a frequency table, for callers that apply the codes themselves
+// bytes that never occur have no code, and neither does the only byte when just one byte occurs since it needs no bits
+pub fn build_codes(freq_table: &[u64; TABLE_SIZE]) -> Box<[SymbolCode; TABLE_SIZE]> {
+    create_code_table(&create_code_tree(freq_table))
+}
 
-    let now = Instant::now();
+// each code as its byte, its bits, and its length in bits, the first bit of a code is its least significant bit
+pub fn export_codes(symbol_table: &[SymbolCode; TABLE_SIZE]) -> Vec<(u8, u64, u8)> {
+    symbol_table.iter()
+        .filter(|symbol| symbol.bit_len > 0)
+        .map(|symbol| (symbol.plain_symbol, symbol.encoded_symbol, symbol.bit_len))
+        .collect()
+}
 
-    let blocks_reader = &mut FileReader::new(archive_filepath)?;
-    let blocks = get_file_blocks(blocks_reader)?;
+// check no code is a prefix of another and the code lengths describe a complete tree, either would mean a bug building the table
+pub(crate) fn validate_code_table(symbol_table: &[SymbolCode; TABLE_SIZE], freq_table: &[u64; TABLE_SIZE]) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::other(format!("Invalid code table, {}", msg));
+    let used: Vec<&SymbolCode> = symbol_table.iter()
+        .enumerate()
+        .filter(|(i, _)| freq_table[*i] > 0)
+        .map(|(_, symbol)| symbol)
+        .collect();
+    for (symbol, &freq) in symbol_table.iter().zip(freq_table.iter()) {
+        if freq > 0 && used.len() > 1 && symbol.bit_len == 0 {
+            return Err(invalid(format!("symbol {} has no code", symbol.plain_symbol)));
+        }
+        if symbol.bit_len > MAX_CODE_LEN {
+            return Err(invalid(format!("the code for symbol {} is longer than {} bits", symbol.plain_symbol, MAX_CODE_LEN)));
+        }
+    }
+    if used.len() < 2 {
+        // a single symbol is coded with no bits
+        return Ok(());
+    }
 
-    let tp = configure_thread_pool(multithreaded, blocks.len())?;
-    decompress_files(&blocks, archive_filepath, &output_dir, &tp)?;
+    for (i, a) in used.iter().enumerate() {
+        for b in &used[(i + 1)..] {
+            let (short, long) = if a.bit_len <= b.bit_len { (a, b) } else { (b, a) };
+            // codes are stored first bit first from the least significant bit, so a prefix is the low bits
+            let mask = if short.bit_len >= MAX_CODE_LEN { u64::MAX } else { (1u64 << short.bit_len) - 1 };
+            if long.encoded_symbol & mask == short.encoded_symbol {
+                return Err(invalid(format!("the code for symbol {} is a prefix of the code for symbol {}",
+                    short.plain_symbol, long.plain_symbol)));
+            }
+        }
+    }
 
-    let elapsed = now.elapsed();
-    println!("Finished unzipping in {:.2?}", elapsed);
+    // the lengths of a complete prefix code satisfy the kraft equality, the sum of 2^-len is exactly 1
+    let kraft_sum: u128 = used.iter().map(|symbol| 1u128 << (MAX_CODE_LEN - symbol.bit_len)).sum();
+    if kraft_sum != 1u128 << MAX_CODE_LEN {
+        return Err(invalid(String::from("the code lengths don't form a complete tree")));
+    }
     Ok(())
 }
 
-pub fn strip_ext(path: &str) -> String {
-    Path::new(path)
-        .with_extension("")
-        .display()
-        .to_string()
+// create the file blocks to be put into the archive - missing the offset this is calculated at write time
+pub(crate) fn create_file_blocks(code_books: &[CodeBook]) -> Vec<FileBlock> {
+    let mut blocks: Vec<FileBlock> = vec![];
+    for code_book in code_books {
+        let block = match code_book.hardlink_of {
+            // a hardlink has the sizes of the block it links to, and that block's index in place of an offset until it's placed
+            Some(target) => FileBlock {
+                filename_rel: String::from(&code_book.filename_rel),
+                file_byte_offset: target as u64,
+                executable: code_book.executable,
+                hardlink: true,
+                ..blocks[target].clone()
+            },
+            // the coder calculates the bit size for the file block for compressed data and for the model
+            None => FileBlock {
+                filename_rel: String::from(&code_book.filename_rel),
+                file_byte_offset: 0,
+                og_byte_size: code_book.og_byte_size,
+                crc32: Some(code_book.crc),
+                tree_bit_size: code_book.tree_bit_size(),
+                data_bit_size: code_book.data_bit_size(),
+                method: code_book.method(),
+                data_offset: None,
+                model_offset: None,
+                executable: code_book.executable,
+                hardlink: false,
+            },
+        };
+        blocks.push(block);
+    }
+    blocks
 }
 
-pub fn get_file_blocks(reader: &mut FileReader) -> io::Result<Vec<FileBlock>> {
-    if reader.read_u64()? != SIG {
-        return Err(io::Error::new(
-            io::ErrorKind::Other, "Cannot read from an invalid zipr file"));
+// write the headers with each block's data offset, returning the blocks with their offsets and the offset of the end of the data
+fn write_block_headers(writer: &mut FileWriter, blocks: &[FileBlock], flags: u8, dictionary: Option<&[u8]>, metadata: &ArchiveMetadata)
+    -> io::Result<(Vec<FileBlock>, u64)> {
+    let compress_names = flags & FLAG_COMPRESSED_NAMES != 0;
+    let name_table = if compress_names { Some(encode_name_table(blocks)?) } else { None };
+
+    // calculate the total block size for the header, including the version and flag bytes, block count, and checksum
+    let mut header_size = ARCHIVE_HEADER_SIZE;
+    for block in blocks {
+        header_size += match name_table {
+            Some(_) => block.get_fields_size(),
+            None => block.get_header_size(),
+        };
     }
-    // iterate through headers until the file separator byte is found or eof
-    let mut blocks = vec![];
-    while !reader.eof() {
-        let sep = reader.read_byte()?;
-        if sep == GRP_SEP {
-            break;
+    header_size += name_table.as_ref().map_or(0, |name_table| name_table.len() as u64);
+    header_size += dictionary.map_or(0, |dictionary| (sizeof(0u32) + dictionary.len()) as u64);
+    header_size += metadata.size();
+
+    // the checksum covers every header byte from the version byte through the last header
+    writer.start_crc();
+    writer.write_byte(FORMAT_VERSION)?;
+    writer.write_byte(flags)?;
+    // the headers are counted instead of separated so no byte value is reserved
+    let block_count = u32::try_from(blocks.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Archive has too many files for a 32 bit count"))?;
+    writer.write_u32(block_count)?;
+    if let Some(dictionary) = dictionary {
+        let dictionary_len = u32::try_from(dictionary.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Dictionary tree is too large for a 32 bit length"))?;
+        writer.write_u32(dictionary_len)?;
+        writer.write_bytes(dictionary)?;
+    }
+    metadata.write(writer)?;
+    if let Some(name_table) = &name_table {
+        for &byte in name_table {
+            writer.write_byte(byte)?;
         }
-        let block = reader.read_block()?;
-        blocks.push(block);
     }
-    Ok(blocks)
+
+    let mut total_offset = 0;
+    let mut placed_blocks = vec![];
+    for block in blocks {
+        // calculate the offset of the compressed data using values from all previous file blocks
+        let mut block = block.clone();
+        if block.hardlink {
+            // invariant: a hardlink links to an earlier block, which is already placed
+            let target: &FileBlock = placed_blocks.get(block.file_byte_offset as usize)
+                .expect("Expected a hardlink to link to an earlier block");
+            block.file_byte_offset = target.file_byte_offset;
+        } else {
+            block.file_byte_offset = header_size + total_offset;
+            // the tree and data are written together and then aligned to the next byte
+            total_offset = block.data_bit_size.checked_add(block.tree_bit_size)
+                .map(|bit_size| bit_size.div_ceil(8))
+                .and_then(|byte_size| total_offset.checked_add(byte_size))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Archive is too large for a 64 bit offset"))?;
+        }
+
+        if name_table.is_some() {
+            writer.write_block_fields(&block)?;
+        } else {
+            writer.write_block(&block)?;
+        }
+        placed_blocks.push(block);
+    }
+    writer.write_u32(writer.current_crc())?;
+    place_dictionary_models(&mut placed_blocks, flags, DICTIONARY_MODEL_OFFSET)?;
+    Ok((placed_blocks, header_size + total_offset))
 }
 
-fn decompress_files(blocks: &[FileBlock], archive_filepath: &str, output_dir: &str, tp: &ThreadPool) -> io::Result<()> {
-    // decompress each file, this can be parallelized because each function call writes to a different file
-    tp.install(|| {
-        blocks.par_iter()
-            .map(|block| decompress_file(block, archive_filepath, output_dir))
-            .collect()
-    })
+// write the headers into memory and read them back before writing them, so a layout bug stops the archive before any data
+fn write_verified_headers(writer: &mut FileWriter, blocks: &[FileBlock], flags: u8, dictionary: Option<&[u8]>, metadata: &ArchiveMetadata)
+    -> io::Result<(Vec<FileBlock>, u64)> {
+    let mut header_bytes = SIG.to_le_bytes().to_vec();
+    let mut header_writer = FileWriter::from_writer(&mut header_bytes);
+    let (placed_blocks, data_end) = write_block_headers(&mut header_writer, blocks, flags, dictionary, metadata)?;
+    header_writer.flush()?;
+    drop(header_writer);
+
+    verify_headers(&header_bytes, &placed_blocks)?;
+    for &byte in &header_bytes[sizeof(SIG)..] {
+        writer.write_byte(byte)?;
+    }
+    Ok((placed_blocks, data_end))
 }
 
-fn decompress_file(block: &FileBlock, archive_filepath: &str, output_dir: &str) -> io::Result<()> {
-    let unarchived_filename = &format!("{}{}{}", output_dir, path::MAIN_SEPARATOR, &block.filename_rel);
-    if let Some(unarchived_parent) = Path::new(unarchived_filename).parent() {
-        fs::create_dir_all(unarchived_parent)?;
+// a hardlink has the offset and sizes of the block it links to, and no two blocks with data of their own share all of them
+fn link_key(block: &FileBlock) -> (u64, u64, u64, u64) {
+    (block.file_byte_offset, block.og_byte_size, block.tree_bit_size, block.data_bit_size)
+}
+
+// check the headers parse back into the blocks they were written from, and the data offsets follow on from the headers
+pub(crate) fn verify_headers(header_bytes: &[u8], blocks: &[FileBlock]) -> io::Result<()> {
+    let mismatch = |msg: String| io::Error::other(format!("Header verification failed, {}", msg));
+    let (read_blocks, _) = read_archive_headers(&mut FileReader::from_bytes(header_bytes)?, false, MissingGroupSeparator::Error)?;
+    if read_blocks != blocks {
+        return Err(mismatch(String::from("the headers read back differ from the headers written")));
     }
+    check_contiguous(blocks, (header_bytes.len() - sizeof(SIG)) as u64, |block| block.tree_bit_size.checked_add(block.data_bit_size))
+        .map(|_| ())
+        .map_err(mismatch)
+}
 
-    let writer = &mut FileWriter::new(unarchived_filename)?;
+// check an archive's blocks are laid out back to back after its headers with no gaps or overlaps, catching writer bugs and tampering
+// in the grouped layout it's the trees that are back to back, and the data is always placed after the last of them when read
+pub fn validate_offsets(archive_filepath: &str) -> io::Result<()> {
     let reader = &mut FileReader::new(archive_filepath)?;
-    decompress(&block, reader, writer)
+    let (blocks, info) = read_archive_headers(reader, false, MissingGroupSeparator::Error)?;
+    let flags = info.flags;
+    let headers_end = reader.byte_position()? - sizeof(SIG) as u64;
+    let inconsistent = |msg: String| io::Error::new(io::ErrorKind::InvalidData, format!("Archive offsets are inconsistent, {}", msg));
+    let blocks_end = if flags & FLAG_GROUPED_LAYOUT != 0 {
+        check_contiguous(&blocks, headers_end, |block| Some(block.tree_bit_size))
+            .map_err(inconsistent)?;
+        blocks.iter()
+            .filter(|block| !block.hardlink)
+            .filter_map(|block| block.data_offset.map(|offset| offset + block.data_bit_size.div_ceil(8)))
+            .fold(headers_end, u64::max)
+    } else {
+        check_contiguous(&blocks, headers_end, |block| block.tree_bit_size.checked_add(block.data_bit_size))
+            .map_err(inconsistent)?
+    };
+    // the data may be followed by an index or another archive, but it can't run past the end of the file
+    let archive_len = reader.stream_len()? - sizeof(SIG) as u64;
+    if blocks_end > archive_len {
+        return Err(inconsistent(format!("the data ends at {} but the archive is only {} bytes", blocks_end, archive_len)));
+    }
+    Ok(())
 }
 
-pub fn sizeof<T>(_: T) -> usize {
-    std::mem::size_of::<T>()
+// check the first block starts right at the end of the headers, and each block right after the one before it, returning where the last ends
+// a hardlink has no data of its own, its offset and sizes are those of an earlier block
+fn check_contiguous(blocks: &[FileBlock], headers_end: u64, bit_len: impl Fn(&FileBlock) -> Option<u64>) -> Result<u64, String> {
+    let mut expected_offset = headers_end;
+    let mut placed = HashSet::new();
+    for block in blocks {
+        if block.hardlink {
+            if !placed.contains(&link_key(block)) {
+                return Err(format!("{} is a hardlink to data at offset {} that no earlier block has",
+                    block.filename_rel, block.file_byte_offset));
+            }
+            continue;
+        }
+        placed.insert(link_key(block));
+        if block.file_byte_offset != expected_offset {
+            return Err(format!("{} has data offset {} but its data starts at {}",
+                block.filename_rel, block.file_byte_offset, expected_offset));
+        }
+        expected_offset = bit_len(block)
+            .and_then(|bit_len| expected_offset.checked_add(bit_len.div_ceil(8)))
+            .ok_or_else(|| format!("{} has sizes too large for a 64 bit offset", block.filename_rel))?;
+    }
+    Ok(expected_offset)
 }
 
-// read the contents of a compressed archive and write into a decompressed stream
-fn decompress(block: &FileBlock, reader: &mut FileReader, writer: &mut FileWriter) -> io::Result<()> {
-    // read from the main archive: jumping to the data segment
-    reader.seek((sizeof(SIG) as u64) + block.file_byte_offset)?;
-
-    let root = read_tree(reader)?;
+// write the index after the data: its own offset, the block count, each full header, and a checksum, then the footer
+// the footer is a fixed size so a reader can find the index by seeking to the end of the archive
+fn write_trailing_index(writer: &mut FileWriter, blocks: &[FileBlock], index_offset: u64) -> io::Result<()> {
+    writer.start_crc();
+    writer.write_u64(index_offset)?;
+    // invariant: the block count already fit in the front headers
+    writer.write_u32(blocks.len() as u32)?;
+    let mut index_size = (sizeof(0u64) + 2 * sizeof(0u32)) as u64;
+    for block in blocks {
+        writer.write_block(block)?;
+        index_size += block.get_header_size();
+    }
+    writer.write_u32(writer.current_crc())?;
+    writer.write_u64(index_size)?;
+    writer.write_u64(INDEX_SIG)
+}
 
-    // decompress each symbol in data segment, stopping at the end
-    let start_read_len = reader.read_len() as i64;
-    while !reader.eof() {
-        let read_len = reader.read_len() as i64;
-        if (read_len - start_read_len) > (block.data_bit_size as i64 - 8) {
-            break;
+fn compress_files(
+    writer: &mut FileWriter,
+    code_books: &[CodeBook],
+    deadline: Option<Instant>,
+    progress: &mut Option<Progress>,
+    verbose: bool,
+) -> io::Result<()> {
+    for code_book in code_books {
+        check_deadline(deadline)?;
+        let start = Instant::now();
+        compress_file(writer, code_book)?;
+        let elapsed = start.elapsed();
+        writer.sync()?;
+        if verbose {
+            eprintln!("{}", file_report(code_book, elapsed));
+        }
+        if let Some(progress) = progress {
+            progress.file_done(code_book.og_byte_size)?;
         }
-        decompress_symbol(reader, writer, &root)?;
     }
     Ok(())
 }
 
-// read the tree from a compressed archive
-fn read_tree(reader: &mut FileReader) -> io::Result<Box<Tree>> {
-    let bit = reader.read_bit()?;
-    if bit == 1 {
-        // read 8 unaligned bits
-        let symbol = reader.read_bits(8)?;
-        Ok(Box::new(Tree::leaf(symbol, 0)))
-    } else {
-        let left = read_tree(reader)?;
-        let right = read_tree(reader)?;
-        Ok(Box::new(Tree::internal(left, right, 0, 0)))
+// compress each file into its own temporary file in parallel, then concatenate them in order after the headers
+// the compressed data streams through the temporary files so no file is ever held in memory
+fn compress_files_parallel(
+    writer: &mut FileWriter,
+    code_books: &[CodeBook],
+    tmp_prefix: &str,
+    deadline: Option<Instant>,
+    progress: &mut Option<Progress>,
+    options: &ArchiveOptions,
+    tp: &ThreadPool,
+) -> io::Result<()> {
+    let file_limit = FileLimit::new(options.max_open_files.unwrap_or(usize::MAX));
+    let tmp_filenames: Vec<String> = (0..code_books.len())
+        .map(|i| format!("{}.{}.tmp", tmp_prefix, i))
+        .collect();
+    let result = tp.install(|| {
+        code_books.par_iter()
+            .zip(&tmp_filenames)
+            .map(|(code_book, tmp_filename)| {
+                check_deadline(deadline)?;
+                // the temporary file and the file being compressed are open together
+                let _permit = file_limit.acquire(2);
+                let start = Instant::now();
+                let mut tmp_writer = FileWriter::new(tmp_filename)?;
+                compress_file(&mut tmp_writer, code_book)?;
+                tmp_writer.flush()?;
+                Ok(start.elapsed())
+            })
+            .collect::<io::Result<Vec<Duration>>>()
+    }).and_then(|times| {
+        let mut chunk = [0u8; 8192];
+        for ((code_book, tmp_filename), elapsed) in code_books.iter().zip(&tmp_filenames).zip(times) {
+            let reader = &mut FileReader::new(tmp_filename)?;
+            loop {
+                let count = reader.read_into(&mut chunk)?;
+                writer.write_bytes(&chunk[..count])?;
+                if count < chunk.len() {
+                    break;
+                }
+            }
+            fs::remove_file(tmp_filename)?;
+            writer.sync()?;
+            if options.verbose {
+                eprintln!("{}", file_report(code_book, elapsed));
+            }
+            if let Some(progress) = progress {
+                progress.file_done(code_book.og_byte_size)?;
+            }
+        }
+        Ok(())
+    });
+    if result.is_err() {
+        // the compression already failed so a failure to clean up isn't reported over it
+        for tmp_filename in &tmp_filenames {
+            let _ = fs::remove_file(tmp_filename);
+        }
     }
+    result
 }
 
-// read the next symbol from the compressed archived and write it into a decompressed stream using the codebook tree
-fn decompress_symbol(reader: &mut FileReader, writer: &mut FileWriter, node: &Box<Tree>) -> io::Result<()> {
-    if node.is_leaf() {
-        writer.write_byte(node.plain_symbol)?;
-        Ok(())
+// one line describing how a file was coded: distinct symbols, model and data sizes, ratio, and time taken
+pub fn file_report(code_book: &CodeBook, elapsed: Duration) -> String {
+    let byte_size = (code_book.tree_bit_size() + code_book.data_bit_size()).div_ceil(8);
+    // an empty file has no ratio
+    let ratio = if code_book.og_byte_size == 0 {
+        String::from("-")
     } else {
-        let bit = reader.read_bit()?;
-        // invariant: a non-leaf should have left and right nodes in a full tree
-        if bit == 0 {
-            let left = node.left.as_ref().expect("Expected left node to be Some");
-            decompress_symbol(reader, writer, left)
-        } else {
-            let right = node.right.as_ref().expect("Expected right node to be Some");
-            decompress_symbol(reader, writer, right)
+        format!("{:.3}", byte_size as f64 / code_book.og_byte_size as f64)
+    };
+    format!("{}: {} symbols, tree {} bits, data {} bits, ratio {}, {:.2?}",
+        code_book.filename_rel, code_book.symbol_count(), code_book.tree_bit_size(), code_book.data_bit_size(), ratio, elapsed)
+}
+
+// write the model and encoded data of a file, aligned to the next byte
+fn compress_file(writer: &mut FileWriter, code_book: &CodeBook) -> io::Result<()> {
+    // a hardlink's data is written for the file it links to
+    if code_book.hardlink_of.is_some() {
+        return Ok(());
+    }
+    code_book.coder.write_model(writer)?;
+    // the copy kept of a file can fail to read long after it was scanned, so the error says which file it was
+    encode_contents(writer, code_book)
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot compress {}: {}", code_book.filename_rel, e)))?;
+    writer.align_to_byte()
+}
+
+fn encode_contents(writer: &mut FileWriter, code_book: &CodeBook) -> io::Result<()> {
+    let reader = &mut match &code_book.contents {
+        Some(contents) => contents.reader()?,
+        None => {
+            // invariant: code books for an archive are created from files unless they keep their contents
+            let filename_abs = code_book.filename_abs.as_ref()
+                .expect("Expected code book to be created from a file");
+            FileReader::new(filename_abs)?
         }
+    };
+    code_book.coder.encode(reader, writer)?;
+    // the headers were written from the size the file had when it was modelled, so any other size would corrupt the archive
+    if reader.read_len() != 8 * code_book.og_byte_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("the file changed size from {} to {} bytes while it was being archived", code_book.og_byte_size, reader.read_len() / 8)));
     }
+    Ok(())
 }
 
-mod tests {
-    use std::{collections::HashMap, fs};
-    use crate::compress::{archive_dir, unarchive_zip};
+pub(crate) fn write_tree(writer: &mut FileWriter, tree: &Tree) -> io::Result<()> {
+    if tree.is_leaf() {
+        writer.write_bit(1)?;
+        writer.write_bits(tree.plain_symbol, 8)?;
+        Ok(())
+    } else {
+        writer.write_bit(0)?;
+        let left = tree.left.as_ref().expect("Expected left node to be Some");
+        write_tree(writer, left)?;
+        let right = tree.right.as_ref().expect("Expected right node to be Some");
+        write_tree(writer, right)
+    }
+}
+
+// encode a tree exactly as it's written into an archive, with the last byte padded with zero bits
+// the archive writes the tree through the same function, so the two encodings can't drift apart
+pub fn serialize_tree(tree: &Tree) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut writer = FileWriter::from_writer(&mut bytes);
+    // invariant: writing into memory can't fail
+    write_tree(&mut writer, tree)
+        .and_then(|_| writer.align_to_byte())
+        .and_then(|_| writer.flush())
+        .expect("Expected writing a tree into memory to succeed");
+    drop(writer);
+    bytes
+}
+
+// decode a tree from the bytes serialize_tree produces, or from the start of a block's model bits
+pub fn deserialize_tree(bytes: &[u8]) -> io::Result<Box<Tree>> {
+    read_tree(&mut FileReader::from_bytes(bytes)?)
+}
+
+pub fn debug_binary_file(filepath: &str) {
+    let mut reader = FileReader::new(filepath)
+        .expect("Cannot create reader in debugger");
+    println!();
+    let mut c = 0;
+    while !reader.eof() {
+        let bit = reader.read_bit()
+            .expect("Cannot read bit in debugger");
+        print!("{}", bit);
+        if (c + 1) % 4 == 0 {
+            print!(" ");
+        }
+        c += 1;
+    }
+}
+
+pub fn debug_tree_file(filepath: &str) {
+    let mut reader = FileReader::new(filepath)
+        .expect("Cannot create reader in debugger");
+    println!();
+    while !reader.eof() {
+        let bit = reader.read_bit()
+            .expect("Cannot read bit in debugger");
+        print!("{}", bit);
+        if bit > 0 {
+            let byte = reader.read_bits(8)
+                .expect("Cannot read bits in debugger");
+            print!("{}", byte as char);
+        }
+    }
+}
+
+pub fn debug_tree(node: &Tree, symbol_code: SymbolCode) {
+    write_debug_tree(&mut io::stdout().lock(), node, symbol_code).expect("Cannot write tree to stdout");
+}
+
+// write a line for each leaf of a tree with its code, the code's length, and its symbol
+pub fn write_debug_tree(out: &mut impl Write, node: &Tree, symbol_code: SymbolCode) -> io::Result<()> {
+    if node.is_leaf() {
+        writeln!(out, "Leaf: {:#b} {} {}", symbol_code.encoded_symbol, symbol_code.bit_len, node.plain_symbol as char)?;
+    }
+    if let Some(left) = &node.left {
+        let symbol_code = symbol_code.append_bit(0);
+        write_debug_tree(out, left, symbol_code)?;
+    }
+    if let Some(right) = &node.right {
+        let symbol_code = symbol_code.append_bit(1);
+        write_debug_tree(out, right, symbol_code)?;
+    }
+    Ok(())
+}
+
+// read the tree an entry was compressed with out of an archive and write its leaves, without decoding the entry's data
+// a dictionary coded entry shows the shared tree it was coded with
+pub fn write_block_tree(out: &mut impl Write, archive_filepath: &str, filename_rel: &str) -> io::Result<()> {
+    let reader = &mut FileReader::new(archive_filepath)?;
+    let blocks = get_file_blocks(reader)?;
+    let block = blocks.iter()
+        .find(|block| block.filename_rel == filename_rel)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No entry named {} in the archive", filename_rel)))?;
+    if block.method == METHOD_STORED {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Entry {} is stored as is, it has no tree", filename_rel)));
+    }
+    reader.seek((sizeof(SIG) as u64) + block.model_offset.unwrap_or(block.file_byte_offset))?;
+    let tree = read_tree(reader)?;
+    writeln!(out, "Tree of {}:", filename_rel)?;
+    write_debug_tree(out, &tree, SymbolCode::new())
+}
+
+// write the bytes in a range of a file as lines of 16, each with its offset, its bytes in hex, and the printable bytes as ascii
+// the dump stops early at the end of the file, and a range with no length runs to the end of the file
+pub fn write_hex_dump(out: &mut impl Write, reader: &mut FileReader, offset: u64, len: Option<u64>) -> io::Result<()> {
+    const LINE_LEN: usize = 16;
+    reader.seek(offset)?;
+    let mut line_offset = offset;
+    let mut remaining = len.unwrap_or(u64::MAX);
+    while remaining > 0 && !reader.eof() {
+        let mut line = vec![];
+        while line.len() < LINE_LEN && (line.len() as u64) < remaining && !reader.eof() {
+            line.push(reader.read_byte()?);
+        }
+        let hex: Vec<String> = (0..LINE_LEN)
+            .map(|i| line.get(i).map_or(String::from("  "), |byte| format!("{:02x}", byte)))
+            .collect();
+        let ascii: String = line.iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        // the hex is split into two groups of 8 like most hex dump tools
+        writeln!(out, "{:08x}  {}  {}  |{}|", line_offset, hex[..8].join(" "), hex[8..].join(" "), ascii)?;
+        line_offset += line.len() as u64;
+        remaining -= line.len() as u64;
+    }
+    Ok(())
+}
+
+// build the huffman tree for the contents of a file
+pub fn code_tree_from_file(filepath: &str) -> io::Result<CodeTree> {
+    let reader = &mut FileReader::new(filepath)?;
+    let freq_table = create_freq_table(reader)?;
+    Ok(create_code_tree(freq_table.as_ref()))
+}
+
+// build the code tree for a hand made frequency table, for looking at how the tree is shaped without a file
+pub fn code_tree_from_freq(freq_table: &[u64; TABLE_SIZE]) -> CodeTree {
+    create_code_tree(freq_table)
+}
+
+// write a tree as a graphviz digraph, internal nodes are labelled with their weight and leaves with their symbol and code
+pub fn write_tree_dot(out: &mut impl Write, tree: &Tree) -> io::Result<()> {
+    writeln!(out, "digraph huffman {{")?;
+    let mut next_id = 0;
+    write_dot_node(out, tree, SymbolCode::new(), &mut next_id)?;
+    writeln!(out, "}}")
+}
+
+// write a node and its subtree, returning the id of the node
+fn write_dot_node(out: &mut impl Write, node: &Tree, symbol_code: SymbolCode, next_id: &mut usize) -> io::Result<usize> {
+    let id = *next_id;
+    *next_id += 1;
+
+    if node.is_leaf() {
+        let code: String = (0..symbol_code.bit_len)
+            .map(|i| if (symbol_code.encoded_symbol >> i) & 1 == 1 { '1' } else { '0' })
+            .collect();
+        writeln!(out, "  n{} [shape=box, label=\"{}\\n{}\"];", id, dot_symbol(node.plain_symbol), code)?;
+        return Ok(id);
+    }
+
+    writeln!(out, "  n{} [shape=circle, label=\"{}\"];", id, node.weight)?;
+    for (bit, child) in [(0, &node.left), (1, &node.right)] {
+        // invariant: a non-leaf should have left and right nodes in a full tree
+        let child = child.as_ref().expect("Expected child node to be Some");
+        let child_id = write_dot_node(out, child, symbol_code.append_bit(bit), next_id)?;
+        writeln!(out, "  n{} -> n{} [label=\"{}\"];", id, child_id, bit)?;
+    }
+    Ok(id)
+}
+
+// printable symbols are shown as characters, anything that would need escaping is shown in hex
+fn dot_symbol(symbol: u8) -> String {
+    if symbol.is_ascii_graphic() && symbol != b'"' && symbol != b'\\' {
+        format!("'{}'", symbol as char)
+    } else {
+        format!("0x{:02x}", symbol)
+    }
+}
+
+// what to do when the directory an archive extracts into already exists
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExistingOutput {
+    // extract into the existing directory, overwriting any files with the same names
+    Merge,
+    // extract into a new directory named with the first free -1, -2, ... suffix
+    Number,
+    // refuse to extract anything
+    Error,
+}
+
+impl ExistingOutput {
+    pub fn parse(name: &str) -> Option<ExistingOutput> {
+        match name {
+            "merge" => Some(ExistingOutput::Merge),
+            "number" => Some(ExistingOutput::Number),
+            "error" => Some(ExistingOutput::Error),
+            _ => None
+        }
+    }
+}
+
+// what to do when the headers of an archive from before version 2 run into the end of the file without a group separator
+// the archive was most likely cut off at the end of its headers, but the headers that were read may still be usable
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissingGroupSeparator {
+    // print a warning and carry on with the headers that were read, the same as before the separator was checked
+    Warn,
+    // refuse to read the archive
+    Error,
+}
+
+// options controlling how files are extracted from an archive
+#[derive(Clone)]
+pub struct ExtractOptions {
+    pub multithreaded: bool,
+    // glob patterns selecting which entries to extract, every entry is extracted when empty
+    pub include: Vec<String>,
+    // allow entries stored with a full path to be extracted to that path instead of being rejected
+    pub absolute: bool,
+    // replace invalid utf-8 in entry names instead of rejecting the archive
+    pub lossy_names: bool,
+    // how an old archive missing the group separator after its headers is treated
+    pub missing_group_separator: MissingGroupSeparator,
+    // check there is enough free space for every entry before anything is extracted
+    pub check_space: bool,
+    // extract every entry even if some fail, reporting the failures at the end instead of stopping at the first
+    pub keep_going: bool,
+    pub existing_output: ExistingOutput,
+    // extract every entry by its base name directly into the output directory, dropping the directories it was archived in
+    pub flat: bool,
+    // cut names too long for the platform down to the limit instead of failing to extract them
+    pub shorten_names: bool,
+    // extract a file that would overwrite an existing file under a name from this template instead
+    pub rename_template: Option<String>,
+    // skip files already extracted with the right contents, for carrying on after an interrupted extraction
+    pub resume: bool,
+    // extract into this directory instead of a directory named after the archive next to it
+    pub directory: Option<String>,
+    // force every extracted file and directory to these unix modes, ignoring the executable bit the archive stored
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    // extract entries with names windows reserves for devices, like CON or NUL, under the name with an underscore added instead of failing
+    pub rename_reserved: bool,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        ExtractOptions {
+            multithreaded: false,
+            include: vec![],
+            absolute: false,
+            lossy_names: false,
+            missing_group_separator: MissingGroupSeparator::Warn,
+            check_space: true,
+            keep_going: false,
+            existing_output: ExistingOutput::Merge,
+            flat: false,
+            shorten_names: false,
+            rename_template: None,
+            resume: false,
+            directory: None,
+            file_mode: None,
+            dir_mode: None,
+            rename_reserved: false,
+        }
+    }
+}
+
+pub fn unarchive_zip(archive_filepath: &str, options: &ExtractOptions) -> io::Result<()> {
+    let now = Instant::now();
+
+    let blocks = read_included_blocks(archive_filepath, options)?;
+
+    // a resumed extraction carries on in the directory the interrupted extraction was writing to
+    let existing_output = if options.resume { ExistingOutput::Merge } else { options.existing_output };
+    let output_dir = match &options.directory {
+        // a chosen directory is merged into like tar does, it's often an existing directory such as the current one
+        Some(directory) => {
+            fs::create_dir_all(directory)?;
+            directory.clone()
+        }
+        None => resolve_output_dir(archive_filepath, existing_output)?,
+    };
+    if options.check_space {
+        check_space(required_space(&blocks), available_space(Path::new(&output_dir))?)?;
+    }
+
+    let names = entry_names(&blocks, options)?;
+    check_not_archive(&names, archive_filepath, &output_dir, options)?;
+    let sink = DirSink::new(archive_filepath, &output_dir, options);
+    let result = extract_blocks(&blocks, &names, archive_filepath, &sink, options);
+    if let Some(mode) = options.dir_mode {
+        apply_dir_mode(&sink.written_paths(), &output_dir, mode)?;
+    }
+    result?;
+
+    let elapsed = now.elapsed();
+    eprintln!("Finished unzipping in {:.2?}", elapsed);
+    Ok(())
+}
+
+// decompress each included entry into a sink instead of a directory on disk, such as an in-memory filesystem
+// names are given with forward slashes on every platform, options about the output directory are up to the sink
+pub fn unarchive_to_sink(archive_filepath: &str, sink: &dyn EntrySink, options: &ExtractOptions) -> io::Result<()> {
+    let blocks = read_included_blocks(archive_filepath, options)?;
+    let names = entry_names(&blocks, options)?;
+    extract_blocks(&blocks, &names, archive_filepath, sink, options)
+}
+
+// the headers are parsed and verified before anything is written
+fn read_included_blocks(archive_filepath: &str, options: &ExtractOptions) -> io::Result<Vec<FileBlock>> {
+    let blocks_reader = &mut FileReader::new(archive_filepath)?;
+    Ok(read_file_blocks_with(blocks_reader, options.lossy_names, options.missing_group_separator)?
+        .into_iter()
+        .filter(|block| matches_any(&options.include, &block.filename_rel))
+        .collect())
+}
+
+fn extract_blocks(blocks: &[FileBlock], names: &[String], archive_filepath: &str, sink: &dyn EntrySink, options: &ExtractOptions) -> io::Result<()> {
+    let threads = thread_count(options.multithreaded, blocks.len(), required_space(blocks), DEFAULT_MIN_PARALLEL_BYTES)?;
+    let tp = configure_thread_pool(threads)?;
+    decompress_files(blocks, names, archive_filepath, sink, options, &tp)
+}
+
+// extract an archive read from a stream such as stdin, into the chosen output directory since there's no archive path to name one after
+// extraction seeks around the archive, so the stream is saved to a temporary file first and removed once it's extracted
+pub fn unarchive_from_reader(source: &mut dyn Read, options: &ExtractOptions) -> io::Result<()> {
+    if options.directory.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Extracting an archive from a stream needs an output directory, use -C to choose one"));
+    }
+    let tmp_path = std::env::temp_dir().join(format!("zipper_stdin_{}.zipr", std::process::id()));
+    let tmp_filename = tmp_path.to_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Temporary directory path is not valid unicode"))?;
+    let result = fs::File::create(&tmp_path)
+        .and_then(|mut file| io::copy(source, &mut file))
+        .and_then(|_| unarchive_zip(tmp_filename, options));
+    let _ = fs::remove_file(&tmp_path);
+    result
+}
+
+// bytes needed to extract every block
+pub fn required_space(blocks: &[FileBlock]) -> u64 {
+    blocks.iter().fold(0, |total, block| total.saturating_add(block.og_byte_size))
+}
+
+// free space on the filesystem a path will be created on, the output directory usually doesn't exist yet
+fn available_space(path: &Path) -> io::Result<u64> {
+    let existing = std::path::absolute(path)?
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .map(Path::to_path_buf)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No part of {} exists", path.display())))?;
+    fs2::available_space(existing)
+}
+
+pub fn check_space(required: u64, available: u64) -> io::Result<()> {
+    if required > available {
+        return Err(io::Error::new(io::ErrorKind::StorageFull,
+            format!("Not enough free space to extract the archive: need {} bytes, have {} bytes", required, available)));
+    }
+    Ok(())
+}
+
+// the directory an archive extracts into is its path without the last extension, so backup.tar.zipr extracts into backup.tar
+// an archive without an extension would extract over itself, so it extracts into a directory with _extracted appended instead
+pub fn default_output_dir(archive_filepath: &str) -> String {
+    if Path::new(archive_filepath).extension().is_some() {
+        strip_ext(archive_filepath)
+    } else {
+        format!("{}_extracted", archive_filepath)
+    }
+}
+
+// pick the directory to extract into, checking whether it already exists
+pub fn resolve_output_dir(archive_filepath: &str, existing_output: ExistingOutput) -> io::Result<String> {
+    let output_dir = default_output_dir(archive_filepath);
+    if !Path::new(&output_dir).exists() {
+        return Ok(output_dir);
+    }
+    match existing_output {
+        ExistingOutput::Merge => Ok(output_dir),
+        ExistingOutput::Number => {
+            let mut n = 1;
+            loop {
+                let numbered_dir = format!("{}-{}", output_dir, n);
+                if !Path::new(&numbered_dir).exists() {
+                    eprintln!("Extracting into {} since {} already exists", numbered_dir, output_dir);
+                    return Ok(numbered_dir);
+                }
+                n += 1;
+            }
+        }
+        ExistingOutput::Error => Err(io::Error::new(io::ErrorKind::AlreadyExists,
+            format!("Cannot extract into {}, it already exists", output_dir))),
+    }
+}
+
+pub fn strip_ext(path: &str) -> String {
+    Path::new(path)
+        .with_extension("")
+        .display()
+        .to_string()
+}
+
+// a cheap check that a file starts with the archive signature, any error means it isn't an archive
+pub fn is_zipper_archive(filepath: &str) -> bool {
+    let mut sig = [0u8; 8];
+    fs::File::open(filepath)
+        .and_then(|mut file| file.read_exact(&mut sig))
+        .map(|_| u64::from_le_bytes(sig) == SIG)
+        .unwrap_or(false)
+}
+
+// the format version an archive was written with, read without checking it's a version this library can read
+// lets tools check compatibility against FORMAT_VERSION before operating on an archive
+pub fn archive_version(filepath: &str) -> io::Result<u8> {
+    let reader = &mut FileReader::new(filepath)?;
+    let sig = reader.read_u64().map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::InvalidData,
+            format!("File is too short to contain the {} byte zipr signature", sizeof(SIG))),
+        _ => e
+    })?;
+    if sig != SIG {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Cannot read the version of an invalid zipr file, the signature does not match"));
+    }
+    Ok(match reader.read_byte()? {
+        REC_SEP | GRP_SEP => 0,
+        version => version,
+    })
+}
+
+// when and by which version of the tool an archive was written, stored after the block count
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchiveMetadata {
+    // seconds since the unix epoch
+    pub created_at: u64,
+    pub tool_version: String,
+}
+
+impl ArchiveMetadata {
+    // SOURCE_DATE_EPOCH overrides the clock so archives can still be built reproducibly
+    pub fn current() -> ArchiveMetadata {
+        let created_at = env::var("SOURCE_DATE_EPOCH").ok()
+            .and_then(|epoch| epoch.parse().ok())
+            .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs()));
+        ArchiveMetadata { created_at, tool_version: String::from(env!("CARGO_PKG_VERSION")) }
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        (sizeof(self.created_at) + sizeof(0u32) + self.tool_version.len()) as u64
+    }
+
+    fn write(&self, writer: &mut FileWriter) -> io::Result<()> {
+        writer.write_u64(self.created_at)?;
+        writer.write_u32(self.tool_version.len() as u32)?;
+        writer.write_bytes(self.tool_version.as_bytes())
+    }
+
+    fn read(reader: &mut FileReader) -> io::Result<ArchiveMetadata> {
+        let created_at = reader.read_u64()?;
+        // the length isn't trusted yet so the bytes are read one at a time instead of allocated up front
+        let version_len = reader.read_u32()?;
+        let mut tool_version = vec![];
+        for _ in 0..version_len {
+            tool_version.push(reader.read_byte()?);
+        }
+        Ok(ArchiveMetadata { created_at, tool_version: String::from_utf8_lossy(&tool_version).into_owned() })
+    }
+}
+
+// the fields of an archive's headers that describe the archive rather than its blocks
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchiveInfo {
+    pub version: u8,
+    pub flags: u8,
+    pub block_count: u32,
+    pub metadata: Option<ArchiveMetadata>,
+}
+
+// read the headers of the first archive in a file to describe it, checking them the same as before extracting
+pub fn read_archive_info(archive_filepath: &str) -> io::Result<ArchiveInfo> {
+    let reader = &mut FileReader::new(archive_filepath)?;
+    let (_, info) = read_archive_headers(reader, true, MissingGroupSeparator::Warn)?;
+    Ok(info)
+}
+
+pub fn get_file_blocks(reader: &mut FileReader) -> io::Result<Vec<FileBlock>> {
+    read_file_blocks(reader, false)
+}
+
+// read the headers of each archive in a file, archives concatenated together are read as one archive
+// the offsets of each block are rebased so they are relative to the signature of the first archive
+pub fn read_file_blocks(reader: &mut FileReader, lossy_names: bool) -> io::Result<Vec<FileBlock>> {
+    read_file_blocks_with(reader, lossy_names, MissingGroupSeparator::Warn)
+}
+
+// read the headers of each archive in a file, choosing how an old archive missing its group separator is treated
+pub fn read_file_blocks_with(reader: &mut FileReader, lossy_names: bool, missing_group_separator: MissingGroupSeparator) -> io::Result<Vec<FileBlock>> {
+    let first_start = reader.byte_position()?;
+    let mut blocks = vec![];
+    loop {
+        let archive_start = reader.byte_position()?;
+        let (mut archive_blocks, info) = read_archive_headers(reader, lossy_names, missing_group_separator)?;
+
+        // the data segment starts after the headers and each block's data is stored contiguously
+        let mut archive_end = reader.byte_position()?;
+        for block in &mut archive_blocks {
+            archive_end += block.get_stored_size();
+            block.file_byte_offset += archive_start - first_start;
+            if let Some(data_offset) = &mut block.data_offset {
+                *data_offset += archive_start - first_start;
+            }
+            if let Some(model_offset) = &mut block.model_offset {
+                *model_offset += archive_start - first_start;
+            }
+        }
+        blocks.append(&mut archive_blocks);
+
+        // anything after the end of an archive must be another archive
+        reader.seek(archive_end)?;
+        if info.flags & FLAG_TRAILING_INDEX != 0 {
+            // the index repeats the headers that were just read, so it's only read to skip past it
+            read_index(reader, lossy_names, info.version)?;
+        }
+        if reader.eof() {
+            return Ok(blocks);
+        }
+    }
+}
+
+// read the blocks from the trailing index at the end of the stream without reading the front headers
+pub fn read_trailing_index(reader: &mut FileReader, lossy_names: bool) -> io::Result<Vec<FileBlock>> {
+    let missing = || io::Error::new(io::ErrorKind::NotFound, "Archive has no trailing index, it must be written with --index");
+    let footer_start = reader.stream_len()?.checked_sub(INDEX_FOOTER_SIZE).ok_or_else(missing)?;
+    reader.seek(footer_start)?;
+    let index_size = reader.read_u64()?;
+    if reader.read_u64()? != INDEX_SIG {
+        return Err(missing());
+    }
+    let index_start = footer_start.checked_sub(index_size)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Trailing index is larger than the archive"))?;
+    reader.seek(index_start)?;
+    // the index doesn't repeat the version or flags, so they're read from the start of the archive the index belongs to
+    let archive_start = index_archive_start(index_start, reader.read_u64()?)?;
+    reader.seek(archive_start + sizeof(SIG) as u64)?;
+    let version = reader.read_byte()?;
+    let flags = reader.read_byte()?;
+    reader.seek(index_start)?;
+    let mut blocks = read_index(reader, lossy_names, version)?;
+    if flags & FLAG_GROUPED_LAYOUT != 0 {
+        place_grouped_data(&mut blocks)?;
+    }
+    place_dictionary_models(&mut blocks, flags, archive_start + DICTIONARY_MODEL_OFFSET)?;
+    Ok(blocks)
+}
+
+// the index knows its own offset, which places the start of its archive within the stream
+fn index_archive_start(index_start: u64, index_offset: u64) -> io::Result<u64> {
+    index_offset.checked_add(sizeof(SIG) as u64)
+        .and_then(|offset| index_start.checked_sub(offset))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Trailing index is corrupt"))
+}
+
+// read an index and its footer starting at the reader's position, with offsets relative to the start of the stream
+fn read_index(reader: &mut FileReader, lossy_names: bool, version: u8) -> io::Result<Vec<FileBlock>> {
+    let index_start = reader.byte_position()?;
+    reader.start_crc();
+    let index_offset = reader.read_u64()?;
+    let block_count = reader.read_u32()?;
+    let mut blocks = vec![];
+    for _ in 0..block_count {
+        blocks.push(reader.read_block(lossy_names, version)?);
+    }
+    let index_crc = reader.current_crc();
+    if reader.read_u32()? != index_crc {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Trailing index checksum does not match, the index is corrupt"));
+    }
+    reader.read_u64()?;
+    if reader.read_u64()? != INDEX_SIG {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Trailing index is corrupt"));
+    }
+
+    let archive_start = index_archive_start(index_start, index_offset)?;
+    for block in &mut blocks {
+        block.file_byte_offset += archive_start;
+    }
+    Ok(blocks)
+}
+
+// read the headers of a single archive starting at its signature, returning the blocks and what the archive says about itself
+fn read_archive_headers(reader: &mut FileReader, lossy_names: bool, missing_group_separator: MissingGroupSeparator) -> io::Result<(Vec<FileBlock>, ArchiveInfo)> {
+    // an empty or short file is reported apart from a wrong signature since it is usually an interrupted copy
+    if reader.eof() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Cannot read from an empty file, it is not a zipr archive"));
+    }
+    let sig = reader.read_u64().map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::InvalidData,
+            format!("File is too short to contain the {} byte zipr signature", sizeof(SIG))),
+        _ => e
+    })?;
+    if sig != SIG {
+        return Err(io::Error::other("Cannot read from an invalid zipr file, the signature does not match"));
+    }
+    let headers_start = reader.byte_position()?;
+    reader.start_crc();
+    let version = match reader.read_byte()? {
+        // an archive from before the version byte starts with the separator of its first header
+        REC_SEP | GRP_SEP => {
+            reader.seek(headers_start)?;
+            0
+        }
+        version if (MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) => version,
+        version => return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Cannot read zipr format version {}, expected version {} to {}", version, MIN_FORMAT_VERSION, FORMAT_VERSION))),
+    };
+    if version < COUNTED_HEADERS_VERSION {
+        let blocks = read_separated_headers(reader, lossy_names, version, headers_start, missing_group_separator)?;
+        let block_count = blocks.len() as u32;
+        return Ok((blocks, ArchiveInfo { version, flags: 0, block_count, metadata: None }));
+    }
+    // version 2 archives have no flags byte, and so none of the features it marks
+    let flags = if version >= FLAGS_VERSION { reader.read_byte()? } else { 0 };
+    if flags & !(FLAG_COMPRESSED_NAMES | FLAG_TRAILING_INDEX | FLAG_GROUPED_LAYOUT | FLAG_SHARED_DICTIONARY) != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Archive has unknown header flags {:#04x}", flags)));
+    }
+    // the count isn't trusted yet so the blocks aren't allocated up front
+    let block_count = reader.read_u32()?;
+    // reaching eof before every header is read means the archive was cut off in the headers
+    let truncated = |e: io::Error, read_count: usize| match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::UnexpectedEof,
+            format!("Archive ends after {} of {} headers, the archive is truncated", read_count, block_count)),
+        _ => e
+    };
+    if flags & FLAG_SHARED_DICTIONARY != 0 {
+        // the tree is read where it is when a block is decoded, so it's only skipped here
+        let dictionary_len = reader.read_u32().map_err(|e| truncated(e, 0))?;
+        for _ in 0..dictionary_len {
+            reader.read_byte().map_err(|e| truncated(e, 0))?;
+        }
+    }
+    // archives before the metadata section was added don't record when or by what they were written
+    let metadata = if version >= METADATA_VERSION {
+        Some(ArchiveMetadata::read(reader).map_err(|e| truncated(e, 0))?)
+    } else {
+        None
+    };
+    let name_table = if flags & FLAG_COMPRESSED_NAMES != 0 {
+        Some(read_name_table(reader).map_err(|e| truncated(e, 0))?)
+    } else {
+        None
+    };
+
+    let mut blocks = vec![];
+    for _ in 0..block_count {
+        let block = match &name_table {
+            // the names are filled in once the table is decoded
+            Some(_) => reader.read_block_fields(String::new(), version),
+            None => reader.read_block(lossy_names, version),
+        };
+        blocks.push(block.map_err(|e| truncated(e, blocks.len()))?);
+    }
+    // verify the headers before any of the offsets or sizes are trusted, or the name table is decoded
+    let header_crc = reader.current_crc();
+    if reader.read_u32()? != header_crc {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Archive header checksum does not match, the headers are corrupt"));
+    }
+    if let Some(name_table) = &name_table {
+        // the table decodes exactly one name for each block
+        for (block, name) in blocks.iter_mut().zip(decode_name_table(name_table, block_count, lossy_names)?) {
+            block.filename_rel = name;
+        }
+    }
+    if flags & FLAG_GROUPED_LAYOUT != 0 {
+        place_grouped_data(&mut blocks)?;
+    }
+    place_dictionary_models(&mut blocks, flags, DICTIONARY_MODEL_OFFSET)?;
+    Ok((blocks, ArchiveInfo { version, flags, block_count, metadata }))
+}
+
+// read the headers of a version 1 or unversioned archive, which are marked by separators instead of counted
+fn read_separated_headers(
+    reader: &mut FileReader,
+    lossy_names: bool,
+    version: u8,
+    headers_start: u64,
+    missing_group_separator: MissingGroupSeparator,
+) -> io::Result<Vec<FileBlock>> {
+    let mut blocks = vec![];
+    let mut found_grp_sep = false;
+    while !reader.eof() {
+        let sep = reader.read_byte()?;
+        if sep == GRP_SEP {
+            found_grp_sep = true;
+            break;
+        }
+        if sep != REC_SEP {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("Expected a record separator before header {} but found byte {:#04x}", blocks.len(), sep)));
+        }
+        blocks.push(reader.read_block(lossy_names, version)?);
+    }
+    // reaching eof before the group separator means the archive was most likely cut off at the end of its headers
+    if !found_grp_sep {
+        let msg = format!("Archive ends after {} headers without a group separator, the archive is truncated", blocks.len());
+        match missing_group_separator {
+            MissingGroupSeparator::Error => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg)),
+            // the checksum would have followed the separator, so there's nothing to check the headers against
+            MissingGroupSeparator::Warn => eprintln!("Warning: {}", msg),
+        }
+    }
+    if version >= METHOD_VERSION {
+        // the checksum was added together with the version byte
+        let header_crc = reader.current_crc();
+        if found_grp_sep && reader.read_u32()? != header_crc {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Archive header checksum does not match, the headers are corrupt"));
+        }
+    } else {
+        // unversioned archives could overcount an offset by a byte, so the blocks are placed one after another from the end of the headers
+        let too_large = || io::Error::new(io::ErrorKind::InvalidData, "Block sizes are too large for a 64 bit offset");
+        let mut offset = reader.byte_position()? - headers_start;
+        for block in &mut blocks {
+            block.file_byte_offset = offset;
+            offset = block.tree_bit_size.checked_add(block.data_bit_size)
+                .and_then(|bit_size| offset.checked_add(bit_size.div_ceil(8)))
+                .ok_or_else(too_large)?;
+        }
+    }
+    Ok(blocks)
+}
+
+// a block coded with the dictionary reads the tree in the archive headers as its model, and its data starts at its own offset
+fn place_dictionary_models(blocks: &mut [FileBlock], flags: u8, model_offset: u64) -> io::Result<()> {
+    for block in blocks.iter_mut().filter(|block| block.method == METHOD_DICTIONARY) {
+        if flags & FLAG_SHARED_DICTIONARY == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{} is coded with a dictionary but the archive has none", block.filename_rel)));
+        }
+        block.model_offset = Some(model_offset);
+        // in the grouped layout the data was already placed after the trees
+        block.data_offset.get_or_insert(block.file_byte_offset);
+    }
+    Ok(())
+}
+
+// in the grouped layout each block's offset is where its tree starts, with every tree aligned to the next byte
+// the data of every block follows the last tree, in block order and each aligned to the next byte
+fn place_grouped_data(blocks: &mut [FileBlock]) -> io::Result<()> {
+    let too_large = || io::Error::new(io::ErrorKind::InvalidData, "Block sizes are too large for a 64 bit offset");
+    let mut data_offset = 0;
+    for block in blocks.iter() {
+        let tree_end = block.file_byte_offset.checked_add(block.tree_bit_size.div_ceil(8)).ok_or_else(too_large)?;
+        data_offset = data_offset.max(tree_end);
+    }
+    let mut placed = HashMap::new();
+    for block in blocks {
+        if block.hardlink {
+            // a hardlink's data is the data of the block it links to
+            let data_offset = placed.get(&link_key(block)).copied().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                format!("{} is a hardlink to data that no earlier block has", block.filename_rel)))?;
+            block.data_offset = Some(data_offset);
+            continue;
+        }
+        placed.insert(link_key(block), data_offset);
+        block.data_offset = Some(data_offset);
+        data_offset = data_offset.checked_add(block.data_bit_size.div_ceil(8)).ok_or_else(too_large)?;
+    }
+    Ok(())
+}
+
+// decompress each entry into a sink, this can be parallelized because each entry is written to its own destination
+// hardlinks are left out until the entries they link to are written, then the sink links them or they're decompressed again
+fn decompress_files(blocks: &[FileBlock], names: &[String], archive_filepath: &str, sink: &dyn EntrySink, options: &ExtractOptions, tp: &ThreadPool) -> io::Result<()> {
+    let extract = |(block, name): (&FileBlock, &String)| -> io::Result<()> {
+        if block.hardlink {
+            return Ok(());
+        }
+        decompress_entry(block, name, archive_filepath, sink)
+    };
+    let mut results: Vec<io::Result<()>> = if options.keep_going {
+        // every file is attempted on its own, so one bad block doesn't stop the rest from being extracted
+        tp.install(|| blocks.par_iter().zip(names).map(extract).collect())
+    } else {
+        tp.install(|| blocks.par_iter().zip(names).map(extract).collect::<io::Result<Vec<_>>>())?
+            .into_iter()
+            .map(Ok)
+            .collect()
+    };
+
+    let mut written = HashMap::new();
+    for ((block, name), result) in blocks.iter().zip(names).zip(&results) {
+        if !block.hardlink && result.is_ok() {
+            written.entry(link_key(block)).or_insert(name);
+        }
+    }
+    for (i, block) in blocks.iter().enumerate().filter(|(_, block)| block.hardlink) {
+        let result = link_entry(block, &names[i], written.get(&link_key(block)).copied(), archive_filepath, sink);
+        if !options.keep_going {
+            result?;
+        } else {
+            results[i] = result;
+        }
+    }
+
+    let mut failed = 0;
+    for (block, result) in blocks.iter().zip(results) {
+        if let Err(e) = result {
+            eprintln!("Failed to extract {}: {}", block.filename_rel, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(io::Error::other(format!("Failed to extract {} of {} files", failed, blocks.len())));
+    }
+    Ok(())
+}
+
+// the name each block is given to the sink, using forward slashes
+// the names are chosen before extracting so flattened names are numbered the same however the threads are scheduled
+fn entry_names(blocks: &[FileBlock], options: &ExtractOptions) -> io::Result<Vec<String>> {
+    if options.flat {
+        flat_names(blocks)
+    } else {
+        Ok(blocks.iter().map(|block| block.filename_rel.clone()).collect())
+    }
+}
+
+// refuse to extract anything over the archive itself before any file is written, since it would be truncated while it's still being read
+// a renaming template moves an output away from an existing file, so the archive is safe then
+fn check_not_archive(output_names: &[String], archive_filepath: &str, output_dir: &str, options: &ExtractOptions) -> io::Result<()> {
+    if options.rename_template.is_some() {
+        return Ok(());
+    }
+    let archive_path = Path::new(archive_filepath);
+    for filename_rel in output_names {
+        let path = output_path(&from_portable_path(filename_rel, MAIN_SEPARATOR), output_dir, options)?;
+        if is_same_file(&path, archive_path) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Cannot extract {} to {}, it is the archive being extracted", filename_rel, path.display())));
+        }
+    }
+    Ok(())
+}
+
+// whether two paths are the same file, either the same path once links are resolved or two hardlinks to one file
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    let (Ok(a_metadata), Ok(b_metadata)) = (fs::metadata(a), fs::metadata(b)) else {
+        return false;
+    };
+    same_inode(&a_metadata, &b_metadata) || matches!((fs::canonicalize(a), fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+#[cfg(unix)]
+fn same_inode(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_inode(_: &fs::Metadata, _: &fs::Metadata) -> bool {
+    false
+}
+
+// the base name of each entry, with a counter appended before the extension when an earlier entry has the same base name
+fn flat_names(blocks: &[FileBlock]) -> io::Result<Vec<String>> {
+    let mut used = HashSet::new();
+    let mut names = vec![];
+    for block in blocks {
+        let name = from_portable_path(&block.filename_rel, MAIN_SEPARATOR);
+        let path = Path::new(&name);
+        let file_name = path.file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                format!("Entry {} has no file name to extract it by", block.filename_rel)))?;
+        let mut flat_name = file_name.to_string_lossy().into_owned();
+        let mut n = 1;
+        while !used.insert(flat_name.clone()) {
+            let stem = path.file_stem().unwrap_or(file_name).to_string_lossy();
+            flat_name = match path.extension() {
+                Some(ext) => format!("{}-{}.{}", stem, n, ext.to_string_lossy()),
+                None => format!("{}-{}", stem, n),
+            };
+            n += 1;
+        }
+        names.push(flat_name);
+    }
+    Ok(names)
+}
+
+// link a hardlink to the entry written for the block it links to, or decompress its data again when the sink can't link it
+fn link_entry(block: &FileBlock, name: &str, target: Option<&String>, archive_filepath: &str, sink: &dyn EntrySink) -> io::Result<()> {
+    if let Some(target) = target {
+        if sink.link(name, target, &EntryMeta::new(block))? {
+            return Ok(());
+        }
+    }
+    decompress_entry(block, name, archive_filepath, sink)
+}
+
+fn decompress_entry(block: &FileBlock, name: &str, archive_filepath: &str, sink: &dyn EntrySink) -> io::Result<()> {
+    let meta = EntryMeta::new(block);
+    if sink.contains(name, &meta)? {
+        return Ok(());
+    }
+    let entry = sink.create(name, &meta)?;
+    // the entry is dropped before the sink finishes it, so a failed file is closed before it's removed
+    let result = FileReader::new(archive_filepath).and_then(|mut reader| {
+        let mut writer = FileWriter::from_writer(entry);
+        decompress(block, &mut reader, &mut writer)?;
+        writer.flush()
+    });
+    sink.finish(name, &meta, result)
+}
+
+// whether a file at a path already has the contents of a block, checking the size before the checksum
+pub(crate) fn matches_block(path: &Path, block: &FileBlock, archive_filepath: &str) -> io::Result<bool> {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() && metadata.len() == block.og_byte_size => {}
+        _ => return Ok(false),
+    }
+    let block_crc = block_crc(block, &mut FileReader::new(archive_filepath)?)?;
+    // invariant: an output path is built from valid strings
+    Ok(file_crc(path.to_str().expect("Expected output path to be valid string"))? == block_crc)
+}
+
+// checksum of a block's original contents
+// archives from before checksums were stored don't have one, so the block is decoded into a checksum instead of onto disk
+fn block_crc(block: &FileBlock, reader: &mut FileReader) -> io::Result<u32> {
+    if let Some(crc) = block.crc32 {
+        return Ok(crc);
+    }
+    let mut writer = FileWriter::from_writer(io::sink());
+    writer.start_crc();
+    decompress(block, reader, &mut writer)?;
+    Ok(writer.current_crc())
+}
+
+fn file_crc(filepath: &str) -> io::Result<u32> {
+    let reader = &mut FileReader::new(filepath)?;
+    reader.start_crc();
+    rest_crc(reader)
+}
+
+// the path a block would be extracted to before any renaming
+pub(crate) fn output_path(filename_rel: &str, output_dir: &str, options: &ExtractOptions) -> io::Result<PathBuf> {
+    let filename_rel = if options.shorten_names { shorten_path(filename_rel) } else { String::from(filename_rel) };
+    let filename_rel = check_reserved_names(&filename_rel, options.rename_reserved)?;
+    resolve_output_path(output_dir, &filename_rel, options.absolute)
+}
+
+// names windows opens as a device instead of a file, with or without an extension
+#[cfg(windows)]
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// whether windows treats a name as a device, ignoring case, anything after the first dot, and trailing spaces
+#[cfg(windows)]
+fn is_reserved_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end_matches(' ');
+    RESERVED_NAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved))
+}
+
+// check no name in a path is reserved, since creating it only gives a cryptic os error or writes to the device
+// renaming adds an underscore to the end of the reserved part of the name, so con.txt is extracted as con_.txt
+#[cfg(windows)]
+fn check_reserved_names(filename_rel: &str, rename: bool) -> io::Result<String> {
+    let mut names = vec![];
+    for name in filename_rel.split(MAIN_SEPARATOR) {
+        if !is_reserved_name(name) {
+            names.push(String::from(name));
+        } else if rename {
+            let stem_len = name.find('.').unwrap_or(name.len());
+            names.push(format!("{}_{}", name[..stem_len].trim_end_matches(' '), &name[stem_len..]));
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidFilename,
+                format!("Cannot extract {}, {} is reserved for a device on windows, use --rename-reserved to extract it under another name",
+                    filename_rel, name)));
+        }
+    }
+    Ok(names.join(&MAIN_SEPARATOR.to_string()))
+}
+
+// only windows reserves names for devices
+#[cfg(not(windows))]
+fn check_reserved_names(filename_rel: &str, _: bool) -> io::Result<String> {
+    Ok(String::from(filename_rel))
+}
+
+// the path a block is extracted to, after checking nothing is in the way and creating its parent directories
+pub(crate) fn prepare_output_path(block: &FileBlock, filename_rel: &str, output_dir: &str, options: &ExtractOptions) -> io::Result<PathBuf> {
+    let mut unarchived_path = output_path(filename_rel, output_dir, options)?;
+    if let Some(template) = &options.rename_template {
+        if unarchived_path.is_file() {
+            unarchived_path = conflict_free_path(&unarchived_path, template)?;
+        }
+    }
+    check_path_limits(&unarchived_path, &block.filename_rel)?;
+    check_output_conflicts(&unarchived_path)?;
+    if let Some(unarchived_parent) = unarchived_path.parent() {
+        fs::create_dir_all(unarchived_parent)?;
+    }
+    Ok(unarchived_path)
+}
+
+// longest single name and whole path the platform allows, in bytes
+#[cfg(windows)]
+const PLATFORM_NAME_MAX: usize = 255;
+#[cfg(windows)]
+const PLATFORM_PATH_MAX: usize = 260;
+#[cfg(target_os = "macos")]
+const PLATFORM_NAME_MAX: usize = 255;
+#[cfg(target_os = "macos")]
+const PLATFORM_PATH_MAX: usize = 1024;
+#[cfg(not(any(windows, target_os = "macos")))]
+const PLATFORM_NAME_MAX: usize = 255;
+#[cfg(not(any(windows, target_os = "macos")))]
+const PLATFORM_PATH_MAX: usize = 4096;
+
+// check a path fits the platform's limits up front, since failing to create it only gives a cryptic os error
+fn check_path_limits(path: &Path, entry: &str) -> io::Result<()> {
+    // an entry name long enough to break the limits is too long to print in full
+    let entry = match entry.char_indices().nth(60) {
+        Some((end, _)) => format!("{}...", &entry[..end]),
+        None => String::from(entry),
+    };
+    let too_long = |what: &str, len: usize, max: usize| io::Error::new(io::ErrorKind::InvalidFilename,
+        format!("Cannot extract {}, {} is {} bytes and the longest allowed is {} bytes, use --shorten-names to shorten it",
+            entry, what, len, max));
+
+    for component in path.components() {
+        let len = component.as_os_str().len();
+        if len > PLATFORM_NAME_MAX {
+            return Err(too_long("a name in its path", len, PLATFORM_NAME_MAX));
+        }
+    }
+    let len = path.as_os_str().len();
+    if len > PLATFORM_PATH_MAX {
+        return Err(too_long("its path", len, PLATFORM_PATH_MAX));
+    }
+    Ok(())
+}
+
+// shorten each name in a path that is too long for the platform
+fn shorten_path(filename_rel: &str) -> String {
+    Path::new(filename_rel)
+        .components()
+        .map(|component| match component {
+            Component::Normal(name) => PathBuf::from(shorten_name(&name.to_string_lossy())),
+            other => PathBuf::from(other.as_os_str()),
+        })
+        .collect::<PathBuf>()
+        .display()
+        .to_string()
+}
+
+// cut a name down to the platform limit, ending it with a hash of the whole name so shortened names stay distinct
+pub fn shorten_name(name: &str) -> String {
+    if name.len() <= PLATFORM_NAME_MAX {
+        return String::from(name);
+    }
+    let hash = format!("~{:08x}", crc32(name.as_bytes()));
+    // a short extension is kept so the file still opens with the same program
+    let ext = Path::new(name).extension()
+        .and_then(|ext| ext.to_str())
+        .filter(|ext| ext.len() <= 16)
+        .map(|ext| format!(".{}", ext))
+        .unwrap_or_default();
+    let mut cut = PLATFORM_NAME_MAX - hash.len() - ext.len();
+    while !name.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    format!("{}{}{}", &name[..cut], hash, ext)
+}
+
+pub const DEFAULT_RENAME_TEMPLATE: &str = "{name}.{n}{ext}";
+
+// the first name from the template that isn't taken, counting n up from 1
+// {name} is the file name without its extension and {ext} is the extension with its dot, or empty if there is none
+pub fn conflict_free_path(path: &Path, template: &str) -> io::Result<PathBuf> {
+    if !template.contains("{n}") {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("Rename template {} must contain {{n}} so each renamed file gets a different name", template)));
+    }
+    let name = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let ext = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    let named = template.replace("{name}", &name).replace("{ext}", &ext);
+    let mut n = 1;
+    loop {
+        let candidate = path.with_file_name(named.replace("{n}", &n.to_string()));
+        if !candidate.exists() {
+            return Ok(candidate);
+        }
+        n += 1;
+    }
+}
+
+// check that a file can be written to the path, a directory can't be replaced by a file and a file can't be used as a directory
+fn check_output_conflicts(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::IsADirectory,
+            format!("Cannot extract file {}, a directory already exists at that path", path.display())));
+    }
+    // the closest ancestor that exists must be a directory for the rest to be created inside it
+    let existing = path.ancestors()
+        .skip(1)
+        .find(|ancestor| fs::symlink_metadata(ancestor).is_ok());
+    if let Some(ancestor) = existing {
+        if !ancestor.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotADirectory,
+                format!("Cannot extract file {}, {} already exists as a file where a directory is needed",
+                    path.display(), ancestor.display())));
+        }
+    }
+    Ok(())
+}
+
+// resolve where an entry is written, rejecting names that would escape the output directory
+pub fn resolve_output_path(output_dir: &str, filename_rel: &str, allow_absolute: bool) -> io::Result<PathBuf> {
+    let name = Path::new(filename_rel);
+    let escape_err = |reason: &str| io::Error::new(io::ErrorKind::InvalidData,
+        format!("Entry {} {}, refusing to extract it", filename_rel, reason));
+
+    if name.components().any(|c| c == Component::ParentDir) {
+        return Err(escape_err("refers to a parent directory"));
+    }
+    if name.has_root() {
+        return if allow_absolute {
+            Ok(name.to_path_buf())
+        } else {
+            Err(escape_err("is stored with an absolute path, use --absolute to extract it to that path"))
+        };
+    }
+    Ok(Path::new(output_dir).join(name))
+}
+
+// copy the model and data bytes of a block out of an archive without decoding them
+pub fn extract_raw_block(archive_filepath: &str, filename_rel: &str) -> io::Result<Vec<u8>> {
+    let reader = &mut FileReader::new(archive_filepath)?;
+    let blocks = get_file_blocks(reader)?;
+    let block = blocks.iter()
+        .find(|block| block.filename_rel == filename_rel)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No entry named {} in the archive", filename_rel)))?;
+
+    // the model and data are written together and then aligned to the next byte, or aligned apart in the grouped layout
+    let too_large = || io::Error::new(io::ErrorKind::InvalidData, format!("Block size of {} is too large", filename_rel));
+    let ranges = match block.data_offset {
+        Some(data_offset) => vec![
+            (block.file_byte_offset, block.tree_bit_size.div_ceil(8)),
+            (data_offset, block.data_bit_size.div_ceil(8)),
+        ],
+        None => {
+            let byte_size = block.tree_bit_size.checked_add(block.data_bit_size)
+                .map(|bit_size| bit_size.div_ceil(8))
+                .ok_or_else(too_large)?;
+            vec![(block.file_byte_offset, byte_size)]
+        }
+    };
+    let mut raw = vec![];
+    for (offset, byte_size) in ranges {
+        reader.seek((sizeof(SIG) as u64) + offset)?;
+        let byte_size = usize::try_from(byte_size).map_err(|_| too_large())?;
+        let start = raw.len();
+        raw.resize(start + byte_size, 0);
+        if reader.read_into(&mut raw[start..])? < byte_size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Archive ends before the end of {}", filename_rel)));
+        }
+    }
+    Ok(raw)
+}
+
+// rewrite an archive of any readable version as the current version, copying each block's model and data without recompressing it
+// archives concatenated together are merged into one, and a grouped layout is rewritten with each model followed by its data
+pub fn upgrade_archive(archive_filepath: &str, output_filepath: &str) -> io::Result<Vec<FileBlock>> {
+    if is_same_file(Path::new(output_filepath), Path::new(archive_filepath)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("Cannot upgrade {} in place, it would be overwritten while it's read", archive_filepath)));
+    }
+    let info = read_archive_info(archive_filepath)?;
+    let reader = &mut FileReader::new(archive_filepath)?;
+    let blocks = read_file_blocks(reader, false)?;
+    let dictionary = read_dictionary(reader, &blocks)?;
+
+    // the headers are written the same as for a new archive, with each hardlink holding the index of the block it links to
+    let mut upgraded_blocks = vec![];
+    for (i, block) in blocks.iter().enumerate() {
+        let mut upgraded_block = FileBlock { data_offset: None, model_offset: None, ..block.clone() };
+        // older archives didn't store a checksum, so each block is decoded to take one
+        upgraded_block.crc32 = Some(block_crc(block, reader)?);
+        if block.hardlink {
+            let target = blocks[..i].iter()
+                .position(|target| !target.hardlink && link_key(target) == link_key(block))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                    format!("Hardlink {} doesn't link to an earlier block", block.filename_rel)))?;
+            upgraded_block.file_byte_offset = target as u64;
+        }
+        upgraded_blocks.push(upgraded_block);
+    }
+    let mut flags = info.flags & (FLAG_COMPRESSED_NAMES | FLAG_TRAILING_INDEX);
+    if dictionary.is_some() {
+        flags |= FLAG_SHARED_DICTIONARY;
+    }
+
+    // an archive keeps when and by what it was written, only archives from before the metadata was stored get the current one
+    let metadata = info.metadata.unwrap_or_else(ArchiveMetadata::current);
+
+    let mut placed_blocks = vec![];
+    write_atomically(output_filepath, false, |writer| {
+        writer.write_u64(SIG)?;
+        let data_end;
+        (placed_blocks, data_end) = write_block_headers(writer, &upgraded_blocks, flags, dictionary.as_deref(), &metadata)?;
+        for block in blocks.iter().filter(|block| !block.hardlink) {
+            copy_block(reader, writer, block)?;
+        }
+        if flags & FLAG_TRAILING_INDEX != 0 {
+            write_trailing_index(writer, &placed_blocks, data_end)?;
+        }
+        Ok(())
+    })?;
+    Ok(placed_blocks)
+}
+
+// the dictionary tree the blocks were coded with, read back from the headers it's stored in
+fn read_dictionary(reader: &mut FileReader, blocks: &[FileBlock]) -> io::Result<Option<Vec<u8>>> {
+    let model_offsets: HashSet<u64> = blocks.iter().filter_map(|block| block.model_offset).collect();
+    if model_offsets.len() > 1 {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "Cannot merge concatenated archives with different dictionaries"));
+    }
+    let Some(&model_offset) = model_offsets.iter().next() else {
+        return Ok(None);
+    };
+    // the tree is prefixed by its length
+    reader.seek((sizeof(SIG) as u64) + model_offset - sizeof(0u32) as u64)?;
+    let dictionary_len = reader.read_u32()?;
+    let mut dictionary = vec![];
+    for _ in 0..dictionary_len {
+        dictionary.push(reader.read_byte()?);
+    }
+    Ok(Some(dictionary))
+}
+
+// copy a block's model and data into the writer as they're stored, then align to the next byte
+// they're copied a byte at a time when stored together, and a bit at a time when the grouped layout stores them apart
+fn copy_block(reader: &mut FileReader, writer: &mut FileWriter, block: &FileBlock) -> io::Result<()> {
+    let ends_early = || io::Error::new(io::ErrorKind::UnexpectedEof, format!("Archive ends before the end of {}", block.filename_rel));
+    reader.seek((sizeof(SIG) as u64) + block.file_byte_offset)?;
+    match block.data_offset {
+        None => {
+            let mut remaining = block.get_stored_size();
+            let mut chunk = vec![0u8; 64 * 1024];
+            while remaining > 0 {
+                let count = remaining.min(chunk.len() as u64) as usize;
+                if reader.read_into(&mut chunk[..count])? < count {
+                    return Err(ends_early());
+                }
+                writer.write_bytes(&chunk[..count])?;
+                remaining -= count as u64;
+            }
+        }
+        Some(data_offset) => {
+            copy_bits(reader, writer, block.tree_bit_size).map_err(|_| ends_early())?;
+            reader.seek((sizeof(SIG) as u64) + data_offset)?;
+            copy_bits(reader, writer, block.data_bit_size).map_err(|_| ends_early())?;
+            writer.align_to_byte()?;
+        }
+    }
+    Ok(())
+}
+
+fn copy_bits(reader: &mut FileReader, writer: &mut FileWriter, bit_size: u64) -> io::Result<()> {
+    for _ in 0..(bit_size / 8) {
+        writer.write_bits(reader.read_bits(8)?, 8)?;
+    }
+    let remainder = (bit_size % 8) as u8;
+    writer.write_bits(reader.read_bits(remainder)?, remainder)
+}
+
+// compress a buffer in memory into the model and data bits of a single block, without an archive around it
+pub fn compress_bytes(name: &str, data: &[u8], method: u8) -> io::Result<(FileBlock, Vec<u8>)> {
+    let code_book = CodeBook::from_bytes_with_method(name, data, method)?;
+    let block = create_file_blocks(std::slice::from_ref(&code_book)).remove(0);
+
+    let mut compressed = vec![];
+    let mut writer = FileWriter::from_writer(&mut compressed);
+    code_book.coder.write_model(&mut writer)?;
+    code_book.coder.encode(&mut FileReader::from_bytes(data)?, &mut writer)?;
+    writer.align_to_byte()?;
+    writer.flush()?;
+    drop(writer);
+    Ok((block, compressed))
+}
+
+// exact number of bits huffman coding the bytes takes, the tree and the encoded data, without encoding anything
+pub fn compressed_bit_size(bytes: &[u8]) -> u64 {
+    let mut freq_table = [0u64; TABLE_SIZE];
+    for &byte in bytes {
+        freq_table[byte as usize] += 1;
+    }
+    let tree = create_code_tree(&freq_table);
+    let symbol_table = create_code_table(&tree);
+    let data_bit_size: u64 = freq_table.iter()
+        .zip(symbol_table.iter())
+        .map(|(freq, symbol)| freq * (symbol.bit_len as u64))
+        .sum();
+    // each leaf is a 1 bit followed by a byte, and each of the internal nodes is a 0 bit
+    let tree_bit_size = 10 * (tree.symbol_count as u64) - 1;
+    tree_bit_size + data_bit_size
+}
+
+// decompress the bits of a block created by compress_bytes back into the original buffer
+pub fn decompress_bytes(block: &FileBlock, compressed: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decompressed = vec![];
+    let mut writer = FileWriter::from_writer(&mut decompressed);
+    let mut coder = new_coder(block.method)?;
+    let reader = &mut FileReader::from_bytes(compressed)?;
+    coder.read_model(reader)?;
+    writer.start_crc();
+    coder.decode(reader, &mut writer, block)?;
+    check_block_crc(block, writer.current_crc())?;
+    writer.flush()?;
+    drop(writer);
+    Ok(decompressed)
+}
+
+pub fn sizeof<T>(_: T) -> usize {
+    std::mem::size_of::<T>()
+}
+
+// read the contents of a compressed archive and write into a decompressed stream
+pub(crate) fn decompress(block: &FileBlock, reader: &mut FileReader, writer: &mut FileWriter) -> io::Result<()> {
+    let coder = open_block(block, reader)?;
+    writer.start_crc();
+    coder.decode(reader, writer, block)?;
+    check_block_crc(block, writer.current_crc())
+}
+
+// check the bytes decompressed for a block have the checksum its header stored, so damaged data isn't mistaken for the file
+pub(crate) fn check_block_crc(block: &FileBlock, crc: u32) -> io::Result<()> {
+    match block.crc32 {
+        Some(expected) if expected != crc => Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Entry {} is corrupt, its checksum is {:08x} but the archive stored {:08x}", block.filename_rel, crc, expected))),
+        _ => Ok(())
+    }
+}
+
+// read a block's model and leave the reader at the start of its data segment, returning the coder to decode it with
+pub(crate) fn open_block(block: &FileBlock, reader: &mut FileReader) -> io::Result<Box<dyn SymbolCoder>> {
+    // read from the main archive: jumping to the data segment, or to the shared tree a dictionary coded block uses
+    reader.seek((sizeof(SIG) as u64) + block.model_offset.unwrap_or(block.file_byte_offset))?;
+
+    // the coder for the block's method reads its model and then decodes the data segment
+    let mut coder = new_coder(block.method)?;
+    coder.read_model(reader)?;
+    // the data follows straight on from the model unless the archive stores every model ahead of the data
+    if let Some(data_offset) = block.data_offset {
+        reader.seek((sizeof(SIG) as u64) + data_offset)?;
+    }
+    Ok(coder)
+}
+
+// measure the bit sizes of a block by reading its model and decoding its data, without trusting the sizes in its header
+// a header whose sizes differ from the measured sizes has been damaged even if its checksum was recomputed to match
+pub fn recompute_block_sizes(reader: &mut FileReader, block: &FileBlock) -> io::Result<FileBitSize> {
+    reader.seek((sizeof(SIG) as u64) + block.model_offset.unwrap_or(block.file_byte_offset))?;
+    let mut coder = new_coder(block.method)?;
+    let model_start = reader.read_len();
+    coder.read_model(reader)?;
+    // a shared tree is stored once in the headers instead of with the block
+    let tree_bit_size = if block.model_offset.is_some() { 0 } else { reader.read_len() - model_start };
+
+    if let Some(data_offset) = block.data_offset {
+        reader.seek((sizeof(SIG) as u64) + data_offset)?;
+    }
+    // the decoder stops after the original size, so the data size it checks against is lifted to the most it could be
+    let unbounded = FileBlock { data_bit_size: u64::MAX, ..block.clone() };
+    let data_start = reader.read_len();
+    coder.decode(reader, &mut FileWriter::from_writer(io::sink()), &unbounded)?;
+    let data_bit_size = reader.read_len() - data_start;
+    Ok(FileBitSize { tree_bit_size, data_bit_size })
+}
+
+// read the tree from a compressed archive
+pub(crate) fn read_tree(reader: &mut FileReader) -> io::Result<Box<Tree>> {
+    read_subtree(reader, 0)
+}
+
+// a tree of 256 symbols is at most 255 levels deep, so a deeper tree is corrupt and would only exhaust the stack
+const MAX_TREE_DEPTH: u32 = TABLE_SIZE as u32 - 1;
+
+fn read_subtree(reader: &mut FileReader, depth: u32) -> io::Result<Box<Tree>> {
+    if depth > MAX_TREE_DEPTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Huffman tree is deeper than any tree of 256 symbols, the tree is corrupt"));
+    }
+    let bit = reader.read_bit()?;
+    if bit == 1 {
+        // read 8 unaligned bits
+        let symbol = reader.read_bits(8)?;
+        Ok(Box::new(Tree::leaf(symbol, 0)))
+    } else {
+        let left = read_subtree(reader, depth + 1)?;
+        let right = read_subtree(reader, depth + 1)?;
+        Ok(Box::new(Tree::internal(left, right, 0, 0)))
+    }
+}
+
+// read the next symbol from the compressed archived and write it into a decompressed stream using the codebook tree
+pub(crate) fn decompress_symbol(reader: &mut FileReader, writer: &mut FileWriter, node: &Tree) -> io::Result<()> {
+    if node.is_leaf() {
+        writer.write_byte(node.plain_symbol)?;
+        Ok(())
+    } else {
+        let bit = reader.read_bit()?;
+        // invariant: a non-leaf should have left and right nodes in a full tree
+        if bit == 0 {
+            let left = node.left.as_ref().expect("Expected left node to be Some");
+            decompress_symbol(reader, writer, left)
+        } else {
+            let right = node.right.as_ref().expect("Expected right node to be Some");
+            decompress_symbol(reader, writer, right)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, io};
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use crate::bitwise_io::{FileReader, FileWriter};
+    use crate::structures::FileBlock;
+    use crate::coder::{METHOD_DICTIONARY, METHOD_HUFFMAN, METHOD_STORED};
+    use crate::dictionary::Dictionary;
+    use crate::compress::{archive_dir, decompress, get_file_blocks, schedule_by_size, unarchive_zip, write_atomically, write_file_blocks};
+    use crate::compress::{archive_dir_with_progress, archive_to_writer, unarchive_from_reader, check_space, collect_files, required_space};
+    use crate::crc::crc32;
+    use crate::progress::ProgressFormat;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::compress::{file_report, thread_count, tree_metrics, TreeMetrics, DEFAULT_MIN_PARALLEL_BYTES};
+    use crate::compress::{default_output_dir, entry_base_path, relative_name, resolve_output_dir, verify_headers, ExistingOutput};
+    use crate::compress::{compressed_bit_size, create_code_table, create_code_tree, validate_code_table, TABLE_SIZE};
+    use crate::compress::{compress_bytes, decompress_bytes, extract_raw_block, from_portable_path, is_zipper_archive, sizeof, to_portable_path, SIG};
+    use crate::compress::{deserialize_tree, serialize_tree};
+    use crate::compress::{code_tree_from_file, write_block_tree, write_tree_dot, ArchiveOptions, CodeBook, ExtractOptions, FileLabel};
+    use crate::compress::{conflict_free_path, DEFAULT_RENAME_TEMPLATE};
+    use crate::compress::{archive_version, create_file_blocks, read_archive_info, upgrade_archive, ArchiveMetadata, shorten_name, write_block_headers, FLAG_GROUPED_LAYOUT, FORMAT_VERSION};
+    use crate::compress::{dir_entry_size, recompute_block_sizes, sort_file_blocks, validate_offsets, ListSort, ListSortKey};
+    use crate::compress::{configure_thread_pool, create_code_books, scan_entries, write_archive, Contents};
+    use crate::compress::{read_file_blocks_with, MissingGroupSeparator, GRP_SEP};
+    use crate::compress::{build_codes, code_tree_from_freq, compressed_format, escape_index_name, export_codes, group_thousands, write_hex_dump};
+    use crate::structures::{ARCHIVE_HEADER_SIZE, CRC32_SIZE, FLAGS_VERSION, NAME_LEN_SIZE};
+    use crate::testing::{assert_archive_round_trip, scratch_dir};
+
+    // offset of the first header in an archive without a dictionary or name table
+    // the signature, version and flag bytes, block count, then the metadata
+    fn headers_start() -> usize {
+        sizeof(SIG) + 6 + ArchiveMetadata::current().size() as usize
+    }
+
+    // overwrite the offset field in a block's header and recompute the header checksum, so the headers still read as written
+    // the bytes hold at least the headers of an archive written without a dictionary or name table
+    fn rewrite_block_offset(bytes: &mut [u8], blocks: &[FileBlock], index: usize, offset: u64) {
+        let header_len = sizeof(SIG) + blocks[0].file_byte_offset as usize;
+        let header_pos = headers_start() + blocks[..index].iter().map(|block| block.get_header_size() as usize).sum::<usize>();
+        // the offset follows the name's length prefix, the name, and the tree and data sizes
+        let offset_pos = header_pos + 4 + blocks[index].filename_rel.len() + 16;
+        bytes[offset_pos..(offset_pos + 8)].copy_from_slice(&offset.to_le_bytes());
+        let crc_pos = header_len - 4;
+        let header_crc = crc32(&bytes[sizeof(SIG)..crc_pos]);
+        bytes[crc_pos..header_len].copy_from_slice(&header_crc.to_le_bytes());
+    }
+
+    #[test]
+    fn test_archive_preserves_entry_order() {
+        let dir = scratch_dir("entry_order");
+        let names = ["charlie.txt", "alpha.txt", "bravo.txt"];
+        let entries: Vec<String> = names.iter()
+            .map(|name| {
+                let path = dir.join(name);
+                fs::write(&path, format!("contents of {}", name)).unwrap();
+                path.to_str().unwrap().to_owned()
+            })
+            .collect();
+
+        let options = ArchiveOptions { multithreaded: true, ..ArchiveOptions::default() };
+        archive_dir(&entries, &options).unwrap();
+
+        let archive_path = format!("{}.zipr", entries[0]);
+        let blocks = get_file_blocks(&mut FileReader::new(&archive_path).unwrap()).unwrap();
+        let mut listing = vec![];
+        write_file_blocks(&mut listing, &blocks, false).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+
+        let positions: Vec<usize> = names.iter()
+            .map(|name| listing.find(name).expect("Expected listing to contain every entry"))
+            .collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]), "Listing is out of order:\n{}", listing);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sort_listing_by_compressed_size() {
+        let block = |name: &str, tree_bit_size: u64, data_bit_size: u64, og_byte_size: u64| FileBlock {
+            filename_rel: String::from(name),
+            file_byte_offset: 0,
+            og_byte_size,
+            crc32: None,
+            tree_bit_size,
+            data_bit_size,
+            method: METHOD_HUFFMAN,
+            data_offset: None,
+            model_offset: None,
+            executable: false,
+            hardlink: false,
+        };
+        let blocks = [block("large.txt", 99, 8000, 2000), block("small.txt", 19, 40, 10), block("medium.txt", 49, 800, 200), block("tie.txt", 0, 59, 8)];
+
+        let sort = ListSort::parse("compressed").unwrap();
+        assert_eq!(sort, ListSort { key: ListSortKey::Compressed, descending: false });
+        let names: Vec<String> = sort_file_blocks(&blocks, sort).into_iter().map(|block| block.filename_rel).collect();
+        // small and tie have the same compressed size so they keep archive order
+        assert_eq!(names, ["small.txt", "tie.txt", "medium.txt", "large.txt"]);
+        assert_eq!(blocks[0].filename_rel, "large.txt");
+
+        let names: Vec<String> = sort_file_blocks(&blocks, ListSort::parse("ratio:desc").unwrap()).into_iter().map(|block| block.filename_rel).collect();
+        assert_eq!(names, ["tie.txt", "small.txt", "medium.txt", "large.txt"]);
+        assert!(ListSort::parse("size").is_none());
+        assert!(ListSort::parse("name:up").is_none());
+    }
+
+    #[test]
+    fn test_recompute_block_sizes_detects_tampering() {
+        let dir = scratch_dir("recompute_sizes");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("text.txt"), "the quick brown fox jumps over the lazy dog ".repeat(200)).unwrap();
+        fs::write(input_dir.join("tiny.txt"), "tiny").unwrap();
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        let reader = &mut FileReader::new(&format!("{}.zipr", input_path)).unwrap();
+        let blocks = get_file_blocks(reader).unwrap();
+        assert_eq!(blocks.iter().map(|block| block.method).collect::<Vec<u8>>(), [METHOD_HUFFMAN, METHOD_STORED]);
+        for block in &blocks {
+            let measured = recompute_block_sizes(reader, block).unwrap();
+            assert_eq!(measured, block.get_bit_size());
+
+            // a header claiming more or fewer data bits than the data holds is caught either way
+            for data_bit_size in [block.data_bit_size + 3, block.data_bit_size - 8] {
+                let tampered = FileBlock { data_bit_size, ..block.clone() };
+                assert_ne!(recompute_block_sizes(reader, &tampered).unwrap(), tampered.get_bit_size());
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_data_fails_checksum() {
+        let dir = scratch_dir("corrupt_data");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("notes.txt"), "the quick brown fox jumps over the lazy dog").unwrap();
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+
+        // stored data decodes whatever its bytes are, so only the checksum can tell it was damaged
+        for method_options in [ArchiveOptions::default(), ArchiveOptions { store_below: u64::MAX, ..ArchiveOptions::default() }] {
+            archive_dir(std::slice::from_ref(&input_path), &method_options).unwrap();
+            let mut bytes = fs::read(&archive_path).unwrap();
+            let last = bytes.len() - 1;
+            bytes[last] ^= 0x5A;
+            fs::write(&archive_path, &bytes).unwrap();
+
+            fs::rename(&input_dir, dir.join("original")).unwrap();
+            assert!(unarchive_zip(&archive_path, &ExtractOptions::default()).is_err());
+            fs::remove_dir_all(&input_dir).unwrap();
+            fs::rename(dir.join("original"), &input_dir).unwrap();
+        }
+        let err = unarchive_zip(&archive_path, &ExtractOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("Entry input/notes.txt is corrupt"), "Unexpected error: {}", err);
+        assert!(!input_dir.join("input/notes.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_failure_names_file_and_removes_archive() {
+        let dir = scratch_dir("read_failure");
+        let path = dir.join("vanishing.txt");
+        let archive_path = format!("{}.zipr", path.to_str().unwrap());
+        // with nothing buffered the file is compressed from its spill file
+        let options = ArchiveOptions { max_buffered_bytes: 0, ..ArchiveOptions::default() };
+        let tp = configure_thread_pool(1).unwrap();
+        fs::write(&path, "contents that are modelled before the copy goes away").unwrap();
+
+        // the file is modelled, then its kept copy is damaged before its data is compressed
+        let replacements: [&dyn Fn(&Path); 2] = [
+            // reading a directory fails with an os error
+            &|spill| { fs::remove_file(spill).unwrap(); fs::create_dir(spill).unwrap(); },
+            &|spill| fs::write(spill, "shorter").unwrap(),
+        ];
+        for replace in replacements {
+            let labels = scan_entries(&[path.to_str().unwrap().to_owned()], &options).unwrap();
+            let code_books = create_code_books(&labels, &options, None, &tp).unwrap();
+            let blocks = create_file_blocks(&code_books);
+            let Some(Contents::Spilled(spill)) = &code_books[0].contents else {
+                panic!("Expected the file to be spilled");
+            };
+            replace(&spill.path);
+            let e = write_archive(&archive_path, &code_books, &blocks, &options, None, &mut None, &tp).unwrap_err();
+            assert!(e.to_string().contains("vanishing.txt"), "Expected the error to name the file: {}", e);
+            assert!(!Path::new(&archive_path).exists());
+            assert!(!Path::new(&format!("{}.tmp", archive_path)).exists());
+            let _ = fs::remove_dir(&spill.path);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // how many times each file being archived was opened
+    static OPENS: Mutex<Option<HashMap<String, usize>>> = Mutex::new(None);
+
+    pub(super) fn count_open(filepath: &str) {
+        *OPENS.lock().unwrap().get_or_insert_with(HashMap::new).entry(String::from(filepath)).or_default() += 1;
+    }
+
+    fn open_count(path: &Path) -> usize {
+        OPENS.lock().unwrap().as_ref().and_then(|opens| opens.get(path.to_str().unwrap()).copied()).unwrap_or(0)
+    }
+
+    #[test]
+    fn test_file_opened_once() {
+        let dir = scratch_dir("read_once");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        let files = [("kept.txt", "contents read once when the file is modelled"), ("spilled.txt", &"too large to keep in memory ".repeat(8))];
+        for (name, data) in &files {
+            fs::write(input_dir.join(name), data).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        // only the smaller file fits in the budget, the larger one is spilled
+        let options = ArchiveOptions { max_buffered_bytes: files[0].1.len() as u64, ..ArchiveOptions::default() };
+        let tp = configure_thread_pool(1).unwrap();
+
+        // the files are gone by the time their data is compressed, which only works if they aren't opened again
+        let labels = scan_entries(std::slice::from_ref(&input_path), &options).unwrap();
+        let code_books = create_code_books(&labels, &options, None, &tp).unwrap();
+        let blocks = create_file_blocks(&code_books);
+        for label in &labels {
+            assert_eq!(open_count(Path::new(&label.filename_abs)), 1, "Expected {} to be opened once", label.filename_abs);
+        }
+        assert!(code_books.iter().any(|code_book| matches!(code_book.contents, Some(Contents::Memory(_)))));
+        assert!(code_books.iter().any(|code_book| matches!(code_book.contents, Some(Contents::Spilled(_)))));
+        fs::remove_dir_all(&input_dir).unwrap();
+        write_archive(&archive_path, &code_books, &blocks, &options, None, &mut None, &tp).unwrap();
+        for label in &labels {
+            assert_eq!(open_count(Path::new(&label.filename_abs)), 1, "Expected {} to be opened once", label.filename_abs);
+        }
+
+        unarchive_zip(&archive_path, &ExtractOptions::default()).unwrap();
+        for (name, data) in &files {
+            assert_eq!(fs::read_to_string(input_dir.join("input").join(name)).unwrap(), *data);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resume_skips_extracted_files() {
+        let dir = scratch_dir("resume");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        let files = [("done.txt", "already extracted before the interruption"), ("missing.txt", "never extracted"), ("partial.txt", "only partly extracted")];
+        for (name, data) in &files {
+            fs::write(input_dir.join(name), data).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(&[input_path], &ArchiveOptions::default()).unwrap();
+        fs::rename(&input_dir, dir.join("original")).unwrap();
+
+        // the interrupted extraction wrote one file in full, and another with the right size but the wrong contents
+        let output_dir = input_dir.join("input");
+        fs::create_dir_all(&output_dir).unwrap();
+        fs::write(output_dir.join("done.txt"), files[0].1).unwrap();
+        fs::write(output_dir.join("partial.txt"), "only partly\0\0\0\0\0\0\0\0\0\0").unwrap();
+        let done_modified = fs::metadata(output_dir.join("done.txt")).unwrap().modified().unwrap();
+
+        // numbering would otherwise extract into a new directory since the output directory exists
+        let options = ExtractOptions { resume: true, existing_output: ExistingOutput::Number, ..ExtractOptions::default() };
+        std::thread::sleep(Duration::from_millis(20));
+        unarchive_zip(&archive_path, &options).unwrap();
+        for (name, data) in &files {
+            assert_eq!(fs::read_to_string(output_dir.join(name)).unwrap(), *data);
+        }
+        assert_eq!(fs::metadata(output_dir.join("done.txt")).unwrap().modified().unwrap(), done_modified);
+        assert!(!dir.join("input-1").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_path_filter_by_size() {
+        let dir = scratch_dir("path_filter");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(input_dir.join("nested")).unwrap();
+        fs::write(input_dir.join("short.txt"), "tiny").unwrap();
+        fs::write(input_dir.join("long.txt"), "more than ten bytes long").unwrap();
+        fs::write(input_dir.join("nested/exact.txt"), "ten bytes!").unwrap();
+        fs::write(input_dir.join("nested/longer.txt"), "also more than ten bytes").unwrap();
+
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let filter_seen = Arc::clone(&seen);
+        let options = ArchiveOptions::default().with_path_filter(move |path, metadata| {
+            filter_seen.lock().unwrap().push(path.file_name().unwrap().to_str().unwrap().to_owned());
+            metadata.len() > 10
+        });
+        let blocks = archive_dir(&[input_path], &options).unwrap();
+
+        let names: Vec<&str> = blocks.iter().map(|block| block.filename_rel.as_str()).collect();
+        assert_eq!(names, ["input/long.txt", "input/nested/longer.txt"]);
+        assert_eq!(*seen.lock().unwrap(), ["long.txt", "exact.txt", "longer.txt", "short.txt"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_exported_codes_are_prefix_free() {
+        let mut rng = StdRng::seed_from_u64(1479);
+        let mut freq_tables = vec![[0u64; TABLE_SIZE], [1u64; TABLE_SIZE]];
+        freq_tables.push(std::array::from_fn(|i| if i % 3 == 0 { 1 << (i % 40) } else { 0 }));
+        for _ in 0..20 {
+            freq_tables.push(std::array::from_fn(|_| if rng.gen_bool(0.5) { rng.gen_range(1..100000) } else { 0 }));
+        }
+
+        for freq_table in &freq_tables {
+            let symbol_table = build_codes(freq_table);
+            // the same check paranoid mode runs, that the codes are prefix free and their lengths meet the kraft equality
+            validate_code_table(&symbol_table, freq_table).unwrap();
+
+            let codes = export_codes(&symbol_table);
+            let used = freq_table.iter().filter(|&&freq| freq > 0).count();
+            if used < 2 {
+                // a lone symbol is coded with no bits so there's nothing to export
+                assert!(codes.is_empty());
+                continue;
+            }
+            assert_eq!(codes.len(), used);
+            for &(symbol, bits, len) in &codes {
+                assert!(freq_table[symbol as usize] > 0);
+                let code = &symbol_table[symbol as usize];
+                assert_eq!((bits, len), (code.encoded_symbol, code.bit_len));
+            }
+        }
+    }
+
+    #[test]
+    fn test_group_thousands() {
+        assert_eq!(group_thousands(0), "0");
+        assert_eq!(group_thousands(7), "7");
+        assert_eq!(group_thousands(999), "999");
+        assert_eq!(group_thousands(1000), "1,000");
+        assert_eq!(group_thousands(65536), "65,536");
+        assert_eq!(group_thousands(100000), "100,000");
+        assert_eq!(group_thousands(1048576), "1,048,576");
+        assert_eq!(group_thousands(u64::MAX), "18,446,744,073,709,551,615");
+    }
+
+    #[test]
+    fn test_hex_dump_lines() {
+        let bytes: Vec<u8> = b"zipper archive\x00\x01\x02\xff dump".to_vec();
+        let mut dump = vec![];
+        write_hex_dump(&mut dump, &mut FileReader::from_bytes(&bytes).unwrap(), 3, Some(20)).unwrap();
+        assert_eq!(String::from_utf8(dump).unwrap(), concat!(
+            "00000003  70 65 72 20 61 72 63 68  69 76 65 00 01 02 ff 20  |per archive.... |\n",
+            "00000013  64 75 6d 70                                       |dump|\n",
+        ));
+
+        // a range past the end of the file stops at the end, and nothing is written for an empty range
+        let mut dump = vec![];
+        write_hex_dump(&mut dump, &mut FileReader::from_bytes(&bytes).unwrap(), 16, None).unwrap();
+        assert_eq!(String::from_utf8(dump).unwrap(),
+            "00000010  02 ff 20 64 75 6d 70                              |.. dump|\n");
+        let mut dump = vec![];
+        write_hex_dump(&mut dump, &mut FileReader::from_bytes(&bytes).unwrap(), 0, Some(0)).unwrap();
+        assert!(dump.is_empty());
+    }
+
+    #[test]
+    fn test_parallel_archive_matches_sequential() {
+        let dir = scratch_dir("parallel_archive");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        // a file spanning many write buffers alongside a few small files
+        let large: Vec<u8> = (0..(3 * 1024 * 1024u32)).map(|i| (i.wrapping_mul(2654435761) >> 27) as u8).collect();
+        fs::write(input_dir.join("large.bin"), &large).unwrap();
+        for name in ["alpha.txt", "bravo.txt", "charlie.txt"] {
+            fs::write(input_dir.join(name), format!("contents of {}", name).repeat(10)).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+
+        // the archives may be written a second apart, so they're given the same creation time
+        let options = ArchiveOptions { created_at: Some(0), ..ArchiveOptions::default() };
+        archive_dir(std::slice::from_ref(&input_path), &options).unwrap();
+        let sequential = fs::read(&archive_path).unwrap();
+        let options = ArchiveOptions { multithreaded: true, ..options };
+        archive_dir(std::slice::from_ref(&input_path), &options).unwrap();
+        let parallel = fs::read(&archive_path).unwrap();
+        assert!(sequential == parallel, "Expected the parallel archive to match the sequential archive");
+
+        // every worker's temporary file is removed once it's concatenated
+        let leftovers: Vec<_> = fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".tmp"))
+            .collect();
+        assert!(leftovers.is_empty(), "Temporary files were left behind: {:?}", leftovers);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_archive_many_files_with_few_open() {
+        let dir = scratch_dir("max_open_files");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        for i in 0..300 {
+            fs::write(input_dir.join(format!("file_{:03}.txt", i)), format!("contents of file {} ", i).repeat(i % 7 + 1)).unwrap();
+        }
+        // the minimum parallel size is dropped so the threads run for small files
+        let options = ArchiveOptions { multithreaded: true, min_parallel_bytes: 0, max_open_files: Some(2), ..ArchiveOptions::default() };
+        let blocks = assert_archive_round_trip(&input_dir, &options);
+        assert_eq!(blocks.len(), 300);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // a sink that can only be appended to, and checks each write lands at the end of what was already written
+    struct ForwardOnly(io::Cursor<Vec<u8>>);
+
+    impl io::Write for ForwardOnly {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            assert_eq!(self.0.position(), self.0.get_ref().len() as u64, "Expected every write to append to the stream");
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    #[test]
+    fn test_archive_to_forward_only_stream() {
+        let dir = scratch_dir("stream_archive");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        let files = [("alpha.txt", "alpha file contents"), ("bravo.txt", "bravo file contents"), ("charlie.txt", "charlie")];
+        for (name, data) in &files {
+            fs::write(input_dir.join(name), data.repeat(50)).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+
+        // files are compressed in parallel and indexed so every part of the writer is covered
+        let options = ArchiveOptions { multithreaded: true, min_parallel_bytes: 0, trailing_index: true, created_at: Some(0), ..ArchiveOptions::default() };
+        let mut sink = ForwardOnly(io::Cursor::new(vec![]));
+        archive_to_writer(std::slice::from_ref(&input_path), &options, &mut sink).unwrap();
+        let streamed = sink.0.into_inner();
+
+        // the stream is the same archive that would be written to a file
+        archive_dir(std::slice::from_ref(&input_path), &options).unwrap();
+        assert!(streamed == fs::read(format!("{}.zipr", input_path)).unwrap(), "Expected the streamed archive to match the file");
+
+        let reader = &mut FileReader::from_bytes(&streamed).unwrap();
+        let blocks = get_file_blocks(reader).unwrap();
+        for (block, (_, data)) in blocks.iter().zip(&files) {
+            let mut decompressed = vec![];
+            let mut writer = FileWriter::from_writer(&mut decompressed);
+            decompress(block, reader, &mut writer).unwrap();
+            writer.flush().unwrap();
+            drop(writer);
+            assert_eq!(decompressed, data.repeat(50).into_bytes());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stream_starts_with_signature_and_extracts_from_reader() {
+        let dir = scratch_dir("stream_reader");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("notes.txt"), "streamed through a pipe").unwrap();
+        let input_path = input_dir.to_str().unwrap().to_owned();
+
+        // nothing but the archive is written to the stream, so it starts with the signature
+        let mut streamed: Vec<u8> = vec![];
+        archive_to_writer(std::slice::from_ref(&input_path), &ArchiveOptions::default(), &mut streamed).unwrap();
+        assert!(streamed.starts_with(&SIG.to_le_bytes()), "Expected the stream to start with the signature");
+
+        let err = unarchive_from_reader(&mut streamed.as_slice(), &ExtractOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let output_dir = dir.join("output");
+        let options = ExtractOptions { directory: Some(output_dir.to_str().unwrap().to_owned()), ..ExtractOptions::default() };
+        unarchive_from_reader(&mut streamed.as_slice(), &options).unwrap();
+        assert_eq!(fs::read_to_string(output_dir.join("input/notes.txt")).unwrap(), "streamed through a pipe");
+
+        // options that need an archive file are refused instead of silently ignored
+        let stats_path = dir.join("stats.json").to_str().unwrap().to_owned();
+        let file_options = [
+            ArchiveOptions { sidecar_index: true, ..ArchiveOptions::default() },
+            ArchiveOptions { compare_gzip: true, ..ArchiveOptions::default() },
+            ArchiveOptions { stats_path: Some(stats_path), ..ArchiveOptions::default() },
+        ];
+        for options in file_options {
+            let err = archive_to_writer(std::slice::from_ref(&input_path), &options, &mut vec![]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(!dir.join("stats.json").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_code_table_must_be_prefix_free() {
+        let mut freq_table = [0u64; TABLE_SIZE];
+        for (i, &byte) in b"abracadabra".iter().enumerate() {
+            freq_table[byte as usize] += i as u64 + 1;
+        }
+        let tree = create_code_tree(&freq_table);
+        let mut symbol_table = create_code_table(&tree);
+        validate_code_table(&symbol_table, &freq_table).unwrap();
+
+        // make the code for b a prefix of the longer code for c
+        let (b, c) = (b'b' as usize, b'c' as usize);
+        assert!(symbol_table[b].bit_len < symbol_table[c].bit_len);
+        let prefix_len = symbol_table[b].bit_len;
+        symbol_table[b].encoded_symbol = symbol_table[c].encoded_symbol & ((1 << prefix_len) - 1);
+        let err = validate_code_table(&symbol_table, &freq_table).unwrap_err();
+        assert!(err.to_string().contains("is a prefix of"), "Unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_small_jobs_use_one_thread() {
+        assert_eq!(thread_count(true, 1, 100, DEFAULT_MIN_PARALLEL_BYTES).unwrap(), 1);
+        assert_eq!(thread_count(true, 1, 100 * DEFAULT_MIN_PARALLEL_BYTES, DEFAULT_MIN_PARALLEL_BYTES).unwrap(), 1);
+        assert_eq!(thread_count(true, 50, 100, DEFAULT_MIN_PARALLEL_BYTES).unwrap(), 1);
+        assert_eq!(thread_count(false, 50, 100 * DEFAULT_MIN_PARALLEL_BYTES, DEFAULT_MIN_PARALLEL_BYTES).unwrap(), 1);
+
+        // a large enough job gets a thread per file up to the number of cores
+        let cores = std::thread::available_parallelism().unwrap().get();
+        assert_eq!(thread_count(true, 50, DEFAULT_MIN_PARALLEL_BYTES, DEFAULT_MIN_PARALLEL_BYTES).unwrap(), cores.min(50));
+    }
+
+    #[test]
+    fn test_tree_metrics() {
+        // c and b are merged first, so a has a 1 bit code and b and c have 2 bit codes
+        let mut freq_table = [0u64; TABLE_SIZE];
+        freq_table[b'a' as usize] = 3;
+        freq_table[b'b' as usize] = 2;
+        freq_table[b'c' as usize] = 1;
+        let metrics = tree_metrics(&create_code_tree(&freq_table));
+        assert_eq!(metrics, TreeMetrics { max_depth: 2, min_depth: 1, weighted_code_len: 1.5, leaf_count: 3 });
+
+        // a single symbol is the root and is coded with no bits
+        let metrics = tree_metrics(&create_code_tree(&[0u64; TABLE_SIZE]));
+        assert_eq!(metrics, TreeMetrics { max_depth: 0, min_depth: 0, weighted_code_len: 0.0, leaf_count: 1 });
+    }
+
+    #[test]
+    fn test_code_tree_ties_are_deterministic() {
+        // every weight ties with others, so the tree only depends on the tie break
+        let mut freq_table = [0u64; TABLE_SIZE];
+        for (i, freq) in freq_table.iter_mut().enumerate().skip(32).take(64) {
+            *freq = 1 + (i as u64 % 3);
+        }
+        let first = serialize_tree(&code_tree_from_freq(&freq_table).root);
+        let second = serialize_tree(&code_tree_from_freq(&freq_table).root);
+        assert_eq!(first, second);
+
+        // of nodes with the same weight the lower symbol is merged first and goes left, and merged nodes come after leaves
+        let mut freq_table = [0u64; TABLE_SIZE];
+        for symbol in b"dcba" {
+            freq_table[*symbol as usize] = 1;
+        }
+        let codes = export_codes(&build_codes(&freq_table));
+        assert_eq!(codes, [(b'a', 0b00, 2), (b'b', 0b10, 2), (b'c', 0b01, 2), (b'd', 0b11, 2)]);
+    }
+
+    #[test]
+    fn test_code_tree_from_freq() {
+        // a single symbol is the root, so its code has no bits
+        let mut freq_table = [0u64; TABLE_SIZE];
+        freq_table[b'a' as usize] = 100;
+        let tree = code_tree_from_freq(&freq_table);
+        assert_eq!(tree.symbol_count, 1);
+        assert_eq!(tree_metrics(&tree).max_depth, 0);
+
+        // two symbols each get a 1 bit code however uneven their weights are
+        freq_table[b'b' as usize] = 1;
+        let tree = code_tree_from_freq(&freq_table);
+        assert_eq!(tree.symbol_count, 2);
+        assert_eq!(tree_metrics(&tree).max_depth, 1);
+
+        // fibonacci weights are the worst case, each merge takes the next leaf so the tree is a chain of n - 1 levels
+        let mut freq_table = [0u64; TABLE_SIZE];
+        let (mut a, mut b) = (1, 1);
+        for freq in freq_table.iter_mut().take(20) {
+            *freq = a;
+            (a, b) = (b, a + b);
+        }
+        let tree = code_tree_from_freq(&freq_table);
+        assert_eq!(tree.symbol_count, 20);
+        assert_eq!(tree_metrics(&tree).max_depth, 19);
+        let max_code_len = build_codes(&freq_table).iter().map(|symbol| symbol.bit_len).max();
+        assert_eq!(max_code_len, Some(19));
+    }
+
+    #[test]
+    fn test_code_book_from_bytes() {
+        // a is coded with 1 bit, b and c with 2 bits each
+        let code_book = CodeBook::from_bytes("abc.txt", b"aaabbc");
+        assert_eq!(code_book.symbol_count(), 3);
+        assert_eq!(code_book.og_byte_size, 6);
+        assert_eq!(code_book.data_bit_size(), 3 + 2 * 2 + 2);
+        assert_eq!(code_book.tree_bit_size(), 10 * 3 - 1);
+
+        let empty = CodeBook::from_bytes("empty.txt", b"");
+        assert_eq!(empty.symbol_count(), 0);
+        assert_eq!(empty.data_bit_size(), 0);
+    }
+
+    #[test]
+    fn test_file_report() {
+        // 29 tree bits and 9 data bits take 5 bytes for 6 original bytes
+        let code_book = CodeBook::from_bytes("abc.txt", b"aaabbc");
+        let report = file_report(&code_book, Duration::from_millis(2));
+        assert_eq!(report, "abc.txt: 3 symbols, tree 29 bits, data 9 bits, ratio 0.833, 2.00ms");
+
+        let empty = CodeBook::from_bytes("empty.txt", b"");
+        assert!(file_report(&empty, Duration::ZERO).contains("ratio -,"));
+    }
+
+    #[test]
+    fn test_serialize_tree_round_trip() {
+        let mut freq_table = [0u64; TABLE_SIZE];
+        freq_table[b'a' as usize] = 3;
+        freq_table[b'b' as usize] = 2;
+        freq_table[b'c' as usize] = 1;
+        let tree = create_code_tree(&freq_table);
+        let bytes = serialize_tree(&tree.root);
+        // 3 leaves of 10 bits and 2 internal nodes of 1 bit, padded to 4 bytes
+        assert_eq!(bytes.len(), 4);
+
+        let decoded = deserialize_tree(&bytes).unwrap();
+        assert_eq!(serialize_tree(&decoded), bytes);
+        assert_eq!(decoded.left.as_ref().unwrap().plain_symbol, tree.root.left.as_ref().unwrap().plain_symbol);
+
+        // the encoding is the same as the model written before a block's data, up to the padding
+        let (_, compressed) = compress_bytes("abc", b"aaabbc", METHOD_HUFFMAN).unwrap();
+        assert_eq!(bytes[..3], compressed[..3]);
+
+        assert!(deserialize_tree(&bytes[..2]).is_err());
+    }
+
+    #[test]
+    fn test_write_tree_dot() {
+        let dir = scratch_dir("tree_dot");
+        let file_path = dir.join("abracadabra.txt");
+        fs::write(&file_path, "abracadabra").unwrap();
+
+        let tree = code_tree_from_file(file_path.to_str().unwrap()).unwrap();
+        let mut dot = vec![];
+        write_tree_dot(&mut dot, &tree.root).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+
+        assert!(dot.starts_with("digraph huffman {"));
+        assert_eq!(dot.matches("shape=box").count(), 5, "Expected a leaf for each of a, b, r, c, d:\n{}", dot);
+        assert_eq!(dot.matches("shape=circle").count(), 4);
+        assert_eq!(dot.matches("->").count(), 8);
+        assert!(dot.contains("label=\"11\""), "Expected the root to be weighted by the file length");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_block_tree() {
+        let dir = scratch_dir("block_tree");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("single.txt"), "a".repeat(100)).unwrap();
+        fs::write(input_dir.join("abracadabra.txt"), "abracadabra").unwrap();
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        let options = ArchiveOptions { store_below: 0, ..ArchiveOptions::default() };
+        archive_dir(std::slice::from_ref(&input_path), &options).unwrap();
+
+        // a file of one symbol has a tree of a single leaf, coded with no bits
+        let mut out = vec![];
+        write_block_tree(&mut out, &archive_path, "input/single.txt").unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out, "Tree of input/single.txt:\nLeaf: 0b0 0 a\n");
+
+        let mut out = vec![];
+        write_block_tree(&mut out, &archive_path, "input/abracadabra.txt").unwrap();
+        assert_eq!(String::from_utf8(out).unwrap().matches("Leaf:").count(), 5);
+
+        let err = write_block_tree(&mut vec![], &archive_path, "input/missing.txt").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_schedule_by_size_keeps_block_order() {
+        let label = |name: &str, size: u64| FileLabel {
+            filename_abs: String::from(name),
+            filename_rel: String::from(name),
+            size,
+            executable: false,
+            file_id: None,
+            hardlink_of: None,
+        };
+        let labels = [label("small", 10), label("large", 3000), label("medium", 500), label("tiny", 10)];
+        assert_eq!(schedule_by_size(&labels), vec![1, 2, 0, 3]);
+
+        let dir = scratch_dir("schedule_by_size");
+        let entries: Vec<String> = labels.iter()
+            .map(|label| {
+                let path = dir.join(&label.filename_rel);
+                fs::write(&path, "x".repeat(label.size as usize)).unwrap();
+                path.to_str().unwrap().to_owned()
+            })
+            .collect();
+
+        let options = ArchiveOptions { multithreaded: true, ..ArchiveOptions::default() };
+        archive_dir(&entries, &options).unwrap();
+
+        let archive_path = format!("{}.zipr", entries[0]);
+        let blocks = get_file_blocks(&mut FileReader::new(&archive_path).unwrap()).unwrap();
+        let names: Vec<&str> = blocks.iter().map(|block| block.filename_rel.as_str()).collect();
+        assert_eq!(names, vec!["small", "large", "medium", "tiny"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_decompress_stops_at_original_size() {
+        let dir = scratch_dir("decompress_stop");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        let data = "hello world, abracadabra";
+        fs::write(input_dir.join("file.txt"), data).unwrap();
+
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(&[input_path], &ArchiveOptions::default()).unwrap();
+        let block = &get_file_blocks(&mut FileReader::new(&archive_path).unwrap()).unwrap()[0];
+
+        // a data size near the limits of a u64 doesn't change where decompression stops
+        let output_path = dir.join("output.txt").to_str().unwrap().to_owned();
+        let large_block = FileBlock { data_bit_size: u64::MAX, ..block.clone() };
+        {
+            let reader = &mut FileReader::new(&archive_path).unwrap();
+            let writer = &mut FileWriter::new(&output_path).unwrap();
+            decompress(&large_block, reader, writer).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&output_path).unwrap(), data);
+
+        // a data size too small for the original bytes is corrupt
+        let small_block = FileBlock { data_bit_size: block.data_bit_size / 2, ..block.clone() };
+        let reader = &mut FileReader::new(&archive_path).unwrap();
+        let writer = &mut FileWriter::new(&output_path).unwrap();
+        assert!(decompress(&small_block, reader, writer).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_bit_size_matches_encoding() {
+        let noise: Vec<u8> = (0..10000u32).map(|i| (i.wrapping_mul(2654435761) >> 20) as u8).collect();
+        let text = "the quick brown fox jumps over the lazy dog".repeat(50);
+        let inputs: [&[u8]; 5] = [b"", b"a", b"aaaaaaaa", text.as_bytes(), &noise];
+        for input in inputs {
+            let predicted = compressed_bit_size(input);
+            let (block, compressed) = compress_bytes("input", input, METHOD_HUFFMAN).unwrap();
+            assert_eq!(predicted, block.tree_bit_size + block.data_bit_size);
+            assert_eq!(compressed.len() as u64, predicted.div_ceil(8));
+        }
+    }
+
+    #[test]
+    fn test_extract_raw_block() {
+        let dir = scratch_dir("raw_block");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        let data = b"the raw bytes of a block are copied without decoding";
+        fs::write(input_dir.join("file.txt"), data).unwrap();
+
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        let blocks = archive_dir(&[input_path], &ArchiveOptions::default()).unwrap();
+
+        let raw = extract_raw_block(&archive_path, "input/file.txt").unwrap();
+        assert_eq!(raw.len() as u64, (blocks[0].tree_bit_size + blocks[0].data_bit_size).div_ceil(8));
+        // the raw bytes are the same as compressing the file on its own
+        let (_, compressed) = compress_bytes("input/file.txt", data, METHOD_HUFFMAN).unwrap();
+        assert_eq!(raw, compressed);
+
+        let err = extract_raw_block(&archive_path, "missing.txt").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_block_layouts() {
+        let files: [(&str, &[u8]); 2] = [("abra.txt", b"abracadabra"), ("river.txt", b"mississippi river")];
+        let code_books: Vec<CodeBook> = files.iter().map(|(name, data)| CodeBook::from_bytes(name, data)).collect();
+        let blocks = create_file_blocks(&code_books);
+        let write_model = |writer: &mut FileWriter, code_book: &CodeBook| {
+            code_book.coder.write_model(writer).unwrap();
+        };
+        let write_data = |writer: &mut FileWriter, code_book: &CodeBook, data: &[u8]| {
+            code_book.coder.encode(&mut FileReader::from_bytes(data).unwrap(), writer).unwrap();
+        };
+
+        // the interleaved layout the archiver writes, each block's data follows straight on from its tree
+        let mut interleaved = vec![];
+        let mut writer = FileWriter::from_writer(&mut interleaved);
+        writer.write_u64(SIG).unwrap();
+        let (placed_blocks, _) = write_block_headers(&mut writer, &blocks, 0, None, &ArchiveMetadata::current()).unwrap();
+        for (code_book, (_, data)) in code_books.iter().zip(&files) {
+            write_model(&mut writer, code_book);
+            write_data(&mut writer, code_book, data);
+            writer.align_to_byte().unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        // the grouped layout stores every tree and then every block's data, each aligned to the next byte
+        let mut grouped = vec![];
+        let mut writer = FileWriter::from_writer(&mut grouped);
+        writer.write_u64(SIG).unwrap();
+        writer.start_crc();
+        writer.write_byte(FORMAT_VERSION).unwrap();
+        writer.write_byte(FLAG_GROUPED_LAYOUT).unwrap();
+        writer.write_u32(blocks.len() as u32).unwrap();
+        let metadata = ArchiveMetadata::current();
+        metadata.write(&mut writer).unwrap();
+        let mut tree_offset = ARCHIVE_HEADER_SIZE + metadata.size() + blocks.iter().map(FileBlock::get_header_size).sum::<u64>();
+        for block in &blocks {
+            writer.write_block(&FileBlock { file_byte_offset: tree_offset, ..block.clone() }).unwrap();
+            tree_offset += block.tree_bit_size.div_ceil(8);
+        }
+        writer.write_u32(writer.current_crc()).unwrap();
+        for code_book in &code_books {
+            write_model(&mut writer, code_book);
+            writer.align_to_byte().unwrap();
+        }
+        for (code_book, (_, data)) in code_books.iter().zip(&files) {
+            write_data(&mut writer, code_book, data);
+            writer.align_to_byte().unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        for archive in [&interleaved, &grouped] {
+            let reader = &mut FileReader::from_bytes(archive).unwrap();
+            let read_blocks = get_file_blocks(reader).unwrap();
+            for (block, (name, data)) in read_blocks.iter().zip(&files) {
+                let mut decompressed = vec![];
+                let mut writer = FileWriter::from_writer(&mut decompressed);
+                decompress(block, reader, &mut writer).unwrap();
+                writer.flush().unwrap();
+                drop(writer);
+                assert_eq!(&decompressed, data, "Data of {} was not found", name);
+            }
+            if archive == &interleaved {
+                assert_eq!(read_blocks, placed_blocks);
+                assert!(read_blocks.iter().all(|block| block.data_offset.is_none()));
+            } else {
+                // the first block's data starts right after the last tree
+                assert_eq!(read_blocks[0].data_offset, Some(tree_offset));
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_archive_from_seekable_stream() {
+        let dir = scratch_dir("seekable_stream");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        let files = [("alpha.txt", "alpha file contents"), ("bravo.txt", "bravo file contents")];
+        for (name, data) in &files {
+            fs::write(input_dir.join(name), data).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(&[input_path], &ArchiveOptions::default()).unwrap();
+
+        // the reader owns the stream, so nothing borrows from the archive bytes once they're read
+        let bytes = fs::read(&archive_path).unwrap();
+        let mut reader = FileReader::from_seekable(io::Cursor::new(bytes)).unwrap();
+        let blocks = get_file_blocks(&mut reader).unwrap();
+        assert_eq!(blocks.len(), files.len());
+        // blocks are read out of order to seek back and forth through the stream
+        for (block, (_, data)) in blocks.iter().zip(&files).rev() {
+            let mut decompressed = vec![];
+            decompress(block, &mut reader, &mut FileWriter::from_writer(&mut decompressed)).unwrap();
+            assert_eq!(decompressed, data.as_bytes());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_headers_flags_bad_offsets() {
+        let dir = scratch_dir("verify_headers");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        for name in ["alpha.txt", "bravo.txt", "charlie.txt"] {
+            fs::write(input_dir.join(name), format!("contents of {}", name)).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let options = ArchiveOptions { verify_headers: true, ..ArchiveOptions::default() };
+        archive_dir(std::slice::from_ref(&input_path), &options).unwrap();
+
+        // the headers verify against the blocks read from them, but not once an offset is off by a byte
+        let bytes = fs::read(format!("{}.zipr", input_path)).unwrap();
+        let mut blocks = get_file_blocks(&mut FileReader::from_bytes(&bytes).unwrap()).unwrap();
+        let header_len = sizeof(SIG) + blocks[0].file_byte_offset as usize;
+        verify_headers(&bytes[..header_len], &blocks).unwrap();
+        blocks[1].file_byte_offset += 1;
+        assert!(verify_headers(&bytes[..header_len], &blocks).is_err());
+
+        // a header written with the wrong offset reads back as written but still doesn't line up with the data
+        let mut header_bytes = bytes[..header_len].to_vec();
+        rewrite_block_offset(&mut header_bytes, &blocks, 1, blocks[1].file_byte_offset);
+        let err = verify_headers(&header_bytes, &blocks).unwrap_err();
+        assert!(err.to_string().contains("input/bravo.txt has data offset"), "Unexpected error: {}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_offsets_flags_overlap() {
+        let dir = scratch_dir("validate_offsets");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        for name in ["alpha.txt", "bravo.txt", "charlie.txt"] {
+            fs::write(input_dir.join(name), format!("contents of {}", name)).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+        validate_offsets(&archive_path).unwrap();
+
+        // move the second block back into the first block's data and fix up the checksum so the headers still read
+        let mut bytes = fs::read(&archive_path).unwrap();
+        let blocks = get_file_blocks(&mut FileReader::from_bytes(&bytes).unwrap()).unwrap();
+        rewrite_block_offset(&mut bytes, &blocks, 1, blocks[1].file_byte_offset - 2);
+        fs::write(&archive_path, &bytes).unwrap();
+
+        let err = validate_offsets(&archive_path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("input/bravo.txt has data offset"), "Unexpected error: {}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_output_dir_naming() {
+        assert_eq!(default_output_dir("dir/input.zipr"), "dir/input");
+        assert_eq!(default_output_dir("dir/backup.tar.zipr"), "dir/backup.tar");
+        assert_eq!(default_output_dir("dir/archive"), "dir/archive_extracted");
+
+        let dir = scratch_dir("output_dir_naming");
+        let archive_path = dir.join("input.zipr");
+        let archive_path = archive_path.to_str().unwrap();
+        let output_dir = dir.join("input");
+        assert_eq!(resolve_output_dir(archive_path, ExistingOutput::Error).unwrap(), output_dir.to_str().unwrap());
+
+        // an existing directory is merged into, numbered around, or refused
+        fs::create_dir_all(&output_dir).unwrap();
+        fs::create_dir_all(dir.join("input-1")).unwrap();
+        assert_eq!(resolve_output_dir(archive_path, ExistingOutput::Merge).unwrap(), output_dir.to_str().unwrap());
+        assert_eq!(resolve_output_dir(archive_path, ExistingOutput::Number).unwrap(), dir.join("input-2").to_str().unwrap());
+        let err = resolve_output_dir(archive_path, ExistingOutput::Error).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_in_place_of_directory_is_reported() {
+        let dir = scratch_dir("file_dir_conflict");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(input_dir.join("nested")).unwrap();
+        fs::write(input_dir.join("nested/file.txt"), "nested file").unwrap();
+
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(&[input_path], &ArchiveOptions::default()).unwrap();
+        fs::rename(&input_dir, dir.join("original")).unwrap();
+
+        // a file sits where the archive expects the nested directory
+        let conflict_path = input_dir.join("input/nested");
+        fs::create_dir_all(conflict_path.parent().unwrap()).unwrap();
+        fs::write(&conflict_path, "not a directory").unwrap();
+
+        let err = unarchive_zip(&archive_path, &ExtractOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotADirectory);
+        assert!(err.to_string().contains(conflict_path.to_str().unwrap()), "Unexpected error: {}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_write_leaves_destination_untouched() {
+        let dir = scratch_dir("atomic_write");
+        let archive_path = dir.join("archive.zipr").to_str().unwrap().to_owned();
+        fs::write(&archive_path, "a previous good archive").unwrap();
+
+        let result = write_atomically(&archive_path, false, |writer| {
+            for byte in 0..u8::MAX {
+                writer.write_byte(byte)?;
+            }
+            writer.flush()?;
+            Err(io::Error::other("simulated failure part way through the archive"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&archive_path).unwrap(), "a previous good archive");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1, "The temporary file should be removed");
+
+        write_atomically(&archive_path, false, |writer| writer.write_u64(SIG)).unwrap();
+        assert_eq!(fs::read(&archive_path).unwrap(), SIG.to_le_bytes());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // archives a directory of files with a method, then checks every file extracts to the original
+    fn assert_method_round_trips(name: &str, method: u8) {
+        let dir = scratch_dir(name);
+        let input_dir = dir.join("input");
+        fs::create_dir_all(input_dir.join("nested")).unwrap();
+        let files = [
+            ("text.txt", b"hello world, abracadabra".to_vec()),
+            ("nested/binary.bin", (0..2000u32).map(|i| (i * 7 % 251) as u8).collect()),
+            ("single.txt", b"zzzzzzzz".to_vec()),
+        ];
+        for (name, data) in &files {
+            fs::write(input_dir.join(name), data).unwrap();
+        }
+
+        // small files are coded with the method too so its edge cases are covered
+        let options = ArchiveOptions { method, store_below: 0, ..ArchiveOptions::default() };
+        let blocks = assert_archive_round_trip(&input_dir, &options);
+        assert!(blocks.iter().all(|block| block.method == method));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_header_fails_before_extraction() {
+        let dir = scratch_dir("corrupt_header");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("file.txt"), "some data that should never be extracted").unwrap();
+
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        let blocks = archive_dir(&[input_path], &ArchiveOptions::default()).unwrap();
+        fs::remove_dir_all(&input_dir).unwrap();
+
+        // flip a bit in the filename of the first header, just past its length prefix
+        // the name stays valid utf-8 so only the checksum can catch the corruption
+        let mut bytes = fs::read(&archive_path).unwrap();
+        let name_pos = headers_start() + NAME_LEN_SIZE as usize;
+        let name = blocks[0].filename_rel.as_bytes();
+        assert_eq!(&bytes[name_pos..name_pos + name.len()], name);
+        bytes[name_pos] ^= 0x01;
+        fs::write(&archive_path, bytes).unwrap();
+
+        let err = unarchive_zip(&archive_path, &ExtractOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("checksum"), "Unexpected error: {}", err);
+        assert!(!input_dir.exists(), "Nothing should be extracted from a corrupt archive");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extract_with_include_patterns() {
+        let dir = scratch_dir("include_patterns");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(input_dir.join("src")).unwrap();
+        fs::write(input_dir.join("notes.txt"), "some notes").unwrap();
+        fs::write(input_dir.join("src/readme.txt"), "read me").unwrap();
+        fs::write(input_dir.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(input_dir.join("data.bin"), [0u8, 1, 2, 3]).unwrap();
+
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(&[input_path], &ArchiveOptions::default()).unwrap();
+        fs::rename(&input_dir, dir.join("original")).unwrap();
+
+        let options = ExtractOptions { include: vec![String::from("*.txt")], ..ExtractOptions::default() };
+        unarchive_zip(&archive_path, &options).unwrap();
+
+        let output_dir = input_dir.join("input");
+        assert_eq!(fs::read_to_string(output_dir.join("notes.txt")).unwrap(), "some notes");
+        assert_eq!(fs::read_to_string(output_dir.join("src/readme.txt")).unwrap(), "read me");
+        assert!(!output_dir.join("src/main.rs").exists());
+        assert!(!output_dir.join("data.bin").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_keep_going_extracts_good_files() {
+        let dir = scratch_dir("keep_going");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        let files = ["alpha.txt", "bravo.txt", "charlie.txt"];
+        for name in files {
+            fs::write(input_dir.join(name), format!("the contents of {} ", name).repeat(20)).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+        let blocks = get_file_blocks(&mut FileReader::new(&archive_path).unwrap()).unwrap();
+
+        // a tree of nothing but internal nodes is too deep to decode, so bravo's block can't be extracted
+        let bravo = blocks.iter().find(|block| block.filename_rel == "input/bravo.txt").unwrap();
+        let tree_start = sizeof(SIG) + bravo.file_byte_offset as usize;
+        let mut bytes = fs::read(&archive_path).unwrap();
+        bytes[tree_start..(tree_start + 40)].fill(0);
+        fs::write(&archive_path, &bytes).unwrap();
+        fs::rename(&input_dir, dir.join("original")).unwrap();
+
+        let options = ExtractOptions { keep_going: true, ..ExtractOptions::default() };
+        let err = unarchive_zip(&archive_path, &options).unwrap_err();
+        assert!(err.to_string().contains("1 of 3"), "Unexpected error: {}", err);
+        for name in ["alpha.txt", "charlie.txt"] {
+            let extracted = fs::read_to_string(input_dir.join("input").join(name)).unwrap();
+            assert_eq!(extracted, format!("the contents of {} ", name).repeat(20));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_flat_extraction_numbers_collisions() {
+        let dir = scratch_dir("flat_extract");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(input_dir.join("a")).unwrap();
+        fs::create_dir_all(input_dir.join("b")).unwrap();
+        fs::write(input_dir.join("a/x.txt"), "x in a").unwrap();
+        fs::write(input_dir.join("b/x.txt"), "x in b").unwrap();
+        fs::write(input_dir.join("b/y.txt"), "y in b").unwrap();
+
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+        fs::rename(&input_dir, dir.join("original")).unwrap();
+
+        let options = ExtractOptions { flat: true, ..ExtractOptions::default() };
+        unarchive_zip(&format!("{}.zipr", input_path), &options).unwrap();
+        assert_eq!(fs::read_to_string(input_dir.join("x.txt")).unwrap(), "x in a");
+        assert_eq!(fs::read_to_string(input_dir.join("x-1.txt")).unwrap(), "x in b");
+        assert_eq!(fs::read_to_string(input_dir.join("y.txt")).unwrap(), "y in b");
+        assert!(!input_dir.join("input").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extract_into_directory() {
+        let dir = scratch_dir("extract_directory");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("a.txt"), "extracted somewhere else").unwrap();
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+        fs::remove_dir_all(&input_dir).unwrap();
+
+        // the target directory doesn't exist yet and has nothing to do with the archive's name or location
+        let target_dir = dir.join("elsewhere").join("target");
+        let options = ExtractOptions { directory: Some(target_dir.to_str().unwrap().to_owned()), ..ExtractOptions::default() };
+        unarchive_zip(&format!("{}.zipr", input_path), &options).unwrap();
+        assert_eq!(fs::read_to_string(target_dir.join("input").join("a.txt")).unwrap(), "extracted somewhere else");
+        assert!(!input_dir.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extract_refuses_to_overwrite_archive() {
+        let dir = scratch_dir("extract_over_archive");
+        let input_path = dir.join("pack");
+        fs::write(&input_path, "the only file in the archive").unwrap();
+        let input_path = input_path.to_str().unwrap().to_owned();
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        // the archive takes the place of its only entry, so extracting next to it would write over it
+        fs::rename(format!("{}.zipr", input_path), &input_path).unwrap();
+        let archive_bytes = fs::read(&input_path).unwrap();
+        let options = ExtractOptions { directory: Some(dir.to_str().unwrap().to_owned()), ..ExtractOptions::default() };
+        let err = unarchive_zip(&input_path, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("it is the archive being extracted"), "Unexpected error: {}", err);
+        assert_eq!(fs::read(&input_path).unwrap(), archive_bytes);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_follow_parent_round_trips_full_path() {
+        let dir = scratch_dir("follow_parent");
+        let nested_dir = dir.join("a").join("b").join("c");
+        fs::create_dir_all(&nested_dir).unwrap();
+        let file_path = nested_dir.join("file.txt");
+        fs::write(&file_path, "stored with its full path").unwrap();
+
+        let input_path = file_path.to_str().unwrap().to_owned();
+        let archive_path = dir.join("full.zipr").to_str().unwrap().to_owned();
+        let options = ArchiveOptions { follow_parent: true, ..ArchiveOptions::default() };
+        let blocks = archive_dir(std::slice::from_ref(&input_path), &options).unwrap();
+        assert_eq!(blocks[0].filename_rel, input_path);
+        fs::rename(format!("{}.zipr", input_path), &archive_path).unwrap();
+        fs::remove_file(&file_path).unwrap();
+
+        // full paths escape the output directory so they need an explicit confirmation
+        let err = unarchive_zip(&archive_path, &ExtractOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("--absolute"), "Unexpected error: {}", err);
+        assert!(!file_path.exists());
+
+        let options = ExtractOptions { absolute: true, ..ExtractOptions::default() };
+        unarchive_zip(&archive_path, &options).unwrap();
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "stored with its full path");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_follow_parent_round_trips_parent_relative_path() {
+        let dir = scratch_dir("follow_parent_relative");
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::create_dir_all(dir.join("b")).unwrap();
+        let file_path = dir.join("b").join("f.txt");
+        fs::write(&file_path, "named through a parent directory").unwrap();
+
+        // a path through .. is stored as the full path it leads to, which can be extracted
+        let input_path = dir.join("a").join("..").join("b").join("f.txt").to_str().unwrap().to_owned();
+        let archive_path = dir.join("relative.zipr").to_str().unwrap().to_owned();
+        let options = ArchiveOptions { follow_parent: true, ..ArchiveOptions::default() };
+        let blocks = archive_dir(std::slice::from_ref(&input_path), &options).unwrap();
+        assert_eq!(blocks[0].filename_rel, file_path.to_str().unwrap());
+        fs::rename(format!("{}.zipr", input_path), &archive_path).unwrap();
+        fs::remove_file(&file_path).unwrap();
+
+        let options = ExtractOptions { absolute: true, ..ExtractOptions::default() };
+        unarchive_zip(&archive_path, &options).unwrap();
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "named through a parent directory");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_headers_are_flagged() {
+        let dir = scratch_dir("truncated_headers");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("first.txt"), "first file").unwrap();
+        fs::write(input_dir.join("second.txt"), "second file").unwrap();
+
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(&[input_path], &ArchiveOptions::default()).unwrap();
+        let blocks = get_file_blocks(&mut FileReader::new(&archive_path).unwrap()).unwrap();
+
+        // cut the archive off part way through the last header, which is followed by the 4 byte checksum
+        let cut_pos = sizeof(SIG) + blocks[0].file_byte_offset as usize - 8;
+        let bytes = fs::read(&archive_path).unwrap();
+        fs::write(&archive_path, &bytes[..cut_pos]).unwrap();
+
+        let err = get_file_blocks(&mut FileReader::new(&archive_path).unwrap()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(err.to_string().contains("after 1 of 2 headers"), "Unexpected error: {}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concatenated_archives_read_as_one() {
+        let dir = scratch_dir("concatenated");
+        let mut archive_bytes = vec![];
+        for (name, contents) in [("first", "the first archive"), ("second", "the second archive")] {
+            let input_dir = dir.join(name);
+            fs::create_dir_all(&input_dir).unwrap();
+            fs::write(input_dir.join(format!("{}_a.txt", name)), contents).unwrap();
+            fs::write(input_dir.join(format!("{}_b.txt", name)), contents.repeat(3)).unwrap();
+
+            let input_path = input_dir.to_str().unwrap().to_owned();
+            archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+            archive_bytes.extend(fs::read(format!("{}.zipr", input_path)).unwrap());
+        }
+        let archive_path = dir.join("combined.zipr").to_str().unwrap().to_owned();
+        fs::write(&archive_path, archive_bytes).unwrap();
+
+        let blocks = get_file_blocks(&mut FileReader::new(&archive_path).unwrap()).unwrap();
+        let names: Vec<&str> = blocks.iter().map(|block| block.filename_rel.as_str()).collect();
+        assert_eq!(names, vec!["first/first_a.txt", "first/first_b.txt", "second/second_a.txt", "second/second_b.txt"]);
+
+        unarchive_zip(&archive_path, &ExtractOptions::default()).unwrap();
+        let output_dir = dir.join("combined");
+        assert_eq!(fs::read_to_string(output_dir.join("first/first_a.txt")).unwrap(), "the first archive");
+        assert_eq!(fs::read_to_string(output_dir.join("second/second_b.txt")).unwrap(), "the second archive".repeat(3));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_time_limit_aborts_without_archive() {
+        let dir = scratch_dir("time_limit");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        for i in 0..4 {
+            fs::write(input_dir.join(format!("{}.txt", i)), "some text to compress".repeat(100)).unwrap();
+        }
+
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        let options = ArchiveOptions { time_limit: Some(Duration::ZERO), ..ArchiveOptions::default() };
+        let err = archive_dir(&[input_path], &options).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(!Path::new(&archive_path).exists());
+        assert!(!Path::new(&format!("{}.tmp", archive_path)).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_huffman_coder_round_trip() {
+        assert_method_round_trips("huffman_round_trip", METHOD_HUFFMAN);
+    }
+
+    #[test]
+    fn test_stored_coder_round_trip() {
+        assert_method_round_trips("stored_round_trip", METHOD_STORED);
+    }
+
+    #[test]
+    fn test_stored_block_copies_in_bulk() {
+        // larger than the reader and writer buffers so the copy crosses several refills
+        let data: Vec<u8> = (0..20000u32).map(|i| (i * 31 % 256) as u8).collect();
+        let (block, compressed) = compress_bytes("stored", &data, METHOD_STORED).unwrap();
+        let decompressed = decompress_bytes(&block, &compressed).unwrap();
+        assert_eq!(decompressed.len() as u64, block.og_byte_size);
+        assert_eq!(decompressed, data);
+
+        // stored data cut short of its original size is an error rather than a short file
+        assert!(decompress_bytes(&block, &compressed[..(compressed.len() - 1)]).is_err());
+    }
+
+    #[test]
+    fn test_tiny_file_is_stored() {
+        let dir = scratch_dir("tiny_file");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("tiny.txt"), "hi").unwrap();
+        fs::write(input_dir.join("large.txt"), "a".repeat(64) + &"b".repeat(32)).unwrap();
+
+        let blocks = assert_archive_round_trip(&input_dir, &ArchiveOptions::default());
+        let method_of = |name: &str| blocks.iter().find(|block| block.filename_rel.ends_with(name)).unwrap().method;
+        assert_eq!(method_of("tiny.txt"), METHOD_STORED);
+        assert_eq!(method_of("large.txt"), METHOD_HUFFMAN);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sampled_high_entropy_file_is_stored() {
+        let dir = scratch_dir("sampled_method");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        let mut rng = StdRng::seed_from_u64(1445);
+        let noise: Vec<u8> = (0..(64 * 1024)).map(|_| rng.gen()).collect();
+        fs::write(input_dir.join("noise.bin"), &noise).unwrap();
+        fs::write(input_dir.join("text.txt"), "the quick brown fox jumps over the lazy dog ".repeat(1000)).unwrap();
+
+        // the stored file is written whole even though only its sample was read to choose the method
+        let options = ArchiveOptions { sample_size: Some(4096), ..ArchiveOptions::default() };
+        let blocks = assert_archive_round_trip(&input_dir, &options);
+        let method_of = |name: &str| blocks.iter().find(|block| block.filename_rel.ends_with(name)).unwrap().method;
+        assert_eq!(method_of("noise.bin"), METHOD_STORED);
+        assert_eq!(method_of("text.txt"), METHOD_HUFFMAN);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_depth_limits_recursion() {
+        let dir = scratch_dir("max_depth");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(input_dir.join("one/two")).unwrap();
+        fs::write(input_dir.join("top.txt"), "top").unwrap();
+        fs::write(input_dir.join("one/middle.txt"), "middle").unwrap();
+        fs::write(input_dir.join("one/two/bottom.txt"), "bottom").unwrap();
+
+        let entries = vec![input_dir.to_str().unwrap().to_owned()];
+        let names_at_depth = |max_depth: usize| -> Vec<String> {
+            let options = ArchiveOptions { max_depth: Some(max_depth), store_below: 0, ..ArchiveOptions::default() };
+            archive_dir(&entries, &options).unwrap().into_iter().map(|block| block.filename_rel).collect()
+        };
+        assert_eq!(names_at_depth(0), ["input/top.txt"]);
+        assert_eq!(names_at_depth(1), ["input/one/middle.txt", "input/top.txt"]);
+        assert_eq!(names_at_depth(2).len(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fifo_is_skipped() {
+        let dir = scratch_dir("fifo");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("file.txt"), "a regular file").unwrap();
+        let fifo_path = input_dir.join("pipe");
+        let status = std::process::Command::new("mkfifo").arg(&fifo_path).status().unwrap();
+        assert!(status.success());
+
+        // nothing ever writes to the fifo, so reading it would block the archive forever
+        let entries = vec![input_dir.to_str().unwrap().to_owned()];
+        let blocks = archive_dir(&entries, &ArchiveOptions::default()).unwrap();
+        let names: Vec<&str> = blocks.iter().map(|block| block.filename_rel.as_str()).collect();
+        assert_eq!(names, ["input/file.txt"]);
+
+        let options = ArchiveOptions { strict: true, ..ArchiveOptions::default() };
+        let err = archive_dir(&entries, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sidecar_index_matches_blocks() {
+        let dir = scratch_dir("sidecar_index");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(input_dir.join("logs")).unwrap();
+        fs::write(input_dir.join("logs/monday.log"), "first log line\nsecond log line\n").unwrap();
+        fs::write(input_dir.join("logs/tuesday.log"), "another day of logs").unwrap();
+        fs::write(input_dir.join("readme.txt"), "").unwrap();
+        // names can hold the tabs and newlines that separate the listing, but only on some platforms
+        #[cfg(unix)]
+        fs::write(input_dir.join("tab\tand\nnewline.log"), "a name that would split its line").unwrap();
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        let options = ArchiveOptions { sidecar_index: true, ..ArchiveOptions::default() };
+        archive_dir(std::slice::from_ref(&input_path), &options).unwrap();
+
+        let blocks = get_file_blocks(&mut FileReader::new(&archive_path).unwrap()).unwrap();
+        let sidecar = fs::read_to_string(format!("{}.idx", archive_path)).unwrap();
+        let lines: Vec<Vec<&str>> = sidecar.lines().map(|line| line.split('\t').collect()).collect();
+        assert_eq!(lines.len(), blocks.len());
+        let archive_bytes = fs::read(&archive_path).unwrap();
+        for (line, block) in lines.iter().zip(&blocks) {
+            let offset: u64 = line[1].parse().unwrap();
+            assert_eq!(line[0], escape_index_name(&block.filename_rel));
+            assert_eq!(offset, sizeof(SIG) as u64 + block.data_offset.unwrap_or(block.file_byte_offset));
+            assert_eq!(line[2].parse::<u64>().unwrap(), block.og_byte_size);
+            assert!(offset <= archive_bytes.len() as u64);
+        }
+        #[cfg(unix)]
+        assert!(sidecar.contains("input/tab\\tand\\nnewline.log\t"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_exclude_larger_than() {
+        let dir = scratch_dir("exclude_larger");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("small.txt"), "a".repeat(100)).unwrap();
+        fs::write(input_dir.join("limit.txt"), "b".repeat(1000)).unwrap();
+        fs::write(input_dir.join("large.txt"), "c".repeat(1001)).unwrap();
+
+        let entries = vec![input_dir.to_str().unwrap().to_owned()];
+        let options = ArchiveOptions { exclude_larger_than: Some(1000), ..ArchiveOptions::default() };
+        let blocks = archive_dir(&entries, &options).unwrap();
+        let names: Vec<&str> = blocks.iter().map(|block| block.filename_rel.as_str()).collect();
+        assert_eq!(names, ["input/limit.txt", "input/small.txt"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_delta_against_base_archive() {
+        let dir = scratch_dir("delta_base");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("a.txt"), "unchanged first file").unwrap();
+        fs::write(input_dir.join("b.txt"), "this file gets edited").unwrap();
+        fs::write(input_dir.join("c.txt"), "unchanged third file").unwrap();
+        let entries = vec![input_dir.to_str().unwrap().to_owned()];
+        archive_dir(&entries, &ArchiveOptions::default()).unwrap();
+        let base_path = dir.join("base.zipr");
+        fs::rename(dir.join("input.zipr"), &base_path).unwrap();
+
+        // the same size and modification time, so only the contents tell the change apart
+        let edited = input_dir.join("b.txt");
+        let modified = fs::metadata(&edited).unwrap().modified().unwrap();
+        fs::write(&edited, "this file got edited!").unwrap();
+        fs::File::options().write(true).open(&edited).unwrap().set_modified(modified).unwrap();
+
+        let options = ArchiveOptions { base: Some(base_path.to_str().unwrap().to_owned()), ..ArchiveOptions::default() };
+        let blocks = archive_dir(&entries, &options).unwrap();
+        let names: Vec<&str> = blocks.iter().map(|block| block.filename_rel.as_str()).collect();
+        assert_eq!(names, ["input/b.txt"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_shared_dictionary_shrinks_small_files() {
+        let dir = scratch_dir("shared_dictionary");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        let document = |i: u32| format!("{{\"id\": {}, \"name\": \"user{}\", \"active\": {}, \"tags\": [\"a\", \"b\"]}}\n", i, i * 7, i.is_multiple_of(2));
+        for i in 0..60 {
+            fs::write(input_dir.join(format!("doc{:02}.json", i)), document(i)).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+        let plain_size = fs::metadata(&archive_path).unwrap().len();
+
+        // the sample is a few documents like the ones being archived, but not the same ones
+        let sample: String = (1000..1010).map(document).collect();
+        let options = ArchiveOptions {
+            dictionary: Some(Arc::new(Dictionary::from_bytes(sample.as_bytes()))),
+            verify_headers: true,
+            ..ArchiveOptions::default()
+        };
+        let blocks = assert_archive_round_trip(&input_dir, &options);
+        let dictionary_size = fs::metadata(&archive_path).unwrap().len();
+        assert!(blocks.iter().all(|block| block.method == METHOD_DICTIONARY && block.tree_bit_size == 0));
+        assert!(dictionary_size < plain_size, "{} bytes with a dictionary, {} bytes without", dictionary_size, plain_size);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_input_is_stored() {
+        let dir = scratch_dir("double_compression");
+        let input_path = dir.join("file.txt");
+        fs::write(&input_path, "some text that compresses well, well, well, well").unwrap();
+        let input_path = input_path.to_str().unwrap().to_owned();
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        // archiving the archive stores it as is instead of huffman coding it again
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(std::slice::from_ref(&archive_path), &ArchiveOptions::default()).unwrap();
+        let blocks = get_file_blocks(&mut FileReader::new(&format!("{}.zipr", archive_path)).unwrap()).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].method, METHOD_STORED);
+        assert_eq!(blocks[0].og_byte_size, fs::metadata(&archive_path).unwrap().len());
+
+        assert_eq!(compressed_format(&SIG.to_le_bytes()), Some("a zipr archive"));
+        assert_eq!(compressed_format(&[0x1F, 0x8B, 0x08, 0x00]), Some("gzip"));
+        assert_eq!(compressed_format(b"PK\x03\x04rest"), Some("zip"));
+        assert_eq!(compressed_format(b"plain text"), None);
+        assert_eq!(compressed_format(&[]), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_is_zipper_archive() {
+        let dir = scratch_dir("is_archive");
+        let input_path = dir.join("file.txt");
+        fs::write(&input_path, "not an archive").unwrap();
+        let input_path = input_path.to_str().unwrap().to_owned();
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        assert!(is_zipper_archive(&format!("{}.zipr", input_path)));
+        assert!(!is_zipper_archive(&input_path));
+        assert!(!is_zipper_archive(dir.join("missing.zipr").to_str().unwrap()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_long_filename_is_rejected_up_front() {
+        let dir = scratch_dir("long_filename");
+        let long_name = "x".repeat(4996) + ".txt";
+        let (block, compressed) = compress_bytes(&long_name, b"a file with a very long name", METHOD_HUFFMAN).unwrap();
+        let archive_path = dir.join("long.zipr").to_str().unwrap().to_owned();
+        let mut writer = FileWriter::new(&archive_path).unwrap();
+        writer.write_u64(SIG).unwrap();
+        write_block_headers(&mut writer, &[block], 0, None, &ArchiveMetadata::current()).unwrap();
+        writer.write_bytes(&compressed).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let err = unarchive_zip(&archive_path, &ExtractOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidFilename);
+        assert!(err.to_string().starts_with(&format!("Cannot extract {}...", &long_name[..60])), "Unexpected error: {}", err);
+
+        // shortened names keep the extension and end in a hash of the full name
+        let options = ExtractOptions { shorten_names: true, ..ExtractOptions::default() };
+        unarchive_zip(&archive_path, &options).unwrap();
+        let short_name = shorten_name(&long_name);
+        assert_eq!(short_name.len(), 255);
+        assert!(short_name.ends_with(&format!("~{:08x}.txt", crc32(long_name.as_bytes()))));
+        let extracted = fs::read(dir.join("long").join(&short_name)).unwrap();
+        assert_eq!(extracted, b"a file with a very long name");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_reserved_name_is_rejected_or_renamed() {
+        let dir = scratch_dir("reserved_name");
+        let (con_block, con_data) = compress_bytes("CON", b"not the console", METHOD_HUFFMAN).unwrap();
+        let (nul_block, nul_data) = compress_bytes("logs/nul.txt", b"not the null device", METHOD_HUFFMAN).unwrap();
+        let archive_path = dir.join("reserved.zipr").to_str().unwrap().to_owned();
+        let mut writer = FileWriter::new(&archive_path).unwrap();
+        writer.write_u64(SIG).unwrap();
+        write_block_headers(&mut writer, &[con_block, nul_block], 0, None, &ArchiveMetadata::current()).unwrap();
+        writer.write_bytes(&con_data).unwrap();
+        writer.write_bytes(&nul_data).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let err = unarchive_zip(&archive_path, &ExtractOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidFilename);
+        assert!(err.to_string().contains("CON is reserved"), "Unexpected error: {}", err);
+
+        let options = ExtractOptions { rename_reserved: true, ..ExtractOptions::default() };
+        unarchive_zip(&archive_path, &options).unwrap();
+        assert_eq!(fs::read(dir.join("reserved").join("CON_")).unwrap(), b"not the console");
+        assert_eq!(fs::read(dir.join("reserved").join("logs").join("nul_.txt")).unwrap(), b"not the null device");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_executable_bit_round_trip() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch_dir("executable_bit");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("run.sh"), "#!/bin/sh\necho hello\n").unwrap();
+        fs::write(input_dir.join("notes.txt"), "not a script").unwrap();
+        fs::set_permissions(input_dir.join("run.sh"), fs::Permissions::from_mode(0o744)).unwrap();
+        fs::set_permissions(input_dir.join("notes.txt"), fs::Permissions::from_mode(0o644)).unwrap();
+
+        let blocks = assert_archive_round_trip(&input_dir, &ArchiveOptions::default());
+        let executable: Vec<(&str, bool)> = blocks.iter().map(|block| (block.filename_rel.as_str(), block.executable)).collect();
+        assert_eq!(executable, [("input/notes.txt", false), ("input/run.sh", true)]);
+
+        let mode = |name: &str| fs::metadata(input_dir.join("input").join(name)).unwrap().permissions().mode();
+        assert_ne!(mode("run.sh") & 0o100, 0);
+        assert_eq!(mode("notes.txt") & 0o111, 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_forced_modes_override_stored() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch_dir("forced_modes");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(input_dir.join("nested")).unwrap();
+        fs::write(input_dir.join("run.sh"), "#!/bin/sh\necho hello\n").unwrap();
+        fs::write(input_dir.join("nested/page.html"), "<p>hello</p>").unwrap();
+        fs::set_permissions(input_dir.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(&[input_path], &ArchiveOptions::default()).unwrap();
+
+        // the stored executable bit is ignored, and the mode is set exactly rather than masked by the umask
+        let output_dir = dir.join("output");
+        let options = ExtractOptions {
+            directory: Some(output_dir.to_str().unwrap().to_owned()),
+            file_mode: Some(0o640),
+            dir_mode: Some(0o750),
+            ..ExtractOptions::default()
+        };
+        unarchive_zip(&archive_path, &options).unwrap();
+        let mode = |path: PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(output_dir.join("input/run.sh")), 0o640);
+        assert_eq!(mode(output_dir.join("input/nested/page.html")), 0o640);
+        assert_eq!(mode(output_dir.join("input")), 0o750);
+        assert_eq!(mode(output_dir.join("input/nested")), 0o750);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hardlinks_stored_once() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = scratch_dir("hardlinks");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        let shared = "data shared by two hardlinks to the same file ".repeat(50);
+        fs::write(input_dir.join("a.txt"), &shared).unwrap();
+        fs::hard_link(input_dir.join("a.txt"), input_dir.join("b.txt")).unwrap();
+        fs::write(input_dir.join("c.txt"), "a file with a single link").unwrap();
+
+        let archive_path = format!("{}.zipr", input_dir.to_str().unwrap());
+        let options = ArchiveOptions { verify_headers: true, ..ArchiveOptions::default() };
+        assert_archive_round_trip(&input_dir, &options);
+
+        // the link shares the data of the first file, and the data of each file follows on from the one before
+        let blocks = get_file_blocks(&mut FileReader::new(&archive_path).unwrap()).unwrap();
+        let links: Vec<bool> = blocks.iter().map(|block| block.hardlink).collect();
+        assert_eq!(links, [false, true, false]);
+        assert_eq!(blocks[1].file_byte_offset, blocks[0].file_byte_offset);
+        assert_eq!(blocks[2].file_byte_offset, blocks[0].file_byte_offset + blocks[0].get_stored_size());
+
+        let output_dir = input_dir.join("input");
+        let metadata = |name: &str| fs::metadata(output_dir.join(name)).unwrap();
+        assert_eq!(metadata("a.txt").ino(), metadata("b.txt").ino());
+        assert_eq!(metadata("a.txt").nlink(), 2);
+
+        // a link whose file isn't extracted gets the data itself
+        fs::remove_dir_all(&input_dir).unwrap();
+        let options = ExtractOptions { include: vec![String::from("*b.txt")], ..ExtractOptions::default() };
+        unarchive_zip(&archive_path, &options).unwrap();
+        assert_eq!(fs::read_to_string(output_dir.join("b.txt")).unwrap(), shared);
+        assert!(!output_dir.join("a.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rename_on_conflict() {
+        let dir = scratch_dir("rename_on_conflict");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("report.txt"), "archived report").unwrap();
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(&[input_path], &ArchiveOptions::default()).unwrap();
+
+        let output_dir = input_dir.join("input");
+        fs::create_dir_all(&output_dir).unwrap();
+        fs::write(output_dir.join("report.txt"), "existing report").unwrap();
+        let options = ExtractOptions { rename_template: Some(String::from(DEFAULT_RENAME_TEMPLATE)), ..ExtractOptions::default() };
+        unarchive_zip(&archive_path, &options).unwrap();
+        unarchive_zip(&archive_path, &options).unwrap();
+
+        assert_eq!(fs::read_to_string(output_dir.join("report.txt")).unwrap(), "existing report");
+        assert_eq!(fs::read_to_string(output_dir.join("report.1.txt")).unwrap(), "archived report");
+        assert_eq!(fs::read_to_string(output_dir.join("report.2.txt")).unwrap(), "archived report");
+
+        // a template without a counter could only ever produce one name
+        let err = conflict_free_path(&output_dir.join("report.txt"), "{name}-copy{ext}").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_archive_version() {
+        let dir = scratch_dir("archive_version");
+        let input_path = dir.join("file.txt");
+        fs::write(&input_path, "versioned contents").unwrap();
+        let input_path = input_path.to_str().unwrap().to_owned();
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        assert_eq!(archive_version(&format!("{}.zipr", input_path)).unwrap(), FORMAT_VERSION);
+        assert_eq!(archive_version(&input_path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_version_3_archive() {
+        let dir = scratch_dir("upgrade");
+        let files: [(&str, &[u8], u8); 3] = [
+            ("old/readme.txt", b"an archive written before blocks had attributes", METHOD_HUFFMAN),
+            ("old/raw.bin", &[7, 0, 255, 42], METHOD_STORED),
+            ("old/empty.txt", b"", METHOD_HUFFMAN),
+        ];
+        let compressed: Vec<(FileBlock, Vec<u8>)> = files.iter()
+            .map(|(name, data, method)| compress_bytes(name, data, *method).unwrap())
+            .collect();
+
+        // a version 3 archive has no checksum or attribute byte in its headers and no metadata
+        let mut archive = vec![];
+        let mut writer = FileWriter::from_writer(&mut archive);
+        writer.write_u64(SIG).unwrap();
+        writer.start_crc();
+        writer.write_byte(FLAGS_VERSION).unwrap();
+        writer.write_byte(0).unwrap();
+        writer.write_u32(files.len() as u32).unwrap();
+        let mut offset = ARCHIVE_HEADER_SIZE + compressed.iter().map(|(block, _)| block.get_header_size() - 1 - CRC32_SIZE as u64).sum::<u64>();
+        for (block, bytes) in &compressed {
+            writer.write_u32(block.filename_rel.len() as u32).unwrap();
+            writer.write_bytes(block.filename_rel.as_bytes()).unwrap();
+            for field in [block.tree_bit_size, block.data_bit_size, offset, block.og_byte_size] {
+                writer.write_u64(field).unwrap();
+            }
+            writer.write_byte(block.method).unwrap();
+            offset += bytes.len() as u64;
+        }
+        writer.write_u32(writer.current_crc()).unwrap();
+        for (_, bytes) in &compressed {
+            writer.write_bytes(bytes).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        let old_path = dir.join("old.zipr");
+        fs::write(&old_path, &archive).unwrap();
+
+        let new_path = dir.join("new.zipr");
+        let (old_path, new_path) = (old_path.to_str().unwrap(), new_path.to_str().unwrap());
+        upgrade_archive(old_path, new_path).unwrap();
+        assert_eq!(archive_version(new_path).unwrap(), FORMAT_VERSION);
+        validate_offsets(new_path).unwrap();
+        // the blocks are copied rather than recompressed, so each keeps its sizes
+        let old_blocks = get_file_blocks(&mut FileReader::new(old_path).unwrap()).unwrap();
+        let new_blocks = get_file_blocks(&mut FileReader::new(new_path).unwrap()).unwrap();
+        let sizes = |blocks: &[FileBlock]| blocks.iter().map(|block| (block.tree_bit_size, block.data_bit_size)).collect::<Vec<_>>();
+        assert_eq!(sizes(&new_blocks), sizes(&old_blocks));
+
+        unarchive_zip(new_path, &ExtractOptions::default()).unwrap();
+        for (name, data, _) in &files {
+            assert_eq!(fs::read(dir.join("new").join(name)).unwrap(), *data);
+        }
+        assert_eq!(upgrade_archive(new_path, new_path).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_keeps_metadata() {
+        let dir = scratch_dir("upgrade_metadata");
+        let input_path = dir.join("file.txt");
+        fs::write(&input_path, "contents written long ago").unwrap();
+        let input_path = input_path.to_str().unwrap().to_owned();
+        let options = ArchiveOptions { created_at: Some(1_000_000), ..ArchiveOptions::default() };
+        archive_dir(std::slice::from_ref(&input_path), &options).unwrap();
+        let old_path = format!("{}.zipr", input_path);
+        let new_path = dir.join("new.zipr");
+        let new_path = new_path.to_str().unwrap();
+
+        upgrade_archive(&old_path, new_path).unwrap();
+        assert_eq!(read_archive_info(new_path).unwrap().metadata, read_archive_info(&old_path).unwrap().metadata);
+        assert!(!Path::new(&format!("{}.tmp", new_path)).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_version_2_and_older_archives() {
+        let dir = scratch_dir("upgrade_separated");
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/upgrade");
+        // each fixture was written from the files under old by the last tree to write its version, before any of the current fields existed
+        for (fixture, version) in [("unversioned.zipr", 0), ("version_1.zipr", 1), ("version_2.zipr", 2)] {
+            let old_path = fixtures.join(fixture);
+            let old_path = old_path.to_str().unwrap();
+            assert_eq!(archive_version(old_path).unwrap(), version);
+
+            let new_path = dir.join("old.zipr");
+            let new_path = new_path.to_str().unwrap();
+            upgrade_archive(old_path, new_path).unwrap();
+            assert_eq!(archive_version(new_path).unwrap(), FORMAT_VERSION);
+            validate_offsets(new_path).unwrap();
+
+            unarchive_zip(new_path, &ExtractOptions::default()).unwrap();
+            for name in ["readme.txt", "nested/data.bin", "same.txt"] {
+                let extracted = fs::read(dir.join("old/old").join(name)).unwrap();
+                assert_eq!(extracted, fs::read(fixtures.join("old").join(name)).unwrap(), "{} differs after upgrading {}", name, fixture);
+            }
+            fs::remove_dir_all(dir.join("old")).unwrap();
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_group_separator_warns_or_errors() {
+        let dir = scratch_dir("missing_grp_sep");
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/upgrade");
+        // the group separator is followed by the header checksum in version 1 archives, and ends the headers in unversioned ones
+        for (fixture, trailer_len) in [("unversioned.zipr", 1), ("version_1.zipr", 5)] {
+            let bytes = fs::read(fixtures.join(fixture)).unwrap();
+            let blocks = get_file_blocks(&mut FileReader::from_bytes(&bytes).unwrap()).unwrap();
+            let grp_sep_pos = sizeof(SIG) + blocks[0].file_byte_offset as usize - trailer_len;
+            assert_eq!(bytes[grp_sep_pos], GRP_SEP);
+            let truncated_path = dir.join(fixture);
+            fs::write(&truncated_path, &bytes[..grp_sep_pos]).unwrap();
+            let truncated_path = truncated_path.to_str().unwrap();
+
+            let reader = &mut FileReader::new(truncated_path).unwrap();
+            let err = read_file_blocks_with(reader, false, MissingGroupSeparator::Error).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            assert!(err.to_string().contains("group separator"), "Unexpected error: {}", err);
+            let options = ExtractOptions { missing_group_separator: MissingGroupSeparator::Error, ..ExtractOptions::default() };
+            assert_eq!(unarchive_zip(truncated_path, &options).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+            // by default the headers that were read are still used
+            let reader = &mut FileReader::new(truncated_path).unwrap();
+            let read_names: Vec<String> = read_file_blocks_with(reader, false, MissingGroupSeparator::Warn).unwrap()
+                .into_iter()
+                .map(|block| block.filename_rel)
+                .collect();
+            let names: Vec<String> = blocks.into_iter().map(|block| block.filename_rel).collect();
+            assert_eq!(read_names, names);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_archive_records_creation() {
+        let dir = scratch_dir("archive_info");
+        let input_path = dir.join("file.txt");
+        fs::write(&input_path, "dated contents").unwrap();
+        let input_path = input_path.to_str().unwrap().to_owned();
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        let info = read_archive_info(&format!("{}.zipr", input_path)).unwrap();
+        assert_eq!((info.version, info.block_count), (FORMAT_VERSION, 1));
+        let metadata = info.metadata.unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert!(metadata.created_at.abs_diff(now) <= 5, "Created at {} but it is now {}", metadata.created_at, now);
+        assert_eq!(metadata.tool_version, env!("CARGO_PKG_VERSION"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_separate_archives() {
+        let dir = scratch_dir("separate");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(input_dir.join("nested")).unwrap();
+        let files = [("first.txt", "first file"), ("second.txt", "second file"), ("nested/third.txt", "third file")];
+        for (name, data) in &files {
+            fs::write(input_dir.join(name), data).unwrap();
+        }
+
+        let entries = vec![input_dir.to_str().unwrap().to_owned()];
+        let options = ArchiveOptions { separate: true, ..ArchiveOptions::default() };
+        archive_dir(&entries, &options).unwrap();
+        assert!(!Path::new(&format!("{}.zipr", entries[0])).exists());
+
+        for (name, data) in &files {
+            let file_path = input_dir.join(name);
+            let archive_path = format!("{}.zipr", file_path.to_str().unwrap());
+            let blocks = get_file_blocks(&mut FileReader::new(&archive_path).unwrap()).unwrap();
+            assert_eq!(blocks.len(), 1);
+
+            // the file's path is where its archive extracts to so move the file out of the way
+            fs::remove_file(&file_path).unwrap();
+            unarchive_zip(&archive_path, &ExtractOptions::default()).unwrap();
+            let extracted = file_path.join(&blocks[0].filename_rel);
+            assert_eq!(fs::read_to_string(extracted).unwrap(), *data);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_archive_does_not_contain_itself() {
+        let dir = scratch_dir("archive_inside_input");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("file.txt"), "archived file").unwrap();
+
+        // a trailing separator puts the archive inside the directory, like archiving the current directory does
+        let entry = format!("{}/", input_dir.to_str().unwrap());
+        archive_dir(std::slice::from_ref(&entry), &ArchiveOptions::default()).unwrap();
+        let archive_path = input_dir.join(".zipr");
+        assert!(archive_path.exists());
+        // archiving again finds the archive from the first run in the directory
+        let blocks = archive_dir(std::slice::from_ref(&entry), &ArchiveOptions::default()).unwrap();
+        let names: Vec<&str> = blocks.iter().map(|block| block.filename_rel.as_str()).collect();
+        assert_eq!(names, ["input/file.txt"]);
+
+        let blocks = get_file_blocks(&mut FileReader::new(archive_path.to_str().unwrap()).unwrap()).unwrap();
+        assert_eq!(blocks.len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_all_inputs_excluded() {
+        let dir = scratch_dir("all_excluded");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("file.txt"), "larger than the limit").unwrap();
+
+        let entries = vec![input_dir.to_str().unwrap().to_owned()];
+        let archive_path = format!("{}.zipr", entries[0]);
+        let options = ArchiveOptions { exclude_larger_than: Some(1), ..ArchiveOptions::default() };
+        let err = archive_dir(&entries, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("No files matched"), "Unexpected error: {}", err);
+        assert!(!Path::new(&archive_path).exists());
+
+        let options = ArchiveOptions { allow_empty: true, ..options };
+        assert!(archive_dir(&entries, &options).unwrap().is_empty());
+        assert!(get_file_blocks(&mut FileReader::new(&archive_path).unwrap()).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unreadable_metadata_is_an_error_or_skipped() {
+        let dir = scratch_dir("unreadable_metadata");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("kept.txt"), "still here").unwrap();
+        // a link to a removed file is listed but has no metadata, like a file deleted mid-walk
+        std::os::unix::fs::symlink(dir.join("removed.txt"), input_dir.join("gone.txt")).unwrap();
+
+        let err = dir_entry_size(&input_dir, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("gone.txt"), "Unexpected error: {}", err);
+        assert_eq!(dir_entry_size(&input_dir, true).unwrap(), "still here".len() as u64);
+
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let err = archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("gone.txt"), "Unexpected error: {}", err);
+
+        let options = ArchiveOptions { skip_errors: true, ..ArchiveOptions::default() };
+        let blocks = archive_dir(&[input_path], &options).unwrap();
+        let names: Vec<&str> = blocks.iter().map(|block| block.filename_rel.as_str()).collect();
+        assert_eq!(names, ["input/kept.txt"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_name_with_separator_byte_round_trips() {
+        let dir = scratch_dir("separator_name");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        // the bytes that used to separate records and the header group
+        let names = ["record\u{1e}sep.txt", "group\u{1d}sep.txt"];
+        for name in &names {
+            fs::write(input_dir.join(name), format!("contents of {}", name)).unwrap();
+        }
+
+        assert_archive_round_trip(&input_dir, &ArchiveOptions::default());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_names_shrink_headers() {
+        let dir = scratch_dir("compressed_names");
+        let input_dir = dir.join("input");
+        let nested_dir = input_dir.join("project/src/components/widgets/buttons");
+        fs::create_dir_all(&nested_dir).unwrap();
+        for i in 0..200 {
+            fs::write(nested_dir.join(format!("button_variant_{:03}.txt", i)), format!("button {}", i)).unwrap();
+        }
+        let entries = vec![input_dir.to_str().unwrap().to_owned()];
+        let archive_path = format!("{}.zipr", entries[0]);
+
+        // the header size is where the data of the first block starts
+        let header_size = |compress_names: bool| {
+            let options = ArchiveOptions { compress_names, ..ArchiveOptions::default() };
+            archive_dir(&entries, &options).unwrap();
+            get_file_blocks(&mut FileReader::new(&archive_path).unwrap()).unwrap()[0].file_byte_offset
+        };
+        let plain_size = header_size(false);
+        let compressed_size = header_size(true);
+        assert!(compressed_size * 2 < plain_size, "Expected {} to be much less than {}", compressed_size, plain_size);
+
+        assert_archive_round_trip(&input_dir, &ArchiveOptions { compress_names: true, ..ArchiveOptions::default() });
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fsync_archive_round_trips() {
+        let dir = scratch_dir("fsync");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        // spans more than one writer buffer so the buffer is synced part way through
+        let data: Vec<u8> = (0..10000u32).map(|i| (i * 7 % 97) as u8).collect();
+        fs::write(input_dir.join("large.bin"), &data).unwrap();
+        fs::write(input_dir.join("small.txt"), "synced file").unwrap();
+
+        let options = ArchiveOptions { fsync: true, ..ArchiveOptions::default() };
+        assert_archive_round_trip(&input_dir, &options);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_required_space() {
+        let block = |og_byte_size: u64| FileBlock {
+            filename_rel: String::from("file"),
+            file_byte_offset: 0,
+            og_byte_size,
+            crc32: None,
+            tree_bit_size: 0,
+            data_bit_size: 0,
+            method: METHOD_STORED,
+            data_offset: None,
+            model_offset: None,
+            executable: false,
+            hardlink: false,
+        };
+        let blocks = [block(100), block(0), block(2500), block(400)];
+        assert_eq!(required_space(&blocks), 3000);
+        assert_eq!(required_space(&[block(u64::MAX), block(1)]), u64::MAX);
+
+        assert!(check_space(3000, 3000).is_ok());
+        let err = check_space(3000, 2999).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(err.to_string().contains("need 3000 bytes, have 2999 bytes"), "Unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_machine_progress_lines() {
+        let dir = scratch_dir("machine_progress");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("first.txt"), "a".repeat(100)).unwrap();
+        fs::write(input_dir.join("second.txt"), "b".repeat(50)).unwrap();
+
+        let entries = vec![input_dir.to_str().unwrap().to_owned()];
+        let options = ArchiveOptions { progress: Some(ProgressFormat::Machine), ..ArchiveOptions::default() };
+        let mut out = vec![];
+        archive_dir_with_progress(&entries, &options, &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines, ["PROGRESS 1/2 100/150", "PROGRESS 2/2 150/150"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_empty_and_short_files_are_not_archives() {
+        let dir = scratch_dir("short_archive");
+        let read_err = |name: &str, bytes: &[u8]| {
+            let path = dir.join(name);
+            fs::write(&path, bytes).unwrap();
+            get_file_blocks(&mut FileReader::new(path.to_str().unwrap()).unwrap()).unwrap_err()
+        };
+
+        let err = read_err("empty.zipr", &[]);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("empty file"), "Unexpected error: {}", err);
+
+        let err = read_err("short.zipr", &SIG.to_le_bytes()[..3]);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("too short"), "Unexpected error: {}", err);
+
+        let err = read_err("other.zipr", b"not an archive");
+        assert!(err.to_string().contains("signature does not match"), "Unexpected error: {}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_collect_files() {
+        let dir = scratch_dir("collect_files");
+        let files = ["a.txt", "nested/b.txt", "nested/deeper/c.txt", "nested/deeper/d.txt"];
+        fs::create_dir_all(dir.join("nested/deeper")).unwrap();
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        for name in &files {
+            fs::write(dir.join(name), name).unwrap();
+        }
+
+        let collected = collect_files(&dir).unwrap();
+        let expected: Vec<PathBuf> = files.iter().map(|name| dir.join(name)).collect();
+        assert_eq!(collected, expected);
+        assert_eq!(collect_files(&dir.join("a.txt")).unwrap(), [dir.join("a.txt")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_loop_is_not_followed() {
+        let dir = scratch_dir("symlink_loop");
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("nested/file.txt"), "inside the loop").unwrap();
+        // the link leads back to the directory it's in, so following it would never end
+        std::os::unix::fs::symlink(&dir, dir.join("nested/loop")).unwrap();
+
+        assert_eq!(collect_files(&dir).unwrap(), [dir.join("nested/file.txt")]);
+        // a directory reached through a link that isn't a loop is still walked
+        std::os::unix::fs::symlink(dir.join("nested"), dir.join("alias")).unwrap();
+        assert_eq!(collect_files(&dir).unwrap(), [dir.join("alias/file.txt"), dir.join("nested/file.txt")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_single_file_is_named_by_base_name() {
+        for entry in ["file.txt", "./file.txt", "/abs/file.txt", "../up/file.txt"] {
+            let path = Path::new(entry);
+            assert_eq!(relative_name(entry_base_path(path), path), "file.txt", "Wrong name for {}", entry);
+        }
+        // a file found under a directory entry keeps the directory's name
+        let entry = Path::new("./dir");
+        let file = Path::new("./dir/sub/file.txt");
+        assert_eq!(relative_name(entry_base_path(entry), file), Path::new("dir/sub/file.txt").to_str().unwrap());
+    }
+
+    #[test]
+    fn test_portable_path_conversion() {
+        assert_eq!(to_portable_path("a\\b\\c", '\\'), "a/b/c");
+        assert_eq!(from_portable_path("a/b/c", '\\'), "a\\b\\c");
+        assert_eq!(from_portable_path(&to_portable_path("a/b/c", '/'), '/'), "a/b/c");
+    }
+
+    // write a directory of text-like files from a seeded generator, so a test needs no fixture checked in
+    fn generate_fixture(dir: &Path, file_count: usize, file_size: usize, seed: u64) {
+        let words = ["the", "quick", "brown", "fox", "jumps", "over", "lazy", "dog", "archive", "huffman", "tree", "\n"];
+        let mut rng = StdRng::seed_from_u64(seed);
+        fs::create_dir_all(dir).unwrap();
+        for i in 0..file_count {
+            let mut text = String::with_capacity(file_size);
+            while text.len() < file_size {
+                text.push_str(words[rng.gen_range(0..words.len())]);
+                text.push(' ');
+            }
+            fs::write(dir.join(format!("medium_{}.txt", i)), text).unwrap();
+        }
+    }
+
+    // archive the same fixture single threaded and then multithreaded, returning the time taken and the archive written by each
+    fn archive_single_and_multithreaded(test_name: &str) -> [(Duration, Vec<u8>); 2] {
+        let dir = scratch_dir(test_name);
+        let input_dir = dir.join("input");
+        generate_fixture(&input_dir, 8, 256 * 1024, 1464);
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+
+        let time_archive = |options: &ArchiveOptions| {
+            let start = std::time::Instant::now();
+            archive_dir(std::slice::from_ref(&input_path), options).unwrap();
+            (start.elapsed(), fs::read(&archive_path).unwrap())
+        };
+        let options = ArchiveOptions { created_at: Some(0), ..ArchiveOptions::default() };
+        let runs = [time_archive(&options), time_archive(&ArchiveOptions { multithreaded: true, ..options.clone() })];
+        fs::remove_dir_all(&dir).unwrap();
+        runs
+    }
+
+    #[test]
+    fn test_multithreaded_matches_single_threaded() {
+        let [(_, sequential), (_, parallel)] = archive_single_and_multithreaded("mt_matches");
+        assert!(sequential == parallel, "Expected the multithreaded archive to match the single threaded archive");
+    }
+
+    // timings are too noisy on a loaded machine to run with every test, run it with cargo test -- --ignored
+    #[test]
+    #[ignore]
+    fn test_multithreaded_throughput() {
+        let [(sequential_time, _), (parallel_time, _)] = archive_single_and_multithreaded("mt_throughput");
+        // this only catches the threads being serialized behind something like a global lock
+        // on top of paying for the threads, which would make the multithreaded run far slower than the single threaded run
+        let limit = 3 * sequential_time + Duration::from_millis(500);
+        assert!(parallel_time <= limit,
+            "Multithreaded archiving took {:.2?}, single threaded took {:.2?}", parallel_time, sequential_time);
+    }
 
     #[test]
     fn test_compress_directory() {
@@ -497,15 +5415,15 @@ mod tests {
                 continue
             }
             let file_data = fs::read_to_string(&path)
-                .expect(&format!("Cannot read file at path {}", path.to_str().unwrap()));
+                .unwrap_or_else(|_| panic!("Cannot read file at path {}", path.to_str().unwrap()));
 
             let relative_path = path.strip_prefix(&input_path).unwrap().to_owned();
             dir_data.insert(relative_path.clone(), file_data);
         }
         println!("Directory files {:?}", dir_data.keys());
 
-        archive_dir(&[input_path], false).unwrap();
-        unarchive_zip("./test/files.zipr", false).unwrap();
+        archive_dir(&[input_path], &ArchiveOptions::default()).unwrap();
+        unarchive_zip("./test/files.zipr", &ExtractOptions::default()).unwrap();
 
         let output_path = "./test/files/files";
         for entry in fs::read_dir(output_path).unwrap() {
@@ -514,11 +5432,11 @@ mod tests {
                 continue
             }
             let file_data = fs::read_to_string(&path)
-                .expect(&format!("Cannot read at file path {}", path.to_str().unwrap()));
+                .unwrap_or_else(|_| panic!("Cannot read at file path {}", path.to_str().unwrap()));
 
-            let relative_path = path.strip_prefix(&output_path).unwrap();
+            let relative_path = path.strip_prefix(output_path).unwrap();
             let other_file_data = dir_data.get(relative_path)
-                .expect(&format!("Cannot find path in map {}", path.to_str().unwrap()));
+                .unwrap_or_else(|| panic!("Cannot find path in map {}", path.to_str().unwrap()));
 
             if file_data != *other_file_data {
                 panic!("File data for file path is different: {}", path.to_str().unwrap())
@@ -0,0 +1,74 @@
+// Joseph Prichard
+// 1/5/2023
+// CRC-32 checksums (IEEE polynomial) used to detect corruption in archives
+
+const POLYNOMIAL: u32 = 0xEDB88320;
+const CRC_TABLE: [u32; 256] = create_crc_table();
+
+// precompute the remainder of each byte so the checksum can be updated a byte at a time
+const fn create_crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// a running checksum over a stream of bytes
+#[derive(Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32 { state: 0xFFFFFFFF }
+    }
+
+    pub fn update(&mut self, byte: u8) {
+        self.state = CRC_TABLE[((self.state ^ byte as u32) & 0xFF) as usize] ^ (self.state >> 8);
+    }
+
+    pub fn update_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.update(byte);
+        }
+    }
+
+    // the checksum of all bytes seen so far
+    pub fn value(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update_bytes(bytes);
+    crc.value()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32_check_value() {
+        // the standard check value for the IEEE polynomial
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b""), 0);
+    }
+}
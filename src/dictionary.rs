@@ -0,0 +1,41 @@
+// Joseph Prichard
+// 1/5/2023
+// Shared code tree built from sample data, stored once in an archive so small similar files don't each store a tree
+
+use std::{fs, io};
+use crate::compress::{create_code_table, create_code_tree, serialize_tree, TABLE_SIZE};
+use crate::structures::SymbolCode;
+
+pub struct Dictionary {
+    symbol_table: Box<[SymbolCode; TABLE_SIZE]>,
+    // the tree as it's stored in the archive headers
+    tree_bytes: Vec<u8>,
+}
+
+impl Dictionary {
+    // build the tree from the byte frequencies of sample data, such as a few files typical of the ones being archived
+    pub fn from_bytes(sample: &[u8]) -> Dictionary {
+        // every byte is counted once more than it appears, so a byte missing from the sample still has a code
+        let mut freq_table = [1u64; TABLE_SIZE];
+        for &byte in sample {
+            freq_table[byte as usize] += 1;
+        }
+        let tree = create_code_tree(&freq_table);
+        Dictionary {
+            symbol_table: create_code_table(&tree),
+            tree_bytes: serialize_tree(&tree.root),
+        }
+    }
+
+    pub fn from_file(filepath: &str) -> io::Result<Dictionary> {
+        Ok(Dictionary::from_bytes(&fs::read(filepath)?))
+    }
+
+    pub fn symbol_table(&self) -> &[SymbolCode; TABLE_SIZE] {
+        &self.symbol_table
+    }
+
+    pub fn tree_bytes(&self) -> &[u8] {
+        &self.tree_bytes
+    }
+}
@@ -0,0 +1,208 @@
+// Joseph Prichard
+// 1/5/2023
+// Extract the entries of an archive through a sink that chooses where each entry is written, such as an in-memory filesystem
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::sync::Mutex;
+use crate::compress::{apply_file_mode, from_portable_path, matches_block, output_path, prepare_output_path, ExtractOptions};
+use crate::structures::FileBlock;
+
+// what's known about an entry before its data is written
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntryMeta<'a> {
+    pub og_byte_size: u64,
+    pub executable: bool,
+    // the block the entry is decoded from, for the directory sink to check and set up the files it writes
+    pub(crate) block: &'a FileBlock,
+}
+
+impl EntryMeta<'_> {
+    pub(crate) fn new(block: &FileBlock) -> EntryMeta<'_> {
+        EntryMeta { og_byte_size: block.og_byte_size, executable: block.executable, block }
+    }
+}
+
+// creates the destination of each entry, the entry's data is written to it and it's dropped before finish is called
+// entries are extracted on several threads at once, so the sink is shared between them
+pub trait EntrySink: Sync {
+    fn create(&self, name: &str, meta: &EntryMeta) -> io::Result<Box<dyn Write + '_>>;
+
+    // whether the sink already holds an entry's data, so it's left as it is instead of being written again
+    fn contains(&self, _name: &str, _meta: &EntryMeta) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    // called with the result of writing an entry, a sink should remove an entry that failed since it holds damaged data
+    fn finish(&self, _name: &str, _meta: &EntryMeta, result: io::Result<()>) -> io::Result<()> {
+        result
+    }
+
+    // make an entry a link to an earlier entry with the same data, returning false to have the data written again instead
+    fn link(&self, _name: &str, _target: &str, _meta: &EntryMeta) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+// writes each entry to a file under a directory on disk, the sink every extraction to disk goes through
+// the extract options choose how existing files, reserved names, long names and modes are handled
+pub struct DirSink<'a> {
+    archive_filepath: &'a str,
+    output_dir: &'a str,
+    options: &'a ExtractOptions,
+    // the path each entry was written to, which differs from its name once it's renamed away from an existing file
+    written: Mutex<HashMap<String, PathBuf>>,
+}
+
+impl<'a> DirSink<'a> {
+    pub fn new(archive_filepath: &'a str, output_dir: &'a str, options: &'a ExtractOptions) -> DirSink<'a> {
+        DirSink { archive_filepath, output_dir, options, written: Mutex::new(HashMap::new()) }
+    }
+
+    // every path an entry was written or linked to
+    pub fn written_paths(&self) -> Vec<PathBuf> {
+        self.written.lock().expect("Expected written paths lock to not be poisoned").values().cloned().collect()
+    }
+
+    fn output_path(&self, name: &str) -> io::Result<PathBuf> {
+        output_path(&from_portable_path(name, MAIN_SEPARATOR), self.output_dir, self.options)
+    }
+
+    fn prepare_output_path(&self, name: &str, meta: &EntryMeta) -> io::Result<PathBuf> {
+        prepare_output_path(meta.block, &from_portable_path(name, MAIN_SEPARATOR), self.output_dir, self.options)
+    }
+
+    fn record(&self, name: &str, path: PathBuf) {
+        self.written.lock().expect("Expected written paths lock to not be poisoned").insert(String::from(name), path);
+    }
+
+    fn path_of(&self, name: &str) -> Option<PathBuf> {
+        self.written.lock().expect("Expected written paths lock to not be poisoned").get(name).cloned()
+    }
+}
+
+impl EntrySink for DirSink<'_> {
+    fn create(&self, name: &str, meta: &EntryMeta) -> io::Result<Box<dyn Write + '_>> {
+        let path = self.prepare_output_path(name, meta)?;
+        let file = fs::File::create(&path)?;
+        self.record(name, path);
+        Ok(Box::new(file))
+    }
+
+    // a resumed extraction keeps a file that already has the entry's contents
+    fn contains(&self, name: &str, meta: &EntryMeta) -> io::Result<bool> {
+        if !self.options.resume {
+            return Ok(false);
+        }
+        let existing_path = self.output_path(name)?;
+        if !matches_block(&existing_path, meta.block, self.archive_filepath)? {
+            return Ok(false);
+        }
+        // the extraction may have stopped between writing the file and setting its mode
+        apply_file_mode(&existing_path, meta.block, self.options)?;
+        self.record(name, existing_path);
+        Ok(true)
+    }
+
+    fn finish(&self, name: &str, meta: &EntryMeta, result: io::Result<()>) -> io::Result<()> {
+        let Some(path) = self.path_of(name) else {
+            return result;
+        };
+        if let Err(e) = result {
+            // a file that failed to decompress or its checksum holds damaged data, so it isn't left behind
+            let _ = fs::remove_file(&path);
+            self.written.lock().expect("Expected written paths lock to not be poisoned").remove(name);
+            return Err(e);
+        }
+        apply_file_mode(&path, meta.block, self.options)
+    }
+
+    // a target that wasn't written has its data extracted again instead
+    fn link(&self, name: &str, target: &str, meta: &EntryMeta) -> io::Result<bool> {
+        let Some(target_path) = self.path_of(target) else {
+            return Ok(false);
+        };
+        let link_path = self.prepare_output_path(name, meta)?;
+        // an existing file is replaced, the same as an extracted file overwrites it
+        if link_path.is_file() {
+            fs::remove_file(&link_path)?;
+        }
+        fs::hard_link(Path::new(&target_path), &link_path)?;
+        self.record(name, link_path);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::sync::Mutex;
+    use crate::compress::{archive_dir, unarchive_to_sink, ArchiveOptions, ExtractOptions};
+    use crate::entry_sink::{EntryMeta, EntrySink};
+    use crate::testing::scratch_dir;
+
+    // collects each entry into a map instead of writing it anywhere
+    #[derive(Default)]
+    struct MemorySink {
+        entries: Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    // buffers an entry and adds it to the map once it's written
+    struct MemoryEntry<'a> {
+        name: String,
+        data: Vec<u8>,
+        sink: &'a MemorySink,
+    }
+
+    impl Write for MemoryEntry<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.data.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for MemoryEntry<'_> {
+        fn drop(&mut self) {
+            let data = std::mem::take(&mut self.data);
+            self.sink.entries.lock().unwrap().insert(self.name.clone(), data);
+        }
+    }
+
+    impl EntrySink for MemorySink {
+        fn create(&self, name: &str, meta: &EntryMeta) -> io::Result<Box<dyn Write + '_>> {
+            let data = Vec::with_capacity(meta.og_byte_size as usize);
+            Ok(Box::new(MemoryEntry { name: String::from(name), data, sink: self }))
+        }
+    }
+
+    #[test]
+    fn test_unarchive_to_memory_sink() {
+        let dir = scratch_dir("entry_sink");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(input_dir.join("nested")).unwrap();
+        let files = HashMap::from([
+            (String::from("input/index.html"), b"<h1>hello</h1>".to_vec()),
+            (String::from("input/nested/data.bin"), (0..2000u32).map(|i| (i * 7 % 256) as u8).collect()),
+            (String::from("input/empty.txt"), vec![]),
+        ]);
+        for (name, data) in &files {
+            fs::write(dir.join(name), data).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        let sink = MemorySink::default();
+        unarchive_to_sink(&archive_path, &sink, &ExtractOptions::default()).unwrap();
+        assert_eq!(sink.entries.into_inner().unwrap(), files);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+// Joseph Prichard
+// 1/5/2023
+// Bound how many files the threads have open at once, so archiving a large directory in parallel can't run out of file handles
+
+use std::sync::{Condvar, Mutex};
+
+pub struct FileLimit {
+    max_open: usize,
+    open: Mutex<usize>,
+    closed: Condvar,
+}
+
+// files counted as open until the permit is dropped
+pub struct FilePermit<'a> {
+    limit: &'a FileLimit,
+    count: usize,
+}
+
+impl FileLimit {
+    // a limit below 1 would never let a file be opened, so it's raised to 1
+    pub fn new(max_open: usize) -> FileLimit {
+        FileLimit { max_open: max_open.max(1), open: Mutex::new(0), closed: Condvar::new() }
+    }
+
+    // wait until there's room to open a number of files, taking them together so a task needing several can't deadlock holding some
+    // a task needing more files than the limit takes the whole limit and opens its files one after another
+    pub fn acquire(&self, count: usize) -> FilePermit<'_> {
+        let count = count.min(self.max_open);
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        while *open + count > self.max_open {
+            open = self.closed.wait(open).unwrap_or_else(|e| e.into_inner());
+        }
+        *open += count;
+        FilePermit { limit: self, count }
+    }
+}
+
+impl Drop for FilePermit<'_> {
+    fn drop(&mut self) {
+        let mut open = self.limit.open.lock().unwrap_or_else(|e| e.into_inner());
+        *open -= self.count;
+        self.limit.closed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use crate::file_limit::FileLimit;
+
+    #[test]
+    fn test_limit_bounds_open_files() {
+        let limit = FileLimit::new(3);
+        let open = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for i in 0..16 {
+                let (limit, open, peak) = (&limit, &open, &peak);
+                scope.spawn(move || {
+                    // some tasks need two files at once, and one needs more than the limit allows
+                    let count = [1, 2, 5][i % 3];
+                    let _permit = limit.acquire(count);
+                    let now_open = open.fetch_add(count.min(3), Ordering::SeqCst) + count.min(3);
+                    peak.fetch_max(now_open, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(2));
+                    open.fetch_sub(count.min(3), Ordering::SeqCst);
+                });
+            }
+        });
+        assert!(peak.load(Ordering::SeqCst) <= 3, "{} files were open at once", peak.load(Ordering::SeqCst));
+    }
+}
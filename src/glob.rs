@@ -0,0 +1,53 @@
+// Joseph Prichard
+// 1/5/2023
+// Glob pattern matching for selecting archive entries by name
+
+// matches a name against a pattern where * matches any run of characters and ? matches a single character
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // position of the last star in the pattern and the name position it was matched against
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            // let the last star consume one more character and try again
+            p = star_p + 1;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    // any trailing stars can match an empty sequence
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// a name is included if there are no patterns or it matches any of the patterns
+pub fn matches_any(patterns: &[String], name: &str) -> bool {
+    patterns.is_empty() || patterns.iter().any(|pattern| glob_match(pattern, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.txt", "a.txt"));
+        assert!(glob_match("*.txt", "dir/a.txt"));
+        assert!(glob_match("dir/?.rs", "dir/a.rs"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("*.txt", "a.txt.bak"));
+        assert!(!glob_match("?.rs", "ab.rs"));
+        assert!(!glob_match("dir/*.rs", "other/a.rs"));
+    }
+}
@@ -0,0 +1,23 @@
+// Joseph Prichard
+// 1/5/2023
+// Library exposing the compressor, archiver, and bit layered io
+
+pub mod compress;
+pub mod coder;
+pub mod bitwise_io;
+pub mod structures;
+pub mod crc;
+pub mod glob;
+pub mod selftest;
+pub mod stats;
+pub mod names;
+pub mod archive_reader;
+pub mod progress;
+pub mod recover;
+pub mod tar_input;
+pub mod tar_output;
+pub mod dictionary;
+pub mod file_limit;
+pub mod entry_sink;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
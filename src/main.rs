@@ -2,80 +2,650 @@
 // 1/5/2023
 // Application to compress or decompress files
 
-use std::env;
-use compress::{get_file_blocks, unarchive_zip};
+use std::{env, fs};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use zipper::compress::{archive_dir, archive_to_writer, code_tree_from_file, list_file_blocks, read_file_blocks_with, sort_file_blocks, strip_ext, unarchive_from_reader, unarchive_zip, write_block_tree, write_hex_dump, write_tree_dot};
+use zipper::compress::{ArchiveOptions, ExistingOutput, ExtractOptions, ListSort, DEFAULT_MAX_BUFFERED_BYTES, DEFAULT_MIN_PARALLEL_BYTES, DEFAULT_MIN_SAVINGS, DEFAULT_STORE_BELOW};
+use zipper::compress::{read_archive_info, upgrade_archive, MissingGroupSeparator, DEFAULT_RENAME_TEMPLATE};
+use zipper::bitwise_io::FileReader;
+use zipper::coder::{parse_method, METHOD_HUFFMAN};
+use zipper::selftest::run_self_test;
+use zipper::recover::recover_archive;
+use zipper::tar_input::archive_tar;
+use zipper::tar_output::unarchive_to_tar;
+use zipper::archive_reader::read_manifest;
+use zipper::dictionary::Dictionary;
+use zipper::progress::ProgressFormat;
 
-use crate::compress::{archive_dir, list_file_blocks};
-use crate::bitwise_io::FileReader;
-
-mod compress;
-mod bitwise_io;
-mod structures;
-
-fn main() {
+fn main() -> std::io::Result<()> {
     let args: Vec<String> = env::args().collect();
 
     let mut entries: Vec<String> = vec![];
     let mut exec_flag: String = String::from("");
     let mut has_mt_flag: bool = false;
+    let mut method: u8 = METHOD_HUFFMAN;
+    let mut include: Vec<String> = vec![];
+    let mut follow_parent: bool = false;
+    let mut absolute: bool = false;
+    let mut time_limit: Option<Duration> = None;
+    let mut lossy_names: bool = false;
+    let mut missing_group_separator: MissingGroupSeparator = MissingGroupSeparator::Warn;
+    let mut portable_paths: bool = true;
+    let mut store_below: u64 = DEFAULT_STORE_BELOW;
+    let mut stats_path: Option<String> = None;
+    let mut exclude_larger_than: Option<u64> = None;
+    let mut separate: bool = false;
+    let mut allow_empty: bool = false;
+    let mut compress_names: bool = false;
+    let mut fsync: bool = false;
+    let mut check_space: bool = true;
+    let mut paranoid: bool = false;
+    let mut verbose: bool = false;
+    let mut stream: bool = false;
+    let mut from_tar: bool = false;
+    let mut to_tar: Option<String> = None;
+    let mut print_tree: bool = false;
+    let mut list_sort: Option<ListSort> = None;
+    let mut grouped: bool = false;
+    let mut manifest: bool = false;
+    let mut sidecar_index: bool = false;
+    let mut base: Option<String> = None;
+    let mut dictionary: Option<String> = None;
+    let mut resume: bool = false;
+    let mut directory: Option<String> = None;
+    let mut file_mode: Option<u32> = None;
+    let mut dir_mode: Option<u32> = None;
+    let mut dump_offset: u64 = 0;
+    let mut dump_len: Option<u64> = None;
+    let mut trailing_index: bool = false;
+    let mut keep_going: bool = false;
+    let mut max_depth: Option<usize> = None;
+    let mut existing_output: ExistingOutput = ExistingOutput::Merge;
+    let mut verify_headers: bool = false;
+    let mut strict: bool = false;
+    let mut skip_errors: bool = false;
+    let mut flat: bool = false;
+    let mut shorten_names: bool = false;
+    let mut rename_reserved: bool = false;
+    let mut rename_template: Option<String> = None;
+    let mut min_parallel_bytes: u64 = DEFAULT_MIN_PARALLEL_BYTES;
+    let mut max_open_files: Option<usize> = None;
+    let mut compare_gzip: bool = false;
+    let mut max_buffered_bytes: u64 = DEFAULT_MAX_BUFFERED_BYTES;
+    let mut sample_size: Option<u64> = None;
+    let mut min_savings: f64 = DEFAULT_MIN_SAVINGS;
+    let mut progress: Option<ProgressFormat> = None;
+    let mut size: usize = 100 * 1024 * 1024;
+    let mut entropy: f64 = 0.5;
 
-    for i in 1..args.len() {
+    let mut i = 1;
+    while i < args.len() {
         let arg = &args[i];
         // invariant: a program argument must have at least 1 character
-        let first = arg.chars().nth(0).expect("Expected an argument to be at least 1 char");
+        let first = arg.chars().next().expect("Expected an argument to be at least 1 char");
 
-        if first == '-' {
+        // a lone - is an entry standing for stdin, not a flag
+        if first == '-' && arg != "-" {
             // if the arg begins with a -, then the arg is a flag
             let flag = String::from(arg);
             if flag == "-mt" {
                 has_mt_flag = true;
+            } else if flag == "--follow-parent" {
+                follow_parent = true;
+            } else if flag == "--absolute" {
+                absolute = true;
+            } else if flag == "--progress" {
+                progress = Some(ProgressFormat::Human);
+            } else if let Some(format) = flag.strip_prefix("--progress=") {
+                progress = Some(ProgressFormat::parse(format)
+                    .unwrap_or_else(|| panic!("Unknown progress format {}, expected human or machine", format)));
+            } else if let Some(value) = flag.strip_prefix("--sort=") {
+                list_sort = Some(ListSort::parse(value)
+                    .unwrap_or_else(|| panic!("Unknown sort {}, expected name, compressed, uncompressed, or ratio with an optional :asc or :desc", value)));
+            } else if flag == "--flat" {
+                flat = true;
+            } else if flag == "--print-tree" {
+                print_tree = true;
+            } else if flag == "--shorten-names" {
+                shorten_names = true;
+            } else if flag == "--rename-reserved" {
+                rename_reserved = true;
+            } else if flag == "--rename-on-conflict" {
+                rename_template = Some(String::from(DEFAULT_RENAME_TEMPLATE));
+            } else if let Some(template) = flag.strip_prefix("--rename-on-conflict=") {
+                rename_template = Some(String::from(template));
+            } else if flag == "--strict" {
+                strict = true;
+            } else if flag == "--skip-errors" {
+                skip_errors = true;
+            } else if flag == "--verify-headers" {
+                verify_headers = true;
+            } else if flag == "--keep-going" {
+                keep_going = true;
+            } else if flag == "--index" {
+                trailing_index = true;
+            } else if flag == "--paranoid" {
+                paranoid = true;
+            } else if flag == "--verbose" {
+                verbose = true;
+            } else if flag == "--stream" {
+                stream = true;
+            } else if flag == "--resume" {
+                resume = true;
+            } else if flag == "--sidecar-index" {
+                sidecar_index = true;
+            } else if flag == "--with-manifest" {
+                manifest = true;
+            } else if flag == "--compare-gzip" || flag == "--compare-with-gzip" {
+                compare_gzip = true;
+            } else if flag == "--grouped" {
+                grouped = true;
+            } else if flag == "--from-tar" {
+                from_tar = true;
+            } else if flag == "--no-check-space" {
+                check_space = false;
+            } else if flag == "--fsync" {
+                fsync = true;
+            } else if flag == "--compress-names" {
+                compress_names = true;
+            } else if flag == "--allow-empty" {
+                allow_empty = true;
+            } else if flag == "--separate" {
+                separate = true;
+            } else if flag == "--native-paths" {
+                portable_paths = false;
+            } else if flag == "--lossy-names" {
+                lossy_names = true;
+            } else if flag == "--strict-headers" {
+                missing_group_separator = MissingGroupSeparator::Error;
+            } else if flag == "--files-from" {
+                // entries are read from a list file in the order they are listed
+                i += 1;
+                let list_path = args.get(i).expect("Expected a list file path after --files-from");
+                entries.extend(read_files_from(list_path)?);
+            } else if flag == "--to-tar" {
+                i += 1;
+                let tar_path = args.get(i).expect("Expected a tar file path after --to-tar");
+                to_tar = Some(tar_path.clone());
+            } else if flag == "-C" || flag == "--directory" {
+                i += 1;
+                let path = args.get(i).expect("Expected a directory after -C");
+                directory = Some(path.clone());
+            } else if flag == "--mode" {
+                i += 1;
+                let value = args.get(i).expect("Expected a mode after --mode");
+                file_mode = Some(parse_mode(value)
+                    .unwrap_or_else(|| panic!("Invalid mode {}, expected an octal mode such as 0644", value)));
+            } else if flag == "--dir-mode" {
+                i += 1;
+                let value = args.get(i).expect("Expected a mode after --dir-mode");
+                dir_mode = Some(parse_mode(value)
+                    .unwrap_or_else(|| panic!("Invalid mode {}, expected an octal mode such as 0755", value)));
+            } else if flag == "--base" || flag == "--since-archive" {
+                i += 1;
+                let base_path = args.get(i).expect("Expected a base archive path after --base");
+                base = Some(base_path.clone());
+            } else if flag == "--dictionary" {
+                i += 1;
+                let dictionary_path = args.get(i).expect("Expected a dictionary file path after --dictionary");
+                dictionary = Some(dictionary_path.clone());
+            } else if flag == "--method" {
+                i += 1;
+                let name = args.get(i).expect("Expected a compression method after --method");
+                method = parse_method(name)
+                    .unwrap_or_else(|| panic!("Unknown compression method {}, expected huffman or stored", name));
+            } else if flag == "--time-limit" {
+                i += 1;
+                let limit = args.get(i).expect("Expected a duration after --time-limit");
+                time_limit = Some(parse_duration(limit)
+                    .unwrap_or_else(|| panic!("Invalid duration {}, expected a number with a ms, s, m, or h suffix", limit)));
+            } else if flag == "--size" {
+                i += 1;
+                let value = args.get(i).expect("Expected a size after --size");
+                size = parse_size(value)
+                    .unwrap_or_else(|| panic!("Invalid size {}, expected a number with an optional K, M, or G suffix", value));
+            } else if flag == "--offset" {
+                i += 1;
+                let value = args.get(i).expect("Expected a byte offset after --offset");
+                dump_offset = value.parse()
+                    .unwrap_or_else(|_| panic!("Invalid offset {}, expected a number of bytes", value));
+            } else if flag == "--len" {
+                i += 1;
+                let value = args.get(i).expect("Expected a byte length after --len");
+                dump_len = Some(value.parse()
+                    .unwrap_or_else(|_| panic!("Invalid length {}, expected a number of bytes", value)));
+            } else if flag == "--store-below" {
+                i += 1;
+                let value = args.get(i).expect("Expected a size after --store-below");
+                store_below = parse_size(value)
+                    .unwrap_or_else(|| panic!("Invalid size {}, expected a number with an optional K, M, or G suffix", value)) as u64;
+            } else if flag == "--if-output-exists" {
+                i += 1;
+                let value = args.get(i).expect("Expected merge, number, or error after --if-output-exists");
+                existing_output = ExistingOutput::parse(value)
+                    .unwrap_or_else(|| panic!("Unknown choice {}, expected merge, number, or error", value));
+            } else if flag == "--max-depth" {
+                i += 1;
+                let value = args.get(i).expect("Expected a depth after --max-depth");
+                max_depth = Some(value.parse()
+                    .unwrap_or_else(|_| panic!("Invalid depth {}, expected a whole number", value)));
+            } else if flag == "--min-parallel-size" {
+                i += 1;
+                let value = args.get(i).expect("Expected a size after --min-parallel-size");
+                min_parallel_bytes = parse_size(value)
+                    .unwrap_or_else(|| panic!("Invalid size {}, expected a number with an optional K, M, or G suffix", value)) as u64;
+            } else if flag == "--max-open-files" {
+                i += 1;
+                let value = args.get(i).expect("Expected a number of files after --max-open-files");
+                max_open_files = Some(value.parse()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .unwrap_or_else(|| panic!("Invalid number of files {}, expected a whole number above 0", value)));
+            } else if flag == "--max-buffered-size" {
+                i += 1;
+                let value = args.get(i).expect("Expected a size after --max-buffered-size");
+                max_buffered_bytes = parse_size(value)
+                    .unwrap_or_else(|| panic!("Invalid size {}, expected a number with an optional K, M, or G suffix", value)) as u64;
+            } else if flag == "--sample-size" {
+                i += 1;
+                let value = args.get(i).expect("Expected a size after --sample-size");
+                sample_size = Some(parse_size(value)
+                    .unwrap_or_else(|| panic!("Invalid size {}, expected a number with an optional K, M, or G suffix", value)) as u64);
+            } else if flag == "--min-savings" {
+                i += 1;
+                let value = args.get(i).expect("Expected a fraction after --min-savings");
+                min_savings = value.parse()
+                    .ok()
+                    .filter(|fraction| (0.0..=1.0).contains(fraction))
+                    .unwrap_or_else(|| panic!("Invalid fraction {}, expected a number between 0 and 1", value));
+            } else if flag == "--exclude-larger-than" {
+                i += 1;
+                let value = args.get(i).expect("Expected a size after --exclude-larger-than");
+                exclude_larger_than = Some(parse_size(value)
+                    .unwrap_or_else(|| panic!("Invalid size {}, expected a number with an optional K, M, or G suffix", value)) as u64);
+            } else if flag == "--stats" {
+                i += 1;
+                let path = args.get(i).expect("Expected a file path after --stats");
+                // fail before any files are archived, rather than once the archive is written
+                if !cfg!(feature = "stats") {
+                    return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "--stats requires building with the stats feature"));
+                }
+                stats_path = Some(String::from(path));
+            } else if flag == "--entropy" {
+                i += 1;
+                let value = args.get(i).expect("Expected an entropy after --entropy");
+                entropy = value.parse()
+                    .unwrap_or_else(|_| panic!("Invalid entropy {}, expected a number between 0 and 1", value));
+            } else if flag == "--include" {
+                i += 1;
+                let pattern = args.get(i).expect("Expected a glob pattern after --include");
+                include.push(String::from(pattern));
             } else {
                 exec_flag = flag;
             }
         } else {
             entries.push(String::from(arg));
         }
+        i += 1;
+    }
+
+    // the self-test generates its own data so it is the only command without a file path
+    if exec_flag == "--selftest" {
+        match self_test(size, entropy, method) {
+            Ok(()) => eprintln!("Finished execution with success code"),
+            Err(e) => panic!("IO error occurred during execution: {}", e)
+        }
+        return Ok(());
     }
 
-    if entries.len() < 1 {
+    if entries.is_empty() {
         println!("Needs at least one file path as an argument");
-        return;
+        return Ok(());
     }
 
     let flags = ExecFlags {
         exec_flag: &exec_flag,
         has_mt_flag,
+        method,
+        include,
+        follow_parent,
+        absolute,
+        time_limit,
+        lossy_names,
+        missing_group_separator,
+        portable_paths,
+        store_below,
+        stats_path,
+        exclude_larger_than,
+        separate,
+        allow_empty,
+        compress_names,
+        fsync,
+        check_space,
+        paranoid,
+        verbose,
+        stream,
+        from_tar,
+        to_tar,
+        print_tree,
+        list_sort,
+        grouped,
+        manifest,
+        sidecar_index,
+        base,
+        dictionary,
+        resume,
+        directory,
+        file_mode,
+        dir_mode,
+        dump_offset,
+        dump_len,
+        sample_size,
+        min_savings,
+        trailing_index,
+        keep_going,
+        max_depth,
+        existing_output,
+        verify_headers,
+        strict,
+        skip_errors,
+        flat,
+        shorten_names,
+        rename_reserved,
+        rename_template,
+        min_parallel_bytes,
+        max_open_files,
+        max_buffered_bytes,
+        compare_gzip,
+        progress,
     };
+    // status goes to stderr so commands that write data to stdout can be redirected
     match exec_cli(&flags, &entries) {
-        Ok(()) => println!("Finished execution with success code"),
-        Err(e) => panic!("IO error occurred during execution: {}", e.to_string())
+        Ok(()) => eprintln!("Finished execution with success code"),
+        Err(e) => panic!("IO error occurred during execution: {}", e)
     }
+    Ok(())
+}
+
+// reads a newline separated list of file paths, skipping blank lines
+fn read_files_from(list_path: &str) -> std::io::Result<Vec<String>> {
+    let contents = fs::read_to_string(list_path)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("Cannot read file list {}: {}", list_path, e)))?;
+    Ok(contents.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+// parses a duration such as 500ms, 30s, 5m, or 1h, a number without a suffix is in seconds
+fn parse_duration(value: &str) -> Option<Duration> {
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return None
+    };
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+// parses a byte size such as 512, 64K, 100M, or 2G, using powers of 1024
+fn parse_size(value: &str) -> Option<usize> {
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: usize = number.parse().ok()?;
+    let multiplier: usize = match unit {
+        "" | "B" => 1,
+        "K" => 1024,
+        "M" => 1024 * 1024,
+        "G" => 1024 * 1024 * 1024,
+        _ => return None
+    };
+    number.checked_mul(multiplier)
+}
+
+// parses a unix permission mode written in octal, such as 0644 or 755
+fn parse_mode(value: &str) -> Option<u32> {
+    u32::from_str_radix(value, 8).ok().filter(|mode| *mode <= 0o7777)
+}
+
+fn self_test(size: usize, entropy: f64, method: u8) -> std::io::Result<()> {
+    let report = run_self_test(size, entropy, method, &mut rand::thread_rng())?;
+    const MIB: f64 = 1024.0 * 1024.0;
+    println!("Original size:    {} bytes", report.og_byte_size);
+    println!("Compressed size:  {} bytes", report.compressed_byte_size);
+    println!("Ratio:            {:.4}", report.ratio());
+    println!("Compression:      {:.2} MiB/s", report.compress_throughput() / MIB);
+    println!("Decompression:    {:.2} MiB/s", report.decompress_throughput() / MIB);
+    println!("Round trip:       {}", if report.correct { "ok" } else { "MISMATCH" });
+    if !report.correct {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Decompressed data does not match the original data"));
+    }
+    Ok(())
 }
 
 struct ExecFlags<'a> {
     exec_flag: &'a str,
     has_mt_flag: bool,
+    method: u8,
+    include: Vec<String>,
+    follow_parent: bool,
+    absolute: bool,
+    time_limit: Option<Duration>,
+    lossy_names: bool,
+    missing_group_separator: MissingGroupSeparator,
+    portable_paths: bool,
+    store_below: u64,
+    stats_path: Option<String>,
+    exclude_larger_than: Option<u64>,
+    separate: bool,
+    allow_empty: bool,
+    compress_names: bool,
+    fsync: bool,
+    check_space: bool,
+    paranoid: bool,
+    verbose: bool,
+    stream: bool,
+    from_tar: bool,
+    to_tar: Option<String>,
+    print_tree: bool,
+    list_sort: Option<ListSort>,
+    grouped: bool,
+    manifest: bool,
+    sidecar_index: bool,
+    base: Option<String>,
+    dictionary: Option<String>,
+    resume: bool,
+    directory: Option<String>,
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+    dump_offset: u64,
+    dump_len: Option<u64>,
+    sample_size: Option<u64>,
+    min_savings: f64,
+    trailing_index: bool,
+    keep_going: bool,
+    max_depth: Option<usize>,
+    existing_output: ExistingOutput,
+    verify_headers: bool,
+    strict: bool,
+    skip_errors: bool,
+    flat: bool,
+    shorten_names: bool,
+    rename_reserved: bool,
+    rename_template: Option<String>,
+    min_parallel_bytes: u64,
+    max_open_files: Option<usize>,
+    max_buffered_bytes: u64,
+    compare_gzip: bool,
+    progress: Option<ProgressFormat>,
 }
 
-fn exec_cli<'a>(exec_flags: &'a ExecFlags, entries: &Vec<String>) -> std::io::Result<()> {
+fn exec_cli(exec_flags: &ExecFlags, entries: &[String]) -> std::io::Result<()> {
     let last = entries.len() - 1;
     // execute a different command based on flag
     match exec_flags.exec_flag {
         "-l" | "list" => {
             let archive_path = &entries[last];
             let blocks_reader = &mut FileReader::new(archive_path)?;
-            let blocks = get_file_blocks(blocks_reader)?;
-            list_file_blocks(&blocks);
+            let blocks = read_file_blocks_with(blocks_reader, exec_flags.lossy_names, exec_flags.missing_group_separator)?;
+            match exec_flags.list_sort {
+                Some(sort) => list_file_blocks(&sort_file_blocks(&blocks, sort), exec_flags.grouped),
+                None => list_file_blocks(&blocks, exec_flags.grouped),
+            }
+            Ok(())
+        }
+        "--manifest" => {
+            let manifest = read_manifest(&entries[last])?;
+            std::io::stdout().lock().write_all(&manifest)
+        }
+        "--info" => {
+            let info = read_archive_info(&entries[last])?;
+            println!("Format version:  {}", info.version);
+            println!("Flags:           {:#04x}", info.flags);
+            println!("Entries:         {}", info.block_count);
+            // archives written before the metadata section was added don't record these
+            match &info.metadata {
+                Some(metadata) => {
+                    println!("Created at:      {} (seconds since the unix epoch)", metadata.created_at);
+                    println!("Written by:      zipper {}", metadata.tool_version);
+                }
+                None => println!("Created at:      unknown, written before format version 5"),
+            }
+            Ok(())
+        }
+        "--hexdump" => {
+            let reader = &mut FileReader::new(&entries[last])?;
+            write_hex_dump(&mut std::io::stdout().lock(), reader, exec_flags.dump_offset, exec_flags.dump_len)
+        }
+        "--dot" => {
+            let filepath = &entries[last];
+            let tree = code_tree_from_file(filepath)?;
+            write_tree_dot(&mut std::io::stdout().lock(), &tree.root)
+        }
+        "--recover" | "recover" => {
+            // the output directory is optional, by default it is named after the archive
+            let archive_path = &entries[0];
+            let output_dir = entries.get(1).cloned()
+                .unwrap_or_else(|| strip_ext(archive_path) + "_recovered");
+            let recovery = recover_archive(archive_path, &output_dir)?;
+            for name in &recovery.recovered {
+                println!("Recovered {}", name);
+            }
+            for name in &recovery.skipped {
+                println!("Could not recover {}", name);
+            }
+            println!("Recovered {} files into {}", recovery.recovered.len(), output_dir);
+            Ok(())
+        }
+        "--upgrade" | "upgrade" => {
+            let archive_path = &entries[0];
+            let output_path = entries.get(1)
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Expected a path to write the upgraded archive to"))?;
+            let blocks = upgrade_archive(archive_path, output_path)?;
+            println!("Upgraded {} files into {}", blocks.len(), output_path);
+            Ok(())
+        }
+        "-d" | "decompress" if exec_flags.print_tree => {
+            // the archive comes first, followed by the entries to print the trees of
+            let archive_path = &entries[0];
+            let stdout = &mut std::io::stdout().lock();
+            for filename_rel in &entries[1..] {
+                write_block_tree(stdout, archive_path, filename_rel)?;
+            }
             Ok(())
         }
         "-d" | "decompress" => {
             let archive_path = &entries[last];
-            unarchive_zip(archive_path, exec_flags.has_mt_flag)
+            let options = ExtractOptions {
+                multithreaded: exec_flags.has_mt_flag,
+                include: exec_flags.include.clone(),
+                absolute: exec_flags.absolute,
+                lossy_names: exec_flags.lossy_names,
+                missing_group_separator: exec_flags.missing_group_separator,
+                check_space: exec_flags.check_space,
+                keep_going: exec_flags.keep_going,
+                existing_output: exec_flags.existing_output,
+                flat: exec_flags.flat,
+                shorten_names: exec_flags.shorten_names,
+                rename_reserved: exec_flags.rename_reserved,
+                rename_template: exec_flags.rename_template.clone(),
+                resume: exec_flags.resume,
+                directory: exec_flags.directory.clone(),
+                file_mode: exec_flags.file_mode,
+                dir_mode: exec_flags.dir_mode,
+            };
+            match &exec_flags.to_tar {
+                // a tar path of - writes the tar to stdout so it can be piped
+                Some(tar_path) if tar_path == "-" => {
+                    unarchive_to_tar(archive_path, std::io::stdout().lock(), &options)?;
+                    Ok(())
+                }
+                Some(tar_path) => {
+                    let blocks = unarchive_to_tar(archive_path, fs::File::create(tar_path)?, &options)?;
+                    println!("Wrote {} files to: {}", blocks.len(), tar_path);
+                    Ok(())
+                }
+                // an archive path of - reads the archive from stdin, such as one piped from --stream
+                None if archive_path == "-" => unarchive_from_reader(&mut std::io::stdin().lock(), &options),
+                None => unarchive_zip(archive_path, &options)
+            }
         }
-        "-c" | "compress" | _ => {
-            let blocks = archive_dir(&entries, exec_flags.has_mt_flag)?;
-            list_file_blocks(&blocks);
+        // "-c" and "compress" are the default command
+        _ => {
+            let options = ArchiveOptions {
+                multithreaded: exec_flags.has_mt_flag,
+                method: exec_flags.method,
+                follow_parent: exec_flags.follow_parent,
+                time_limit: exec_flags.time_limit,
+                portable_paths: exec_flags.portable_paths,
+                store_below: exec_flags.store_below,
+                stats_path: exec_flags.stats_path.clone(),
+                exclude_larger_than: exec_flags.exclude_larger_than,
+                separate: exec_flags.separate,
+                allow_empty: exec_flags.allow_empty,
+                compress_names: exec_flags.compress_names,
+                fsync: exec_flags.fsync,
+                progress: exec_flags.progress,
+                paranoid: exec_flags.paranoid,
+                sample_size: exec_flags.sample_size,
+                min_savings: exec_flags.min_savings,
+                trailing_index: exec_flags.trailing_index,
+                max_depth: exec_flags.max_depth,
+                verify_headers: exec_flags.verify_headers,
+                strict: exec_flags.strict,
+                skip_errors: exec_flags.skip_errors,
+                min_parallel_bytes: exec_flags.min_parallel_bytes,
+                verbose: exec_flags.verbose,
+                path_filter: None,
+                manifest: exec_flags.manifest,
+                sidecar_index: exec_flags.sidecar_index,
+                base: exec_flags.base.clone(),
+                dictionary: exec_flags.dictionary.as_deref().map(Dictionary::from_file).transpose()?.map(Arc::new),
+                created_at: None,
+                max_open_files: exec_flags.max_open_files,
+                max_buffered_bytes: exec_flags.max_buffered_bytes,
+                compare_gzip: exec_flags.compare_gzip,
+            };
+            if exec_flags.from_tar {
+                let blocks = archive_tar(&entries[0], &options)?;
+                list_file_blocks(&blocks, exec_flags.grouped);
+                return Ok(());
+            }
+            if exec_flags.stream {
+                // the archive is written to stdout, so nothing else can be printed there
+                archive_to_writer(entries, &options, &mut std::io::stdout().lock())?;
+                return Ok(());
+            }
+            let blocks = archive_dir(entries, &options)?;
+            list_file_blocks(&blocks, exec_flags.grouped);
             Ok(())
         }
     }
-}
\ No newline at end of file
+}
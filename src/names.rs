@@ -0,0 +1,150 @@
+// Joseph Prichard
+// 1/5/2023
+// Compressed name table for archives of many files sharing long path prefixes
+
+use std::io;
+use crate::bitwise_io::{decode_name, FileReader};
+use crate::coder::METHOD_HUFFMAN;
+use crate::compress::{compress_bytes, decompress_bytes};
+use crate::structures::FileBlock;
+
+// size of the shared prefix length and suffix length stored before each name in the table
+const NAME_FIELD_SIZE: u64 = std::mem::size_of::<u32>() as u64;
+
+// front code the names: each name is stored as the length of the prefix it shares with the previous name and the rest of the name
+pub fn front_code(names: &[&str]) -> Vec<u8> {
+    let mut table = vec![];
+    let mut previous: &[u8] = &[];
+    for name in names {
+        let name = name.as_bytes();
+        let shared = previous.iter()
+            .zip(name)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let suffix = &name[shared..];
+        table.extend_from_slice(&(shared as u32).to_le_bytes());
+        table.extend_from_slice(&(suffix.len() as u32).to_le_bytes());
+        table.extend_from_slice(suffix);
+        previous = name;
+    }
+    table
+}
+
+// reverse front coding, returning the raw bytes of each name
+pub fn front_decode(table: &[u8], count: u32) -> io::Result<Vec<Vec<u8>>> {
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "Name table is corrupt");
+    let mut names: Vec<Vec<u8>> = vec![];
+    let mut pos = 0;
+    let read_u32 = |pos: &mut usize| -> io::Result<usize> {
+        let bytes = table.get(*pos..(*pos + 4)).ok_or_else(corrupt)?;
+        *pos += 4;
+        // invariant: the slice is exactly 4 bytes long
+        Ok(u32::from_le_bytes(bytes.try_into().expect("Expected 4 bytes")) as usize)
+    };
+    for _ in 0..count {
+        let shared = read_u32(&mut pos)?;
+        let suffix_len = read_u32(&mut pos)?;
+        let previous: &[u8] = names.last().map_or(&[], |name| name);
+        let prefix = previous.get(..shared).ok_or_else(corrupt)?;
+        let suffix = table.get(pos..(pos + suffix_len)).ok_or_else(corrupt)?;
+        pos += suffix_len;
+        names.push([prefix, suffix].concat());
+    }
+    if pos != table.len() {
+        return Err(corrupt());
+    }
+    Ok(names)
+}
+
+// encode the name table section: the sizes of the huffman coded table followed by the coded table itself
+pub fn encode_name_table(blocks: &[FileBlock]) -> io::Result<Vec<u8>> {
+    let names: Vec<&str> = blocks.iter().map(|block| block.filename_rel.as_str()).collect();
+    let (table_block, compressed) = compress_bytes("names", &front_code(&names), METHOD_HUFFMAN)?;
+    let mut section = vec![];
+    section.extend_from_slice(&table_block.og_byte_size.to_le_bytes());
+    section.extend_from_slice(&table_block.tree_bit_size.to_le_bytes());
+    section.extend_from_slice(&table_block.data_bit_size.to_le_bytes());
+    section.extend_from_slice(&compressed);
+    Ok(section)
+}
+
+// the name table section as it's stored, read without decoding so the header checksum can be checked first
+pub struct NameTableSection {
+    table_block: FileBlock,
+    compressed: Vec<u8>,
+}
+
+pub fn read_name_table(reader: &mut FileReader) -> io::Result<NameTableSection> {
+    let table_block = FileBlock {
+        filename_rel: String::from("names"),
+        file_byte_offset: 0,
+        og_byte_size: reader.read_u64()?,
+        // the name table is covered by the header checksum instead
+        crc32: None,
+        tree_bit_size: reader.read_u64()?,
+        data_bit_size: reader.read_u64()?,
+        method: METHOD_HUFFMAN,
+        data_offset: None,
+        model_offset: None,
+        executable: false,
+        hardlink: false,
+    };
+    let byte_size = table_block.tree_bit_size.checked_add(table_block.data_bit_size)
+        .map(|bit_size| bit_size.div_ceil(8))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Name table is corrupt"))?;
+    // the sizes aren't trusted yet so the bytes are read one at a time instead of allocated up front
+    let mut compressed = vec![];
+    for _ in 0..byte_size {
+        compressed.push(reader.read_byte()?);
+    }
+    Ok(NameTableSection { table_block, compressed })
+}
+
+// decode the names of a section whose header checksum has been checked
+pub fn decode_name_table(section: &NameTableSection, count: u32, lossy_names: bool) -> io::Result<Vec<String>> {
+    // every byte of a table coded with more than one symbol takes at least a bit, and a table of one symbol can only be
+    // the empty names, all zeros, so a larger size is a crafted header that would decode without end
+    let max_size = section.table_block.data_bit_size.max(2 * NAME_FIELD_SIZE * count as u64);
+    if section.table_block.og_byte_size > max_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Name table claims {} bytes, more than its data can hold", section.table_block.og_byte_size)));
+    }
+    let table = decompress_bytes(&section.table_block, &section.compressed)?;
+    front_decode(&table, count)?
+        .into_iter()
+        .map(|name| decode_name(name, lossy_names))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use crate::bitwise_io::FileReader;
+    use crate::names::{decode_name_table, front_code, front_decode, read_name_table};
+
+    #[test]
+    fn test_front_coding_round_trip() {
+        let names = ["src/main.rs", "src/lib.rs", "src/lib.rs", "README.md", ""];
+        let table = front_code(&names);
+        let decoded = front_decode(&table, names.len() as u32).unwrap();
+        let decoded: Vec<&str> = decoded.iter().map(|name| std::str::from_utf8(name).unwrap()).collect();
+        assert_eq!(decoded, names);
+
+        // a table cut short or with a shared prefix longer than the previous name is corrupt
+        assert!(front_decode(&table[..(table.len() - 1)], names.len() as u32).is_err());
+        assert!(front_decode(&[5, 0, 0, 0, 0, 0, 0, 0], 1).is_err());
+    }
+
+    #[test]
+    fn test_single_leaf_table_cannot_claim_unbounded_size() {
+        // sizes of a table coded with a tree of one leaf, an a, and no data bits claiming a huge original size
+        let mut section = vec![];
+        section.extend_from_slice(&(u64::MAX / 2).to_le_bytes());
+        section.extend_from_slice(&9u64.to_le_bytes());
+        section.extend_from_slice(&0u64.to_le_bytes());
+        section.extend_from_slice(&[0x01 | (b'a' << 1), b'a' >> 7]);
+        let table = read_name_table(&mut FileReader::from_bytes(&section).unwrap()).unwrap();
+        let err = decode_name_table(&table, 1, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,53 @@
+// Joseph Prichard
+// 1/5/2023
+// Progress reporting as each file is compressed, either as text for people or as lines for other programs to parse
+
+use std::io::{self, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProgressFormat {
+    Human,
+    // PROGRESS <files_done>/<files_total> <bytes_done>/<bytes_total>
+    Machine,
+}
+
+impl ProgressFormat {
+    pub fn parse(name: &str) -> Option<ProgressFormat> {
+        match name {
+            "human" => Some(ProgressFormat::Human),
+            "machine" => Some(ProgressFormat::Machine),
+            _ => None
+        }
+    }
+}
+
+pub struct Progress<'a> {
+    format: ProgressFormat,
+    out: &'a mut dyn Write,
+    files_done: usize,
+    files_total: usize,
+    bytes_done: u64,
+    bytes_total: u64,
+}
+
+impl<'a> Progress<'a> {
+    pub fn new(format: ProgressFormat, out: &'a mut dyn Write, files_total: usize, bytes_total: u64) -> Progress<'a> {
+        Progress { format, out, files_done: 0, files_total, bytes_done: 0, bytes_total }
+    }
+
+    // report a file of a number of bytes as done
+    pub fn file_done(&mut self, byte_size: u64) -> io::Result<()> {
+        self.files_done += 1;
+        self.bytes_done += byte_size;
+        match self.format {
+            ProgressFormat::Human => {
+                let percent = if self.bytes_total == 0 { 100.0 } else { self.bytes_done as f64 / self.bytes_total as f64 * 100.0 };
+                writeln!(self.out, "Compressed {} of {} files ({:.1}%)", self.files_done, self.files_total, percent)?;
+            }
+            ProgressFormat::Machine => {
+                writeln!(self.out, "PROGRESS {}/{} {}/{}", self.files_done, self.files_total, self.bytes_done, self.bytes_total)?;
+            }
+        }
+        self.out.flush()
+    }
+}
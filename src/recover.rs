@@ -0,0 +1,308 @@
+// Joseph Prichard
+// 1/5/2023
+// Best-effort salvage of the files in a damaged archive by scanning for anything that looks like a block header
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use crate::coder::{METHOD_HUFFMAN, METHOD_STORED};
+use crate::compress::{decompress_bytes, from_portable_path, resolve_output_path, sizeof, SIG, TABLE_SIZE};
+use crate::structures::{FileBlock, ATTR_EXECUTABLE, ATTR_HARDLINK, CRC32_SIZE, HEADER_FIXED_SIZE};
+
+// longest name a scanned record may have, anything longer is assumed to be garbage
+const MAX_NAME_LEN: usize = 4096;
+
+// largest file of a single repeated byte a scanned record may claim, its data takes no bits so its size can't be checked
+// against the archive and garbage could otherwise claim a file of any size
+const MAX_SINGLE_SYMBOL_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Default)]
+pub struct Recovery {
+    // names of the files that decompressed cleanly and were written out
+    pub recovered: Vec<String>,
+    // names of records that looked like headers but whose data couldn't be decompressed
+    pub skipped: Vec<String>,
+}
+
+// scan the whole archive for block headers and extract every block that decompresses cleanly and matches its checksum, ignoring the header checksum
+// names stored in a compressed name table can't be found this way, so those archives have nothing to recover
+pub fn recover_archive(archive_filepath: &str, output_dir: &str) -> io::Result<Recovery> {
+    let bytes = fs::read(archive_filepath)?;
+    let sig = SIG.to_le_bytes();
+    let mut sig_positions: Vec<usize> = bytes.windows(sig.len())
+        .enumerate()
+        .filter(|(_, window)| *window == sig)
+        .map(|(i, _)| i)
+        .collect();
+    // if the signature itself is damaged, assume the archive starts at the beginning of the file
+    if sig_positions.first() != Some(&0) {
+        sig_positions.insert(0, 0);
+    }
+
+    let mut recovery = Recovery::default();
+    // the file each block's data was recovered to by where the data starts, or none if it couldn't be recovered
+    let mut recovered_paths: HashMap<usize, Option<PathBuf>> = HashMap::new();
+    for (i, &sig_pos) in sig_positions.iter().enumerate() {
+        // offsets are relative to the end of the signature of the archive the header belongs to
+        let base = sig_pos + sizeof(SIG);
+        let end = sig_positions.get(i + 1).copied().unwrap_or(bytes.len());
+        let mut pos = base;
+        while pos < end {
+            let Some((block, record_len)) = parse_record(&bytes, base, pos) else {
+                pos += 1;
+                continue;
+            };
+            pos += record_len;
+            let data_start = base + block.file_byte_offset as usize;
+            let recovered = match recovered_paths.get(&data_start) {
+                // a hardlink shares the data of the block it links to, so it's linked to the file recovered for that block
+                Some(target_path) if block.hardlink => match target_path {
+                    Some(target_path) => recover_link(target_path, &block, output_dir)?,
+                    None => false,
+                },
+                // anything else claiming data that was already recovered is assumed to be garbage
+                Some(_) => continue,
+                None => {
+                    let path = recover_block(&bytes, data_start, &block, output_dir)?;
+                    recovered_paths.insert(data_start, path.clone());
+                    path.is_some()
+                }
+            };
+            if recovered {
+                recovery.recovered.push(block.filename_rel);
+            } else {
+                recovery.skipped.push(block.filename_rel);
+            }
+        }
+    }
+    Ok(recovery)
+}
+
+// try to read a block header at a position, returning it with its length if every field is plausible
+fn parse_record(bytes: &[u8], base: usize, pos: usize) -> Option<(FileBlock, usize)> {
+    let read_u64 = |at: usize| -> Option<u64> {
+        Some(u64::from_le_bytes(bytes.get(at..(at + 8))?.try_into().ok()?))
+    };
+    let name_len = u32::from_le_bytes(bytes.get(pos..(pos + 4))?.try_into().ok()?) as usize;
+    if name_len == 0 || name_len > MAX_NAME_LEN {
+        return None;
+    }
+    let name_start = pos + 4;
+    let name = std::str::from_utf8(bytes.get(name_start..(name_start + name_len))?).ok()?;
+    if name.chars().any(char::is_control) {
+        return None;
+    }
+
+    let fields = name_start + name_len;
+    // the checksum follows the sizes and offset, then the method and attribute bytes
+    let crc_pos = fields + 4 * sizeof(0u64);
+    let method_pos = crc_pos + CRC32_SIZE;
+    let attributes = *bytes.get(method_pos + 1)?;
+    let block = FileBlock {
+        filename_rel: String::from(name),
+        tree_bit_size: read_u64(fields)?,
+        data_bit_size: read_u64(fields + 8)?,
+        file_byte_offset: read_u64(fields + 16)?,
+        og_byte_size: read_u64(fields + 24)?,
+        crc32: Some(u32::from_le_bytes(bytes.get(crc_pos..method_pos)?.try_into().ok()?)),
+        method: *bytes.get(method_pos)?,
+        data_offset: None,
+        model_offset: None,
+        executable: attributes & ATTR_EXECUTABLE != 0,
+        // a hardlink has the offset of an earlier block's data, it's linked to that block's file instead of decompressed again
+        hardlink: attributes & ATTR_HARDLINK != 0,
+    };
+    if !is_plausible(&block, base, bytes.len()) {
+        return None;
+    }
+    Some((block, (fields + HEADER_FIXED_SIZE as usize) - pos))
+}
+
+// check the sizes agree with the method and the data fits inside the file
+fn is_plausible(block: &FileBlock, base: usize, file_len: usize) -> bool {
+    let sizes_match = match block.method {
+        METHOD_STORED => block.tree_bit_size == 0 && block.og_byte_size.checked_mul(8) == Some(block.data_bit_size),
+        METHOD_HUFFMAN => {
+            // a tree of n symbols is 10n - 1 bits, and a tree of 1 symbol codes each byte with no bits
+            let tree_bits = block.tree_bit_size.saturating_add(1);
+            let symbols = tree_bits / 10;
+            let valid_tree = tree_bits.is_multiple_of(10) && (1..=TABLE_SIZE as u64).contains(&symbols);
+            let valid_data = if symbols == 1 {
+                block.data_bit_size == 0 && block.og_byte_size <= MAX_SINGLE_SYMBOL_SIZE
+            } else {
+                block.og_byte_size <= block.data_bit_size && block.og_byte_size.checked_mul(255).is_some_and(|max| block.data_bit_size <= max)
+            };
+            valid_tree && valid_data
+        }
+        _ => false
+    };
+    let data_end = block.tree_bit_size.checked_add(block.data_bit_size)
+        .map(|bit_size| bit_size.div_ceil(8))
+        .and_then(|byte_size| byte_size.checked_add(block.file_byte_offset))
+        .and_then(|end| end.checked_add(base as u64));
+    sizes_match && data_end.is_some_and(|end| end <= file_len as u64)
+}
+
+// decompress a block and write it out, returning the path it was written to or none if the block's data or name is unusable
+fn recover_block(bytes: &[u8], data_start: usize, block: &FileBlock, output_dir: &str) -> io::Result<Option<PathBuf>> {
+    let byte_size = (block.tree_bit_size + block.data_bit_size).div_ceil(8) as usize;
+    let Ok(data) = decompress_bytes(block, &bytes[data_start..(data_start + byte_size)]) else {
+        return Ok(None);
+    };
+    let Some(path) = prepare_path(block, output_dir)? else {
+        return Ok(None);
+    };
+    fs::write(&path, data)?;
+    Ok(Some(path))
+}
+
+// link a hardlink to the file recovered for the block it links to, copying the file where it can't be linked
+fn recover_link(target_path: &Path, block: &FileBlock, output_dir: &str) -> io::Result<bool> {
+    let Some(path) = prepare_path(block, output_dir)? else {
+        return Ok(false);
+    };
+    if path == target_path {
+        return Ok(true);
+    }
+    // a file left by an earlier recovery is replaced, the same as a recovered file overwrites it
+    if path.is_file() {
+        fs::remove_file(&path)?;
+    }
+    if fs::hard_link(target_path, &path).is_err() {
+        fs::copy(target_path, &path)?;
+    }
+    Ok(true)
+}
+
+// the path a block is recovered to with its parent directories created, or none if its name is unusable
+fn prepare_path(block: &FileBlock, output_dir: &str) -> io::Result<Option<PathBuf>> {
+    let filename_rel = from_portable_path(&block.filename_rel, MAIN_SEPARATOR);
+    let Ok(path) = resolve_output_path(output_dir, &filename_rel, false) else {
+        return Ok(None);
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(Some(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::bitwise_io::FileReader;
+    use crate::compress::{archive_dir, get_file_blocks, sizeof, unarchive_zip, ArchiveMetadata, ArchiveOptions, ExtractOptions, SIG};
+    use crate::coder::METHOD_HUFFMAN;
+    use crate::crc::crc32;
+    use crate::recover::recover_archive;
+    use crate::testing::scratch_dir;
+
+    #[test]
+    fn test_recover_intact_files() {
+        let dir = scratch_dir("recover");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        let files = [("alpha.txt", "alpha file contents"), ("bravo.txt", "bravo file contents"), ("charlie.txt", "charlie file contents")];
+        for (name, data) in &files {
+            fs::write(input_dir.join(name), data).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+        let blocks = get_file_blocks(&mut FileReader::new(&archive_path).unwrap()).unwrap();
+
+        // break the name length of the second header: signature, version and flag bytes, block count, metadata, then the first header
+        let second_header = sizeof(SIG) + 6 + ArchiveMetadata::current().size() as usize + blocks[0].get_header_size() as usize;
+        let mut bytes = fs::read(&archive_path).unwrap();
+        bytes[second_header + 3] = 0xFF;
+        fs::write(&archive_path, &bytes).unwrap();
+        assert!(unarchive_zip(&archive_path, &ExtractOptions::default()).is_err());
+
+        let output_dir = dir.join("recovered");
+        let recovery = recover_archive(&archive_path, output_dir.to_str().unwrap()).unwrap();
+        assert_eq!(recovery.recovered, ["input/alpha.txt", "input/charlie.txt"]);
+        assert_eq!(fs::read_to_string(output_dir.join("input/alpha.txt")).unwrap(), files[0].1);
+        assert_eq!(fs::read_to_string(output_dir.join("input/charlie.txt")).unwrap(), files[2].1);
+        assert!(!output_dir.join("input/bravo.txt").exists());
+
+        // garbage is scanned without panicking and nothing is recovered from it
+        let garbage_path = dir.join("garbage.zipr");
+        let garbage: Vec<u8> = (0..20000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        fs::write(&garbage_path, garbage).unwrap();
+        let recovery = recover_archive(garbage_path.to_str().unwrap(), output_dir.to_str().unwrap()).unwrap();
+        assert!(recovery.recovered.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_recover_hardlinks() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = scratch_dir("recover_hardlinks");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::write(input_dir.join("a.txt"), "data shared by two hardlinks").unwrap();
+        fs::hard_link(input_dir.join("a.txt"), input_dir.join("b.txt")).unwrap();
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        let archive_path = format!("{}.zipr", input_path);
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        // the link's record has the offset of its target's data, and is linked to the recovered target
+        let output_dir = dir.join("recovered");
+        let recovery = recover_archive(&archive_path, output_dir.to_str().unwrap()).unwrap();
+        assert_eq!(recovery.recovered, ["input/a.txt", "input/b.txt"]);
+        assert_eq!(fs::read_to_string(output_dir.join("input/b.txt")).unwrap(), "data shared by two hardlinks");
+        let inode = |name: &str| fs::metadata(output_dir.join("input").join(name)).unwrap().ino();
+        assert_eq!(inode("a.txt"), inode("b.txt"));
+
+        // a link to data that couldn't be recovered is reported with it
+        let blocks = get_file_blocks(&mut FileReader::new(&archive_path).unwrap()).unwrap();
+        let mut bytes = fs::read(&archive_path).unwrap();
+        bytes[sizeof(SIG) + blocks[0].file_byte_offset as usize] ^= 0xFF;
+        fs::write(&archive_path, &bytes).unwrap();
+        fs::remove_dir_all(&output_dir).unwrap();
+        let recovery = recover_archive(&archive_path, output_dir.to_str().unwrap()).unwrap();
+        assert!(recovery.recovered.is_empty());
+        assert_eq!(recovery.skipped, ["input/a.txt", "input/b.txt"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_single_symbol_record_size_is_bounded() {
+        let dir = scratch_dir("recover_single_symbol");
+        // a record of a file of one repeated byte, whose tree is a single leaf and whose data takes no bits
+        let record = |name: &str, offset: u64, og_byte_size: u64, crc: u32| {
+            let mut bytes = vec![];
+            bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(name.as_bytes());
+            for field in [9, 0, offset, og_byte_size] {
+                bytes.extend_from_slice(&field.to_le_bytes());
+            }
+            bytes.extend_from_slice(&crc.to_le_bytes());
+            bytes.extend_from_slice(&[METHOD_HUFFMAN, 0]);
+            bytes
+        };
+        // offsets are relative to the end of where the signature would be
+        let mut bytes = vec![0u8; sizeof(SIG)];
+        let records_len = (record("small.txt", 0, 0, 0).len() + record("huge.txt", 0, 0, 0).len()) as u64;
+        bytes.extend(record("small.txt", records_len, 100, crc32(&[b'a'; 100])));
+        bytes.extend(record("huge.txt", records_len + 2, u64::MAX / 2, 0));
+        // each record has its own tree of a single leaf holding an a
+        for _ in 0..2 {
+            bytes.extend_from_slice(&[0x01 | (b'a' << 1), b'a' >> 7]);
+        }
+        let archive_path = dir.join("damaged.zipr");
+        fs::write(&archive_path, &bytes).unwrap();
+
+        let output_dir = dir.join("recovered");
+        let recovery = recover_archive(archive_path.to_str().unwrap(), output_dir.to_str().unwrap()).unwrap();
+        assert_eq!(recovery.recovered, ["small.txt"]);
+        assert!(recovery.skipped.is_empty(), "The huge record should not look like a header: {:?}", recovery.skipped);
+        assert_eq!(fs::read(output_dir.join("small.txt")).unwrap(), [b'a'; 100]);
+        assert!(!output_dir.join("huge.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
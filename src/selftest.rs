@@ -0,0 +1,105 @@
+// Joseph Prichard
+// 1/5/2023
+// Self-test that compresses synthetic data of a target entropy in memory to measure ratio and throughput
+
+use std::io;
+use std::time::{Duration, Instant};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+use crate::compress::{compress_bytes, decompress_bytes, entropy_bits, TABLE_SIZE};
+
+pub struct SelfTestReport {
+    pub og_byte_size: u64,
+    pub compressed_byte_size: u64,
+    pub compress_time: Duration,
+    pub decompress_time: Duration,
+    // true if the decompressed data is identical to the generated data
+    pub correct: bool,
+}
+
+impl SelfTestReport {
+    // compressed size as a fraction of the original size, lower is better
+    pub fn ratio(&self) -> f64 {
+        if self.og_byte_size == 0 {
+            return 1.0;
+        }
+        self.compressed_byte_size as f64 / self.og_byte_size as f64
+    }
+
+    // original bytes processed per second
+    pub fn compress_throughput(&self) -> f64 {
+        self.og_byte_size as f64 / self.compress_time.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn decompress_throughput(&self) -> f64 {
+        self.og_byte_size as f64 / self.decompress_time.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+// compress and decompress synthetic data without writing anything to disk
+pub fn run_self_test(size: usize, entropy: f64, method: u8, rng: &mut impl Rng) -> io::Result<SelfTestReport> {
+    let data = synthetic_data(size, entropy, rng)?;
+
+    let start = Instant::now();
+    let (block, compressed) = compress_bytes("selftest", &data, method)?;
+    let compress_time = start.elapsed();
+
+    let start = Instant::now();
+    let decompressed = decompress_bytes(&block, &compressed)?;
+    let decompress_time = start.elapsed();
+
+    Ok(SelfTestReport {
+        og_byte_size: data.len() as u64,
+        compressed_byte_size: compressed.len() as u64,
+        compress_time,
+        decompress_time,
+        correct: decompressed == data,
+    })
+}
+
+// generate bytes whose entropy is a fraction of the 8 bit maximum, 0 is a single repeated byte and 1 is uniform noise
+pub fn synthetic_data(size: usize, entropy: f64, rng: &mut impl Rng) -> io::Result<Vec<u8>> {
+    if !(0.0..=1.0).contains(&entropy) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("Entropy {} must be between 0 and 1", entropy)));
+    }
+    let weights = geometric_weights(entropy * 8.0);
+    // invariant: the first weight is always 1 so the weights can't all be zero
+    let dist = WeightedIndex::new(weights).expect("Expected the weights to have a positive sum");
+    Ok((0..size).map(|_| dist.sample(rng) as u8).collect())
+}
+
+// weights of r^i for each symbol, with r searched for so the distribution has the target entropy in bits
+fn geometric_weights(target_bits: f64) -> Vec<f64> {
+    let weights_for = |r: f64| -> Vec<f64> { (0..TABLE_SIZE).map(|i| r.powi(i as i32)).collect() };
+
+    // entropy increases with r, from 0 bits at r = 0 to 8 bits at r = 1
+    let (mut low, mut high) = (0.0, 1.0);
+    for _ in 0..64 {
+        let mid = (low + high) / 2.0;
+        if entropy_bits(&weights_for(mid)) < target_bits {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    weights_for((low + high) / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::coder::METHOD_HUFFMAN;
+    use crate::selftest::run_self_test;
+
+    #[test]
+    fn test_low_entropy_compresses_better() {
+        let mut rng = StdRng::seed_from_u64(1418);
+        let low = run_self_test(64 * 1024, 0.2, METHOD_HUFFMAN, &mut rng).unwrap();
+        let high = run_self_test(64 * 1024, 0.9, METHOD_HUFFMAN, &mut rng).unwrap();
+
+        assert!(low.correct && high.correct);
+        assert!(low.ratio() < high.ratio(), "Expected ratio {} to be less than {}", low.ratio(), high.ratio());
+    }
+}
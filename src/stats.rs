@@ -0,0 +1,208 @@
+// Joseph Prichard
+// 1/5/2023
+// Archive-wide statistics that can be saved as JSON to compare compression performance across runs
+
+use std::io::{self, Write};
+use std::time::Duration;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use crate::archive_reader::decompress_to_vec;
+use crate::bitwise_io::FileReader;
+use crate::compress::read_file_blocks;
+use crate::structures::FileBlock;
+
+// time spent in each phase of creating an archive, in seconds
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "stats", derive(serde::Serialize))]
+pub struct PhaseTimes {
+    pub scan_secs: f64,
+    pub model_secs: f64,
+    pub write_secs: f64,
+}
+
+impl PhaseTimes {
+    pub fn new(scan: Duration, model: Duration, write: Duration) -> PhaseTimes {
+        PhaseTimes { scan_secs: scan.as_secs_f64(), model_secs: model.as_secs_f64(), write_secs: write.as_secs_f64() }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "stats", derive(serde::Serialize))]
+pub struct ArchiveStats {
+    pub total_files: u64,
+    pub total_input_bytes: u64,
+    pub total_output_bytes: u64,
+    // per-file ratios of compressed to original size, empty files have no ratio and are left out
+    pub mean_ratio: f64,
+    pub median_ratio: f64,
+    pub min_ratio: f64,
+    pub max_ratio: f64,
+    pub phases: PhaseTimes,
+}
+
+impl ArchiveStats {
+    pub fn from_blocks(blocks: &[FileBlock], phases: PhaseTimes) -> ArchiveStats {
+        let ratios: Vec<f64> = blocks.iter()
+            .filter(|block| block.og_byte_size > 0)
+            .map(|block| block_byte_size(block) as f64 / block.og_byte_size as f64)
+            .collect();
+        let mean_ratio = if ratios.is_empty() { 0.0 } else { ratios.iter().sum::<f64>() / ratios.len() as f64 };
+
+        ArchiveStats {
+            total_files: blocks.len() as u64,
+            total_input_bytes: blocks.iter().map(|block| block.og_byte_size).sum(),
+            // a hardlink's data is counted once for the block it links to
+            total_output_bytes: blocks.iter().map(FileBlock::get_stored_size).sum(),
+            mean_ratio,
+            median_ratio: median(&ratios),
+            min_ratio: ratios.iter().copied().reduce(f64::min).unwrap_or(0.0),
+            max_ratio: ratios.iter().copied().reduce(f64::max).unwrap_or(0.0),
+            phases,
+        }
+    }
+}
+
+// bytes a block's model and data take up in the archive, including the padding to the next byte
+fn block_byte_size(block: &FileBlock) -> u64 {
+    (block.tree_bit_size + block.data_bit_size).div_ceil(8)
+}
+
+// the middle value, or the mean of the two middle values for an even count
+pub fn median(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+// an entry's size in the archive next to its size gzipped on its own
+#[derive(Clone, Debug, PartialEq)]
+pub struct GzipComparison {
+    pub filename_rel: String,
+    pub og_byte_size: u64,
+    pub zipper_bytes: u64,
+    pub gzip_bytes: u64,
+}
+
+pub fn gzip_size(data: &[u8]) -> io::Result<u64> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?.len() as u64)
+}
+
+// gzip each entry of an archive in memory, to show where deflate's repeated string matching beats huffman coding alone
+// hardlinks are left out since their data is stored once for the entry they link to
+pub fn compare_with_gzip(archive_filepath: &str) -> io::Result<Vec<GzipComparison>> {
+    let reader = &mut FileReader::new(archive_filepath)?;
+    let blocks = read_file_blocks(reader, true)?;
+    blocks.iter()
+        .filter(|block| !block.hardlink)
+        .map(|block| Ok(GzipComparison {
+            filename_rel: block.filename_rel.clone(),
+            og_byte_size: block.og_byte_size,
+            zipper_bytes: block_byte_size(block),
+            gzip_bytes: gzip_size(&decompress_to_vec(block, reader)?)?,
+        }))
+        .collect()
+}
+
+pub fn write_gzip_comparison(out: &mut dyn Write, comparisons: &[GzipComparison]) -> io::Result<()> {
+    let winner = |zipper_bytes: u64, gzip_bytes: u64| match zipper_bytes.cmp(&gzip_bytes) {
+        std::cmp::Ordering::Less => "zipper is smaller",
+        std::cmp::Ordering::Equal => "same size",
+        std::cmp::Ordering::Greater => "gzip is smaller",
+    };
+    for comparison in comparisons {
+        writeln!(out, "{}: {} bytes, zipper {} bytes, gzip {} bytes, {}", comparison.filename_rel, comparison.og_byte_size,
+            comparison.zipper_bytes, comparison.gzip_bytes, winner(comparison.zipper_bytes, comparison.gzip_bytes))?;
+    }
+    let og_byte_size: u64 = comparisons.iter().map(|comparison| comparison.og_byte_size).sum();
+    let zipper_bytes: u64 = comparisons.iter().map(|comparison| comparison.zipper_bytes).sum();
+    let gzip_bytes: u64 = comparisons.iter().map(|comparison| comparison.gzip_bytes).sum();
+    writeln!(out, "Total: {} bytes, zipper {} bytes, gzip {} bytes, {}", og_byte_size, zipper_bytes, gzip_bytes, winner(zipper_bytes, gzip_bytes))
+}
+
+#[cfg(feature = "stats")]
+pub fn write_stats(filepath: &str, stats: &ArchiveStats) -> io::Result<()> {
+    let json = serde_json::to_string_pretty(stats)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    std::fs::write(filepath, json + "\n")
+}
+
+#[cfg(not(feature = "stats"))]
+pub fn write_stats(_: &str, _: &ArchiveStats) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Writing stats requires building with the stats feature"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::compress::{archive_dir, ArchiveOptions};
+    use crate::stats::{compare_with_gzip, gzip_size, write_gzip_comparison, ArchiveStats, PhaseTimes};
+    use crate::structures::FileBlock;
+    use crate::testing::scratch_dir;
+
+    fn block(og_byte_size: u64, data_bit_size: u64) -> FileBlock {
+        FileBlock {
+            filename_rel: String::from("file"),
+            file_byte_offset: 0,
+            og_byte_size,
+            crc32: None,
+            tree_bit_size: 0,
+            data_bit_size,
+            method: 0,
+            data_offset: None,
+            model_offset: None,
+            executable: false,
+            hardlink: false,
+        }
+    }
+
+    #[test]
+    fn test_median_ratio() {
+        // ratios of 0.5, 0.25, 1.0, and 0.75, the empty file has no ratio
+        let blocks = [block(100, 400), block(100, 200), block(0, 0), block(100, 800), block(100, 600)];
+        let stats = ArchiveStats::from_blocks(&blocks, PhaseTimes::default());
+        assert_eq!(stats.total_files, 5);
+        assert_eq!(stats.total_output_bytes, 250);
+        assert_eq!(stats.median_ratio, 0.625);
+        assert_eq!(stats.min_ratio, 0.25);
+        assert_eq!(stats.max_ratio, 1.0);
+
+        let odd = ArchiveStats::from_blocks(&blocks[..3], PhaseTimes::default());
+        assert_eq!(odd.median_ratio, 0.375);
+    }
+
+    #[test]
+    fn test_compare_with_gzip() {
+        let dir = scratch_dir("compare_gzip");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(&input_dir).unwrap();
+        // english repeats whole words and phrases, which deflate matches and huffman coding alone can't
+        let text = "It was the best of times, it was the worst of times, it was the age of wisdom, it was the age of foolishness. ".repeat(40);
+        fs::write(input_dir.join("tale.txt"), &text).unwrap();
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        let comparisons = compare_with_gzip(&format!("{}.zipr", input_path)).unwrap();
+        assert_eq!(comparisons.len(), 1);
+        let comparison = &comparisons[0];
+        assert_eq!((comparison.filename_rel.as_str(), comparison.og_byte_size), ("input/tale.txt", text.len() as u64));
+        assert_eq!(comparison.gzip_bytes, gzip_size(text.as_bytes()).unwrap());
+        assert!(comparison.gzip_bytes < comparison.zipper_bytes, "gzip {} bytes, zipper {} bytes", comparison.gzip_bytes, comparison.zipper_bytes);
+
+        let mut report = vec![];
+        write_gzip_comparison(&mut report, &comparisons).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("input/tale.txt") && report.contains("gzip is smaller"), "Unexpected report: {}", report);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
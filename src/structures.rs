@@ -4,7 +4,7 @@
 
 use std::cmp::Ordering;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct FileBlock {
     // relative name of file to base directory in archive
     pub filename_rel: String,
@@ -12,42 +12,136 @@ pub struct FileBlock {
     pub file_byte_offset: u64,
     // original file size
     pub og_byte_size: u64,
+    // crc32 of the original file contents, checked when the file is decompressed
+    // none for archives written before checksums were added to the headers
+    pub crc32: Option<u32>,
     // length of encoded tree structure in bits
     pub tree_bit_size: u64,
     // length of compressed data in bits
     pub data_bit_size: u64,
+    // method byte of the coder used to compress the file
+    pub method: u8,
+    // byte offset position of the compressed data when it's stored apart from the tree, this isn't written in the header
+    // none when the data follows straight on from the tree
+    pub data_offset: Option<u64>,
+    // byte offset position of the tree when it's shared with other blocks instead of stored with the block, this isn't written in the header
+    // none when the tree is at the block's own offset
+    pub model_offset: Option<u64>,
+    // whether the owner could execute the file, restored as +x on platforms with an executable bit
+    pub executable: bool,
+    // whether the file is a hardlink to an earlier block, sharing that block's offset and sizes instead of storing its own data
+    // until the headers are written, a hardlink's offset holds the index of the block it links to
+    pub hardlink: bool,
 }
 
-pub fn sizeof<T>(_: T) -> usize {
+// size of the field a getter borrows, so the layout constants follow the types of the fields themselves
+const fn field_size<T>(_: fn(&FileBlock) -> &T) -> usize {
     std::mem::size_of::<T>()
 }
 
+// size of the length prefix written before each name in a block header
+pub const NAME_LEN_SIZE: u64 = std::mem::size_of::<u32>() as u64;
+
+// size of the checksum written in a block header, it's optional only for older archives
+pub const CRC32_SIZE: usize = std::mem::size_of::<u32>();
+
+// size of the fields that follow the name in a block header, in the order they are written
+pub const HEADER_FIXED_SIZE: u64 = (field_size(|block| &block.tree_bit_size) +
+    field_size(|block| &block.data_bit_size) +
+    field_size(|block| &block.file_byte_offset) +
+    field_size(|block| &block.og_byte_size) +
+    CRC32_SIZE +
+    field_size(|block| &block.method) +
+    field_size(|block| &block.executable)) as u64;
+
+// first format version whose block headers store the method byte, archives from before the version byte are read as version 0
+pub const METHOD_VERSION: u8 = 1;
+
+// first format version whose headers are counted and whose names are length prefixed, instead of separated by marker bytes
+pub const COUNTED_HEADERS_VERSION: u8 = 2;
+
+// first format version with a flags byte after the version byte
+pub const FLAGS_VERSION: u8 = 3;
+
+// first format version whose block headers end with an attribute byte
+pub const ATTRIBUTES_VERSION: u8 = 4;
+
+// first format version that records when and by which version of the tool the archive was written
+pub const METADATA_VERSION: u8 = 5;
+
+// first format version whose block headers store a checksum of the file after its original size
+pub const CHECKSUM_VERSION: u8 = 6;
+
+// first format version whose attribute byte can mark a block as a hardlink, older archives never set the bit
+pub const HARDLINK_VERSION: u8 = 7;
+
+// bit of the attribute byte set when the file is executable
+pub const ATTR_EXECUTABLE: u8 = 0x01;
+
+// bit of the attribute byte set when the file is a hardlink to an earlier block
+pub const ATTR_HARDLINK: u8 = 0x02;
+
+// size of the version and flag bytes and block count before the headers, and the checksum after them
+pub const ARCHIVE_HEADER_SIZE: u64 = (2 * std::mem::size_of::<u8>() + 2 * std::mem::size_of::<u32>()) as u64;
+
+// size of the footer after a trailing index: the size of the index and its signature
+pub const INDEX_FOOTER_SIZE: u64 = (2 * std::mem::size_of::<u64>()) as u64;
+
+// bit sizes of the model and compressed data of a block
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileBitSize {
+    pub tree_bit_size: u64,
+    pub data_bit_size: u64,
+}
+
 impl FileBlock {
+    // the bit sizes stored in the block's header
+    pub fn get_bit_size(&self) -> FileBitSize {
+        FileBitSize { tree_bit_size: self.tree_bit_size, data_bit_size: self.data_bit_size }
+    }
+
     pub fn get_header_size(&self) -> u64 {
-        // string len calculation includes null terminator
-        let size = 1 +
-            self.filename_rel.as_bytes().len() +
-            sizeof(self.tree_bit_size) +
-            sizeof(self.data_bit_size) +
-            sizeof(self.file_byte_offset) +
-            sizeof(self.og_byte_size);
-        size as u64
+        // string len calculation includes the length prefix
+        NAME_LEN_SIZE + self.filename_rel.len() as u64 + self.get_fields_size()
+    }
+
+    // size of the header fields that follow the name
+    pub fn get_fields_size(&self) -> u64 {
+        HEADER_FIXED_SIZE
+    }
+
+    // bytes the tree and data take up in the archive, they are aligned separately when the data is stored apart
+    pub fn get_stored_size(&self) -> u64 {
+        // a hardlink's tree and data are the ones stored for the block it links to
+        if self.hardlink {
+            return 0;
+        }
+        match self.data_offset {
+            Some(_) => self.tree_bit_size.div_ceil(8) + self.data_bit_size.div_ceil(8),
+            None => (self.tree_bit_size + self.data_bit_size).div_ceil(8),
+        }
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 pub struct SymbolCode {
     pub plain_symbol: u8,
-    pub encoded_symbol: u32,
+    // the code bits, first bit in the least significant position
+    pub encoded_symbol: u64,
     pub bit_len: u8,
 }
 
+// longest code a symbol code can hold, a huffman tree only gets this deep for inputs of tens of terabytes
+pub const MAX_CODE_LEN: u8 = 64;
+
 impl SymbolCode {
     pub fn new() -> SymbolCode {
         SymbolCode { plain_symbol: 0, encoded_symbol: 0, bit_len: 0 }
     }
 
-    pub fn append_bit(&self, bit: u32) -> SymbolCode {
+    pub fn append_bit(&self, bit: u64) -> SymbolCode {
+        // invariant: a code is never extended past the bits the code can hold
+        assert!(self.bit_len < MAX_CODE_LEN, "Expected symbol code to be at most {} bits", MAX_CODE_LEN);
         SymbolCode {
             plain_symbol: self.plain_symbol,
             encoded_symbol: self.encoded_symbol ^ (bit << self.bit_len),
@@ -56,11 +150,98 @@ impl SymbolCode {
     }
 }
 
+// a growable sequence of bits for codes of any length, packed the way bits are written to an archive
+// the first bit is in the least significant position of the first byte, and the bits past the length are always zero
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BitVec {
+    bytes: Vec<u8>,
+    len: u64,
+}
+
+impl BitVec {
+    pub fn new() -> BitVec {
+        BitVec { bytes: vec![], len: 0 }
+    }
+
+    // the first bit_len bits of the bytes, none if there aren't that many bits
+    pub fn from_bytes(bytes: &[u8], bit_len: u64) -> Option<BitVec> {
+        if bit_len > 8 * bytes.len() as u64 {
+            return None;
+        }
+        let mut bytes = bytes[..(bit_len.div_ceil(8) as usize)].to_vec();
+        if !bit_len.is_multiple_of(8) {
+            // invariant: a length that isn't a multiple of 8 always leaves a partial last byte
+            let last = bytes.last_mut().expect("Expected a partial last byte");
+            *last &= (1u8 << (bit_len % 8)) - 1;
+        }
+        Some(BitVec { bytes, len: bit_len })
+    }
+
+    pub fn push_bit(&mut self, bit: u8) {
+        if self.len.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if bit > 0 {
+            // invariant: a byte was pushed for the bit above if the last one was full
+            let last = self.bytes.last_mut().expect("Expected a byte to hold the bit");
+            *last |= 1 << (self.len % 8);
+        }
+        self.len += 1;
+    }
+
+    pub fn append(&mut self, other: &BitVec) {
+        if self.len.is_multiple_of(8) {
+            self.bytes.extend_from_slice(&other.bytes);
+            self.len += other.len;
+        } else {
+            other.iter().for_each(|bit| self.push_bit(bit));
+        }
+    }
+
+    pub fn get(&self, i: u64) -> Option<u8> {
+        if i >= self.len {
+            return None;
+        }
+        Some((self.bytes[(i / 8) as usize] >> (i % 8)) & 1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..self.len).map(|i| (self.bytes[(i / 8) as usize] >> (i % 8)) & 1)
+    }
+
+    // length in bits
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // the bits packed into bytes, with the last byte padded with zero bits
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl From<&SymbolCode> for BitVec {
+    fn from(symbol: &SymbolCode) -> BitVec {
+        let mut bits = BitVec::new();
+        for i in 0..symbol.bit_len {
+            bits.push_bit(((symbol.encoded_symbol >> i) & 1) as u8);
+        }
+        bits
+    }
+}
+
 pub struct Tree {
     pub left: Option<Box<Tree>>,
     pub right: Option<Box<Tree>>,
     pub plain_symbol: u8,
     pub weight: u64,
+    // breaks ties between nodes of equal weight so the tree doesn't depend on how the heap orders them
+    // a leaf's order is its symbol, and internal nodes are ordered after every leaf in the order they're made
+    pub order: u32,
 }
 
 impl Tree {
@@ -71,21 +252,23 @@ impl Tree {
             right: None,
             plain_symbol: symbol,
             weight,
+            order: symbol as u32,
         }
     }
 
     // moves the left and right nodes
     pub fn internal(left: Box<Tree>, right: Box<Tree>, symbol: u8, weight: u64) -> Tree {
         Tree {
-            left: Some(Box::new(*left)),
-            right: Some(Box::new(*right)),
+            left: Some(left),
+            right: Some(right),
             plain_symbol: symbol,
             weight,
+            order: 0,
         }
     }
 
     pub fn is_leaf(&self) -> bool {
-        self.left == None && self.right == None
+        self.left.is_none() && self.right.is_none()
     }
 }
 
@@ -93,18 +276,73 @@ impl Eq for Tree {}
 
 impl PartialEq<Self> for Tree {
     fn eq(&self, other: &Self) -> bool {
-        self.weight == other.weight
+        self.weight == other.weight && self.order == other.order
     }
 }
 
 impl PartialOrd<Self> for Tree {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(&other))
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for Tree {
+    // reversed so the max heap pops the lightest node first, and the earliest of nodes with the same weight
     fn cmp(&self, other: &Self) -> Ordering {
         other.weight.cmp(&self.weight)
+            .then_with(|| other.order.cmp(&self.order))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitwise_io::FileWriter;
+    use crate::structures::{BitVec, SymbolCode};
+
+    #[test]
+    fn test_bit_vec_round_trips() {
+        let mut bits = BitVec::new();
+        let pattern = [1, 0, 1, 1, 0, 0, 0, 1, 1, 0, 1];
+        for bit in pattern {
+            bits.push_bit(bit);
+        }
+        assert_eq!(bits.len(), 11);
+        assert_eq!(bits.iter().collect::<Vec<u8>>(), pattern);
+        assert_eq!(bits.as_bytes(), [0b1000_1101, 0b0000_0101]);
+        assert_eq!(bits.get(10), Some(1));
+        assert_eq!(bits.get(11), None);
+
+        // appending onto a length that isn't byte aligned shifts every appended bit
+        let mut appended = bits.clone();
+        appended.append(&bits);
+        assert_eq!(appended.len(), 22);
+        assert_eq!(appended.iter().collect::<Vec<u8>>(), [pattern, pattern].concat());
+        let mut aligned = BitVec::from_bytes(&[0xFF], 8).unwrap();
+        aligned.append(&bits);
+        assert_eq!(aligned.iter().skip(8).collect::<Vec<u8>>(), pattern);
+
+        // the bytes read back into the same bits, padding past the length is cleared
+        assert_eq!(BitVec::from_bytes(appended.as_bytes(), appended.len()), Some(appended.clone()));
+        assert_eq!(BitVec::from_bytes(&[0xFF, 0xFF], 11).unwrap().as_bytes(), [0xFF, 0b0000_0111]);
+        assert_eq!(BitVec::from_bytes(&[0xFF], 9), None);
+        assert!(BitVec::from_bytes(&[], 0).unwrap().is_empty());
+
+        // a symbol code packs into the same bytes the writer produces for it
+        let code = SymbolCode { plain_symbol: b'a', encoded_symbol: 0x1_2345_6789, bit_len: 37 };
+        let mut written = vec![];
+        let mut writer = FileWriter::from_writer(&mut written);
+        writer.write_symbol(&code).unwrap();
+        writer.align_to_byte().unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(BitVec::from(&code).as_bytes(), written);
+
+        let mut written = vec![];
+        let mut writer = FileWriter::from_writer(&mut written);
+        writer.write_bit_vec(&appended).unwrap();
+        writer.align_to_byte().unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(appended.as_bytes(), written);
+    }
+}
@@ -0,0 +1,131 @@
+// Joseph Prichard
+// 1/5/2023
+// Recompress the members of a tar archive straight into an archive, without extracting the tar to disk first
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, MAIN_SEPARATOR};
+use std::sync::atomic::AtomicU64;
+use std::time::Instant;
+use crate::bitwise_io::decode_name;
+use crate::compress::{add_manifest, configure_thread_pool, create_file_blocks, from_portable_path, method_for_size, thread_count, write_archive};
+use crate::compress::{ArchiveOptions, CodeBook};
+use crate::progress::Progress;
+use crate::structures::FileBlock;
+
+// archive each regular file in a tar as its own block, written next to the tar with .zipr in place of .tar
+// directories, links, and other special members have no contents to compress so they're skipped
+pub fn archive_tar(tar_filepath: &str, options: &ArchiveOptions) -> io::Result<Vec<FileBlock>> {
+    let deadline = options.time_limit.map(|limit| Instant::now() + limit);
+    let now = Instant::now();
+    let mut code_books = read_tar_code_books(File::open(tar_filepath)?, options)?;
+    if code_books.is_empty() && !options.allow_empty {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("{} has no files, use --allow-empty to write an empty archive", tar_filepath)));
+    }
+    if options.manifest {
+        add_manifest(&mut code_books, options)?;
+    }
+    let blocks = create_file_blocks(&code_books);
+
+    let byte_size = code_books.iter().map(|code_book| code_book.og_byte_size).sum();
+    let tp = configure_thread_pool(thread_count(options.multithreaded, code_books.len(), byte_size, options.min_parallel_bytes)?)?;
+    let progress_out = &mut io::stderr();
+    let mut progress = options.progress.map(|format| Progress::new(format, progress_out, code_books.len(), byte_size));
+    // the archive extracts to a directory named after it, so it can't keep the tar's name or it would extract onto the tar
+    let archive_filename = String::from(tar_filepath.strip_suffix(".tar").unwrap_or(tar_filepath)) + ".zipr";
+    write_archive(&archive_filename, &code_books, &blocks, options, deadline, &mut progress, &tp)?;
+
+    eprintln!("Finished zipping in {:.2?}", now.elapsed());
+    eprintln!("Wrote archive to: {}", std::fs::canonicalize(&archive_filename)?.display());
+    Ok(blocks)
+}
+
+// read every regular file in a tar into a code book that keeps the file's bytes, in the order the tar stores them
+// members are kept in memory until they add up to the buffer budget, and the rest are spilled to temporary files
+pub fn read_tar_code_books(tar: impl Read, options: &ArchiveOptions) -> io::Result<Vec<CodeBook>> {
+    let mut code_books = vec![];
+    let budget = AtomicU64::new(options.max_buffered_bytes);
+    let mut archive = tar::Archive::new(tar);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = member_name(entry.path_bytes().into_owned(), options)?;
+        let size = entry.size();
+        // a skipped member's bytes are passed over by the next entry without being read
+        if options.exclude_larger_than.is_some_and(|max_size| size > max_size) {
+            continue;
+        }
+
+        let method = method_for_size(size, options);
+        let executable = entry.header().mode()? & 0o100 != 0;
+        let mut code_book = CodeBook::from_stream(&name, &mut entry, size, method, &budget)?;
+        // only the owner's execute bit is kept, the same as for files read from disk
+        code_book.executable = executable;
+        code_books.push(code_book);
+    }
+    Ok(code_books)
+}
+
+// tar names always use forward slashes and are often written as ./dir/file, so the ./ components are dropped
+fn member_name(name_bytes: Vec<u8>, options: &ArchiveOptions) -> io::Result<String> {
+    let name = decode_name(name_bytes, false)?;
+    let name = name.split('/')
+        .filter(|part| !part.is_empty() && Path::new(part).components().all(|c| c != Component::CurDir))
+        .collect::<Vec<_>>()
+        .join("/");
+    if options.portable_paths {
+        Ok(name)
+    } else {
+        Ok(from_portable_path(&name, MAIN_SEPARATOR))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::compress::{unarchive_zip, ArchiveOptions, ExtractOptions};
+    use crate::tar_input::archive_tar;
+    use crate::testing::scratch_dir;
+
+    #[test]
+    fn test_archive_tar_members() {
+        let dir = scratch_dir("tar_input");
+
+        // build the tar in memory with a directory member, which has nothing to compress
+        let files = [("./docs/readme.txt", "a readme inside a tar"), ("data.csv", "a,b,c\n1,2,3\n"), ("empty.txt", "")];
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        builder.append_data(&mut header, "docs/", &[][..]).unwrap();
+        for (name, data) in &files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, data.as_bytes()).unwrap();
+        }
+        let tar_path = dir.join("bundle.tar");
+        fs::write(&tar_path, builder.into_inner().unwrap()).unwrap();
+
+        let tar_path = tar_path.to_str().unwrap();
+        // with a budget that only fits the first member, the others are spilled to temporary files instead of kept in memory
+        for max_buffered_bytes in [ArchiveOptions::default().max_buffered_bytes, files[0].1.len() as u64] {
+            let options = ArchiveOptions { max_buffered_bytes, ..ArchiveOptions::default() };
+            let blocks = archive_tar(tar_path, &options).unwrap();
+            let names: Vec<&str> = blocks.iter().map(|block| block.filename_rel.as_str()).collect();
+            assert_eq!(names, ["docs/readme.txt", "data.csv", "empty.txt"]);
+
+            unarchive_zip(dir.join("bundle.zipr").to_str().unwrap(), &ExtractOptions::default()).unwrap();
+            for (name, data) in &files {
+                assert_eq!(fs::read_to_string(dir.join("bundle").join(name)).unwrap(), *data);
+            }
+            fs::remove_dir_all(dir.join("bundle")).unwrap();
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,79 @@
+// Joseph Prichard
+// 1/5/2023
+// Decompress the entries of an archive into a tar stream instead of loose files, so they can be piped into other tools
+
+use std::io::{self, Write};
+use std::path::MAIN_SEPARATOR;
+use crate::archive_reader::decompress_to_vec;
+use crate::bitwise_io::FileReader;
+use crate::compress::{read_file_blocks, to_portable_path, ExtractOptions};
+use crate::glob::matches_any;
+use crate::structures::FileBlock;
+
+// write each included entry as a regular file in a tar, returning the blocks that were written
+// only the include patterns and lossy names apply, the options for choosing output paths have nothing to do with a tar
+pub fn unarchive_to_tar(archive_filepath: &str, out: impl Write, options: &ExtractOptions) -> io::Result<Vec<FileBlock>> {
+    let reader = &mut FileReader::new(archive_filepath)?;
+    let blocks: Vec<FileBlock> = read_file_blocks(reader, options.lossy_names)?
+        .into_iter()
+        .filter(|block| matches_any(&options.include, &block.filename_rel))
+        .collect();
+
+    let mut builder = tar::Builder::new(out);
+    for block in &blocks {
+        // each entry is decompressed in full since the tar header needs its size before the data
+        let data = decompress_to_vec(block, reader)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(data.len() as u64);
+        header.set_mode(if block.executable { 0o755 } else { 0o644 });
+        // tar names always use forward slashes, even if the archive was written with native paths
+        let name = to_portable_path(&block.filename_rel, MAIN_SEPARATOR);
+        builder.append_data(&mut header, name, data.as_slice())?;
+    }
+    builder.into_inner()?.flush()?;
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+    use crate::compress::{archive_dir, ArchiveOptions, ExtractOptions};
+    use crate::tar_output::unarchive_to_tar;
+    use crate::testing::scratch_dir;
+
+    #[test]
+    fn test_unarchive_to_tar() {
+        let dir = scratch_dir("tar_output");
+        let input_dir = dir.join("input");
+        fs::create_dir_all(input_dir.join("nested")).unwrap();
+        let files = [("a.txt", "first file"), ("nested/b.txt", "second file in a directory"), ("empty.txt", "")];
+        for (name, data) in &files {
+            fs::write(input_dir.join(name), data).unwrap();
+        }
+        let input_path = input_dir.to_str().unwrap().to_owned();
+        archive_dir(std::slice::from_ref(&input_path), &ArchiveOptions::default()).unwrap();
+
+        let mut tar_bytes = vec![];
+        unarchive_to_tar(&format!("{}.zipr", input_path), &mut tar_bytes, &ExtractOptions::default()).unwrap();
+
+        let mut members = vec![];
+        let mut archive = tar::Archive::new(tar_bytes.as_slice());
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_str().unwrap().to_owned();
+            let mut data = String::new();
+            entry.read_to_string(&mut data).unwrap();
+            members.push((name, data));
+        }
+        members.sort();
+        let mut expected: Vec<(String, String)> = files.iter()
+            .map(|(name, data)| (format!("input/{}", name), String::from(*data)))
+            .collect();
+        expected.sort();
+        assert_eq!(members, expected);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+// Joseph Prichard
+// 1/5/2023
+// Assertions for downstream test suites checking their data survives compression, enabled with the testing feature
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::coder::{METHOD_HUFFMAN, METHOD_STORED};
+use crate::compress::{archive_dir, compress_bytes, decompress_bytes, unarchive_zip, ArchiveOptions, ExtractOptions};
+use crate::structures::FileBlock;
+
+// an empty directory for a test to work in, named with the process id so concurrent runs don't share it
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("zipper_{}_{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// archive a directory, move it aside to "original" next to it, extract the archive and check every file matches the original
+// the extracted files are left under the input directory, and the blocks written to the archive are returned
+pub fn assert_archive_round_trip(input_dir: &Path, options: &ArchiveOptions) -> Vec<FileBlock> {
+    let input_path = input_dir.to_str().unwrap().to_owned();
+    let archive_path = format!("{}.zipr", input_path);
+    let blocks = archive_dir(std::slice::from_ref(&input_path), options)
+        .unwrap_or_else(|e| panic!("Failed to archive {}: {}", input_path, e));
+
+    let original_dir = input_dir.with_file_name("original");
+    fs::rename(input_dir, &original_dir).unwrap();
+    unarchive_zip(&archive_path, &ExtractOptions::default())
+        .unwrap_or_else(|e| panic!("Failed to extract {}: {}", archive_path, e));
+
+    let output_dir = input_dir.join(input_dir.file_name().unwrap());
+    assert_same_files(&original_dir, &output_dir);
+    blocks
+}
+
+// panics at the first file under the expected directory that is missing or different under the actual one
+fn assert_same_files(expected_dir: &Path, actual_dir: &Path) {
+    for entry in fs::read_dir(expected_dir).unwrap() {
+        let entry = entry.unwrap();
+        let expected_path = entry.path();
+        let actual_path = actual_dir.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            assert_same_files(&expected_path, &actual_path);
+        } else {
+            let actual = fs::read(&actual_path).unwrap_or_else(|e| panic!("Failed to read extracted file {:?}: {}", actual_path, e));
+            assert!(actual == fs::read(&expected_path).unwrap(), "Extracted file {:?} is different", actual_path);
+        }
+    }
+}
+
+// compress and decompress the bytes in memory with every method, panicking at the first byte that differs
+pub fn assert_round_trip(bytes: &[u8]) {
+    for method in [METHOD_HUFFMAN, METHOD_STORED] {
+        let (block, compressed) = compress_bytes("round_trip", bytes, method)
+            .unwrap_or_else(|e| panic!("Failed to compress {} bytes with method {}: {}", bytes.len(), method, e));
+        let decompressed = decompress_bytes(&block, &compressed)
+            .unwrap_or_else(|e| panic!("Failed to decompress {} bytes with method {}: {}", bytes.len(), method, e));
+
+        if let Some(pos) = bytes.iter().zip(&decompressed).position(|(a, b)| a != b) {
+            panic!("Round trip with method {} differs at byte {} of {}: expected {:#04x}, got {:#04x}",
+                method, pos, bytes.len(), bytes[pos], decompressed[pos]);
+        }
+        if decompressed.len() != bytes.len() {
+            panic!("Round trip with method {} produced {} bytes, expected {}", method, decompressed.len(), bytes.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::testing::assert_round_trip;
+
+    #[test]
+    fn test_edge_cases_round_trip() {
+        let mut rng = StdRng::seed_from_u64(1455);
+        let random: Vec<u8> = (0..10000).map(|_| rng.gen()).collect();
+        let every_byte: Vec<u8> = (0..=255).collect();
+        let inputs: [&[u8]; 5] = [b"", b"x", &[7; 4096], &every_byte, &random];
+        for input in inputs {
+            assert_round_trip(input);
+        }
+    }
+}